@@ -0,0 +1,222 @@
+//! `#[derive(TelemetryPayload)]` for kos telemetry payloads.
+//!
+//! ```ignore
+//! #[derive(Serialize, TelemetryPayload)]
+//! #[telemetry(measurement = "gripper", tag = "gripper_id", version = 2)]
+//! pub struct GripperState {
+//!     pub gripper_id: u32,
+//!     pub position: f32,
+//!     pub force: Option<f32>,
+//!     pub closed: bool,
+//!     #[telemetry(skip)]
+//!     pub debug_label: String,
+//! }
+//! ```
+//!
+//! generates an `IntoLineProtocol` impl with `gripper_id` as a tag and the
+//! remaining fields as line protocol fields, plus a `GripperState::TOPIC`
+//! constant holding the canonical subtopic (the measurement name) and a
+//! `SchemaVersion` impl. `version` defaults to 1.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, LitInt, LitStr, PathArguments,
+    Type,
+};
+
+const FIELD_TYPES: &[&str] = &["f32", "f64", "u32", "u64", "i64", "bool"];
+
+#[proc_macro_derive(TelemetryPayload, attributes(telemetry))]
+pub fn derive_telemetry_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut measurement: Option<LitStr> = None;
+    let mut tags: Vec<LitStr> = Vec::new();
+    let mut version: Option<LitInt> = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("telemetry"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("measurement") {
+                measurement = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("tag") {
+                tags.push(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("version") {
+                version = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `measurement`, `tag` or `version`"))
+            }
+        })?;
+    }
+    let measurement = measurement.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "missing #[telemetry(measurement = \"...\")]",
+        )
+    })?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "TelemetryPayload requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "TelemetryPayload can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut tag_exprs = Vec::new();
+    let mut field_exprs = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named field");
+        let name = ident.to_string();
+
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("telemetry"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip`"))
+                }
+            })?;
+        }
+        if skip {
+            continue;
+        }
+
+        if tags.iter().any(|tag| tag.value() == name) {
+            tag_exprs.push(quote! { (#name, ::std::string::ToString::to_string(&self.#ident)) });
+            continue;
+        }
+
+        let expr = match option_inner(&field.ty) {
+            Some(inner) => {
+                check_field_type(inner)?;
+                quote! { self.#ident.map(::kos::telemetry::line_protocol::FieldValue::from) }
+            }
+            None => {
+                check_field_type(&field.ty)?;
+                quote! { ::std::option::Option::Some(::kos::telemetry::line_protocol::FieldValue::from(self.#ident)) }
+            }
+        };
+        field_exprs.push(quote! { (#name, #expr) });
+    }
+
+    for tag in &tags {
+        if !fields.iter().any(|field| {
+            field
+                .ident
+                .as_ref()
+                .is_some_and(|ident| *ident == tag.value())
+        }) {
+            return Err(syn::Error::new_spanned(tag, "no field with this name"));
+        }
+    }
+
+    let version = match version {
+        Some(version) => {
+            version.base10_parse::<u16>()?;
+            quote! { #version }
+        }
+        None => quote! { 1 },
+    };
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            /// Canonical telemetry subtopic for this payload.
+            pub const TOPIC: &'static str = #measurement;
+        }
+
+        impl #impl_generics ::kos::telemetry::payloads::SchemaVersion for #ident #ty_generics #where_clause {
+            const SCHEMA_VERSION: u16 = #version;
+        }
+
+        impl #impl_generics ::kos::telemetry::line_protocol::IntoLineProtocol for #ident #ty_generics #where_clause {
+            fn measurement(&self) -> &'static str {
+                #measurement
+            }
+
+            fn tags(&self) -> ::std::vec::Vec<(&'static str, ::std::string::String)> {
+                ::std::vec![#(#tag_exprs),*]
+            }
+
+            fn fields(
+                &self,
+            ) -> ::std::vec::Vec<(
+                &'static str,
+                ::std::option::Option<::kos::telemetry::line_protocol::FieldValue>,
+            )> {
+                ::std::vec![#(#field_exprs),*]
+            }
+        }
+    })
+}
+
+/// The `T` of an `Option<T>` field type.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+fn check_field_type(ty: &Type) -> syn::Result<()> {
+    let supported = match ty {
+        Type::Path(path) => path
+            .path
+            .get_ident()
+            .is_some_and(|ident| FIELD_TYPES.iter().any(|t| ident == t)),
+        _ => false,
+    };
+    if supported {
+        Ok(())
+    } else {
+        Err(syn::Error::new_spanned(
+            ty,
+            format!(
+                "unsupported telemetry field type, expected one of {} or an Option of them; \
+                 use #[telemetry(skip)] to leave the field out",
+                FIELD_TYPES.join(", ")
+            ),
+        ))
+    }
+}
@@ -0,0 +1,88 @@
+//! Publishing a burst of samples one message at a time versus as a single
+//! batch, through a sink that discards everything so only the telemetry
+//! side is measured.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kos::telemetry::{Telemetry, TelemetryConfig, TelemetryError, TelemetrySink};
+use rumqttc::QoS;
+use serde::Serialize;
+use std::sync::Arc;
+
+struct NullSink;
+
+#[async_trait]
+impl TelemetrySink for NullSink {
+    async fn send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<(), TelemetryError> {
+        Ok(())
+    }
+
+    fn try_send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<bool, TelemetryError> {
+        Ok(true)
+    }
+}
+
+#[derive(Serialize)]
+struct Sample {
+    joint: u32,
+    position: f32,
+    velocity: f32,
+    torque: f32,
+}
+
+fn samples(n: u32) -> Vec<Sample> {
+    (0..n)
+        .map(|joint| Sample {
+            joint,
+            position: joint as f32 * 0.1,
+            velocity: 0.5,
+            torque: -1.25,
+        })
+        .collect()
+}
+
+fn bench_batch(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let telemetry = runtime.block_on(async {
+        Telemetry::with_sink(
+            TelemetryConfig::new("bench_robot", "localhost", 1883),
+            Arc::new(NullSink),
+        )
+    });
+
+    let mut group = c.benchmark_group("batch");
+    for n in [10, 100, 1000] {
+        let items = samples(n);
+        group.throughput(Throughput::Elements(n.into()));
+        group.bench_with_input(BenchmarkId::new("individual", n), &items, |b, items| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    for item in items {
+                        telemetry.publish("joints", item).await.unwrap();
+                    }
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("publish_batch", n), &items, |b, items| {
+            b.iter(|| {
+                runtime
+                    .block_on(telemetry.publish_batch("joints", items))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_batch);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+//! Encoding payloads on the publish path, in JSON and MessagePack, with a
+//! sink that discards them.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kos::telemetry::{
+    SerializationFormat, Telemetry, TelemetryConfig, TelemetryError, TelemetrySink,
+};
+use rumqttc::QoS;
+use std::sync::Arc;
+
+struct NullSink;
+
+#[async_trait]
+impl TelemetrySink for NullSink {
+    async fn send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<(), TelemetryError> {
+        Ok(())
+    }
+
+    fn try_send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<bool, TelemetryError> {
+        Ok(true)
+    }
+}
+
+/// `n` joint states, as a control loop would publish them.
+fn joints(n: usize) -> serde_json::Value {
+    (0..n)
+        .map(|i| {
+            serde_json::json!({
+                "actuator_id": i,
+                "actual_position": i as f32 * 0.1,
+                "actual_velocity": 0.5,
+                "actual_torque": -1.25,
+            })
+        })
+        .collect()
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, serialization) in [
+        ("json", SerializationFormat::Json),
+        ("msgpack", SerializationFormat::MessagePack),
+    ] {
+        let mut config = TelemetryConfig::new("bench_robot", "localhost", 1883);
+        config.serialization = serialization;
+        // Outside a runtime, so no background tasks compete with the loop.
+        let telemetry = Telemetry::with_sink(config, Arc::new(NullSink));
+
+        for n in [1, 20, 200] {
+            let payload = joints(n);
+            group.bench_with_input(BenchmarkId::new(name, n), &payload, |b, payload| {
+                b.iter(|| telemetry.try_publish("joints", payload).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
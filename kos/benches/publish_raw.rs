@@ -0,0 +1,82 @@
+//! Publishing a body that is already serialized JSON with `publish_raw`
+//! versus deserializing it and publishing the value with `publish`.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kos::telemetry::{Telemetry, TelemetryConfig, TelemetryError, TelemetrySink};
+use rumqttc::QoS;
+use std::sync::Arc;
+
+struct NullSink;
+
+#[async_trait]
+impl TelemetrySink for NullSink {
+    async fn send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<(), TelemetryError> {
+        Ok(())
+    }
+
+    fn try_send(
+        &self,
+        _topic: String,
+        _payload: Vec<u8>,
+        _qos: QoS,
+    ) -> Result<bool, TelemetryError> {
+        Ok(true)
+    }
+}
+
+/// A JSON array of `n` joint states, as another service might forward it.
+fn body(n: usize) -> Bytes {
+    let joints: Vec<_> = (0..n)
+        .map(|i| {
+            serde_json::json!({
+                "actuator_id": i,
+                "actual_position": i as f32 * 0.1,
+                "actual_velocity": 0.5,
+                "actual_torque": -1.25,
+            })
+        })
+        .collect();
+    serde_json::to_vec(&joints).unwrap().into()
+}
+
+fn bench_publish_raw(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let telemetry = runtime.block_on(async {
+        Telemetry::with_sink(
+            TelemetryConfig::new("bench_robot", "localhost", 1883),
+            Arc::new(NullSink),
+        )
+    });
+
+    let mut group = c.benchmark_group("publish_raw");
+    for n in [1, 20, 200] {
+        let body = body(n);
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::new("publish_raw", n), &body, |b, body| {
+            b.iter(|| {
+                runtime
+                    .block_on(telemetry.publish_raw("joints", body.clone()))
+                    .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("publish", n), &body, |b, body| {
+            b.iter(|| {
+                let value: serde_json::Value = serde_json::from_slice(body).unwrap();
+                runtime
+                    .block_on(telemetry.publish("joints", &value))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_publish_raw);
+criterion_main!(benches);
@@ -75,10 +75,7 @@ impl Write for CompressedWriter {
                 "Attempted to write to finalized log {}",
                 self.path.display()
             );
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Writer has been finalized",
-            ))
+            Err(io::Error::other("Writer has been finalized"))
         }
     }
 
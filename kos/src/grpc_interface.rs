@@ -37,6 +37,9 @@ pub mod google {
         tonic::include_proto!("kos/google.longrunning");
     }
 
+    // Generated from the upstream Google protos, whose doc comments trip
+    // newer clippy lints.
+    #[allow(clippy::doc_overindented_list_items)]
     pub mod api {
         tonic::include_proto!("kos/google.api");
     }
@@ -0,0 +1,128 @@
+use super::error::{Result, TelemetryError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Returned by `Telemetry::publish_tracked`; resolves once the broker
+/// acknowledged that particular message.
+#[must_use = "the acknowledgement is only observed by waiting on it"]
+pub struct PublishAck {
+    rx: oneshot::Receiver<()>,
+}
+
+impl PublishAck {
+    pub(crate) fn new(rx: oneshot::Receiver<()>) -> Self {
+        Self { rx }
+    }
+
+    /// A handle for a message that was deliberately not sent, e.g. to a
+    /// muted topic, which resolves right away.
+    pub(crate) fn skipped() -> Self {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(());
+        Self::new(rx)
+    }
+
+    /// Waits for the PUBACK, or PUBCOMP at QoS 2. QoS 0 messages count as
+    /// acknowledged once they are written to the socket. Fails with
+    /// `AckTimeout` after `timeout`, or `NotConnected` if the client was
+    /// closed first.
+    pub async fn wait(self, timeout: Duration) -> Result<()> {
+        match tokio::time::timeout(timeout, self.rx).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(TelemetryError::NotConnected),
+            Err(_) => Err(TelemetryError::AckTimeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::test_broker::TestBroker;
+    use crate::telemetry::Telemetry;
+    use rumqttc::QoS;
+
+    #[tokio::test]
+    async fn resolves_once_acknowledged() {
+        let (tx, rx) = oneshot::channel();
+        let ack = PublishAck::new(rx);
+        tx.send(()).unwrap();
+        ack.wait(Duration::from_secs(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_client_closes_first() {
+        let (tx, rx) = oneshot::channel::<()>();
+        drop(tx);
+        assert!(matches!(
+            PublishAck::new(rx).wait(Duration::from_secs(1)).await,
+            Err(TelemetryError::NotConnected)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_without_an_acknowledgement() {
+        let (_tx, rx) = oneshot::channel::<()>();
+        assert!(matches!(
+            PublishAck::new(rx).wait(Duration::from_secs(1)).await,
+            Err(TelemetryError::AckTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn publish_tracked_waits_for_the_broker() {
+        let broker = TestBroker::start().await;
+        let mut config = broker.config("test_robot");
+        config
+            .topic_qos
+            .insert("commands".to_string(), QoS::AtLeastOnce);
+        let telemetry = Telemetry::new(config).unwrap();
+        assert!(telemetry.wait_connected(Duration::from_secs(5)).await);
+
+        let ack = telemetry.publish_tracked("commands", &1).await.unwrap();
+        ack.wait(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(broker.published()[0].topic, "robots/test_robot/commands");
+
+        broker.set_withholding_acks(true);
+        let ack = telemetry.publish_tracked("commands", &2).await.unwrap();
+        assert!(matches!(
+            ack.wait(Duration::from_millis(100)).await,
+            Err(TelemetryError::AckTimeout)
+        ));
+        telemetry.shutdown(Duration::from_millis(100)).await.ok();
+    }
+
+    #[tokio::test]
+    async fn publish_tracked_skips_muted_topics() {
+        let broker = TestBroker::start().await;
+        let telemetry = Telemetry::new(broker.config("test_robot")).unwrap();
+        assert!(telemetry.wait_connected(Duration::from_secs(5)).await);
+
+        telemetry.mute("commands");
+        let ack = telemetry.publish_tracked("commands", &1).await.unwrap();
+        ack.wait(Duration::from_millis(100)).await.unwrap();
+        assert_eq!(telemetry.muted_count(), 1);
+
+        telemetry.unmute("commands");
+        let ack = telemetry.publish_tracked("commands", &2).await.unwrap();
+        ack.wait(Duration::from_secs(5)).await.unwrap();
+        let published = broker.published();
+        assert_eq!(published.len(), 1);
+        let payload: serde_json::Value = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(payload["data"], 2);
+        // The muted message did not take a sequence number either.
+        assert_eq!(payload["sequence"], 0);
+        telemetry.shutdown(Duration::from_millis(100)).await.ok();
+    }
+
+    #[tokio::test]
+    async fn publish_tracked_fails_while_disconnected() {
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        let telemetry = Telemetry::new(broker.config("test_robot")).unwrap();
+        assert!(matches!(
+            telemetry.publish_tracked("commands", &1).await,
+            Err(TelemetryError::NotConnected)
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+//! Throttles the rate limits while publishing is slow, see
+//! `TelemetryConfig::adaptive_rate`. The scale backs off multiplicatively and
+//! recovers additively, so a congested link is relieved quickly and probed
+//! gently afterwards.
+
+use super::config::AdaptiveRateConfig;
+use super::Telemetry;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+pub(crate) async fn run(telemetry: Telemetry, adaptive: AdaptiveRateConfig) {
+    let mut ticker = tokio::time::interval(adaptive.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately.
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        if telemetry.connection.is_shutting_down() {
+            break;
+        }
+        let latency = telemetry.counters.take_control_latency();
+        if latency.count == 0 {
+            continue;
+        }
+
+        let scale = telemetry.rate_limiter.scale();
+        let next = next_scale(&adaptive, scale, latency.p99);
+        if next == scale {
+            continue;
+        }
+        if scale == 1.0 {
+            tracing::info!(
+                "Publish latency p99 of {:?} over {:?}, throttling telemetry",
+                latency.p99,
+                adaptive.p99_threshold
+            );
+        } else if next == 1.0 {
+            tracing::info!("Publish latency recovered, telemetry rates restored");
+        }
+        tracing::debug!("Telemetry rate scale: {}", next);
+        telemetry.rate_limiter.set_scale(next);
+    }
+}
+
+fn next_scale(adaptive: &AdaptiveRateConfig, scale: f32, p99: Duration) -> f32 {
+    if p99 > adaptive.p99_threshold {
+        (scale * adaptive.decrease).max(adaptive.min_scale)
+    } else {
+        (scale + adaptive.increase).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::TestClock;
+    use crate::telemetry::config::TelemetryConfig;
+    use crate::telemetry::error::Result;
+    use crate::telemetry::sink::TelemetrySink;
+    use rumqttc::QoS;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Takes `latency` of the configured clock to accept each message.
+    struct CongestedSink {
+        clock: Arc<TestClock>,
+        latency_millis: AtomicU64,
+    }
+
+    #[async_trait::async_trait]
+    impl TelemetrySink for CongestedSink {
+        async fn send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<()> {
+            self.clock.advance(Duration::from_millis(
+                self.latency_millis.load(Ordering::SeqCst),
+            ));
+            Ok(())
+        }
+
+        fn try_send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn imu_rate(telemetry: &Telemetry) -> f32 {
+        telemetry
+            .effective_rates()
+            .into_iter()
+            .find(|(topic, _)| topic == "imu")
+            .map(|(_, hz)| hz)
+            .unwrap()
+    }
+
+    #[test]
+    fn backs_off_multiplicatively_and_recovers_additively() {
+        let adaptive = AdaptiveRateConfig::default();
+        let slow = adaptive.p99_threshold * 2;
+        assert_eq!(next_scale(&adaptive, 1.0, slow), 0.5);
+        assert_eq!(next_scale(&adaptive, 0.15, slow), adaptive.min_scale);
+        assert_eq!(next_scale(&adaptive, 0.5, Duration::ZERO), 0.6);
+        assert_eq!(next_scale(&adaptive, 0.95, Duration::ZERO), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_follow_simulated_congestion() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let sink = Arc::new(CongestedSink {
+            clock: clock.clone(),
+            latency_millis: AtomicU64::new(200),
+        });
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.clock = clock;
+        config.rate_limits.insert("imu".to_string(), 100.0);
+        config.adaptive_rate = Some(AdaptiveRateConfig::default());
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+
+        // One latency sample between each of the checks, once a second.
+        let mut rates = Vec::new();
+        let check = || async {
+            telemetry.publish("gps", &1).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            imu_rate(&telemetry)
+        };
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..5 {
+            rates.push(check().await);
+        }
+        assert_eq!(rates, [50.0, 25.0, 12.5, 10.0, 10.0]);
+
+        sink.latency_millis.store(0, Ordering::SeqCst);
+        rates.clear();
+        for _ in 0..10 {
+            rates.push(check().await);
+        }
+        assert!(
+            rates.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            rates
+        );
+        assert_eq!(rates.last(), Some(&100.0));
+    }
+}
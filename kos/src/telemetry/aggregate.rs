@@ -0,0 +1,279 @@
+use super::error::Result;
+use super::line_protocol::{FieldValue, IntoLineProtocol};
+use super::payloads::{FieldSummary, ImuReading, ImuSummary, JointState, JointSummary};
+use super::Telemetry;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// When an `Aggregator` closes its window and publishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregationWindow {
+    /// After this much time, by `TelemetryConfig::clock`, since the first
+    /// push of the window.
+    Time(Duration),
+    /// After this many pushes.
+    Count(u64),
+}
+
+/// Payloads an `Aggregator` can summarize. Every numeric field of
+/// `IntoLineProtocol::fields` is aggregated; booleans and strings are
+/// ignored.
+pub trait Summarize: IntoLineProtocol {
+    /// Samples with the same key are aggregated together, e.g. per actuator.
+    type Key: Ord + Clone;
+    type Summary: Serialize;
+
+    fn key(&self) -> Self::Key;
+
+    fn summary(
+        key: Self::Key,
+        samples: u64,
+        fields: BTreeMap<String, FieldSummary>,
+    ) -> Self::Summary;
+}
+
+impl Summarize for JointState {
+    type Key = u32;
+    type Summary = JointSummary;
+
+    fn key(&self) -> u32 {
+        self.actuator_id
+    }
+
+    fn summary(key: u32, samples: u64, fields: BTreeMap<String, FieldSummary>) -> JointSummary {
+        JointSummary {
+            actuator_id: key,
+            samples,
+            fields,
+        }
+    }
+}
+
+impl Summarize for ImuReading {
+    type Key = ();
+    type Summary = ImuSummary;
+
+    fn key(&self) {}
+
+    fn summary(_key: (), samples: u64, fields: BTreeMap<String, FieldSummary>) -> ImuSummary {
+        ImuSummary { samples, fields }
+    }
+}
+
+struct Accumulator {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn summary(&self) -> FieldSummary {
+        FieldSummary {
+            min: self.min,
+            max: self.max,
+            mean: self.sum / self.count as f64,
+            count: self.count,
+        }
+    }
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    samples: u64,
+    fields: BTreeMap<&'static str, Accumulator>,
+}
+
+struct Window<K> {
+    /// Monotonic time of the first push.
+    started: Option<Duration>,
+    pushes: u64,
+    groups: BTreeMap<K, Group>,
+}
+
+/// Accumulates samples over a window and publishes the min, max and mean of
+/// each numeric field, per key, instead of every raw sample. One message
+/// with the summaries of every key is published to `topic` per window.
+///
+/// Like `BatchSink`, the window is checked whenever samples are pushed, so
+/// callers that stop pushing should call `flush`.
+pub struct Aggregator<P: Summarize> {
+    telemetry: Telemetry,
+    topic: String,
+    window: AggregationWindow,
+    state: Mutex<Window<P::Key>>,
+}
+
+impl<P: Summarize> Aggregator<P> {
+    pub fn new(telemetry: Telemetry, topic: &str, window: AggregationWindow) -> Self {
+        Self {
+            telemetry,
+            topic: topic.to_string(),
+            window,
+            state: Mutex::new(Window {
+                started: None,
+                pushes: 0,
+                groups: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Adds `samples`, e.g. the joint states of every actuator at one
+    /// control tick, and publishes if this closes the window.
+    pub async fn push(&self, samples: &[P]) -> Result<()> {
+        let ready = {
+            let mut state = self.lock();
+            let now = self.telemetry.config.clock.now_monotonic();
+            let started = *state.started.get_or_insert(now);
+            state.pushes += 1;
+
+            for sample in samples {
+                let group = state.groups.entry(sample.key()).or_default();
+                group.samples += 1;
+                for (name, value) in sample.fields() {
+                    let value = match value {
+                        Some(FieldValue::Float(value)) => value,
+                        Some(FieldValue::Integer(value)) => value as f64,
+                        _ => continue,
+                    };
+                    if value.is_finite() {
+                        group.fields.entry(name).or_default().add(value);
+                    }
+                }
+            }
+
+            match self.window {
+                AggregationWindow::Time(window) => now.saturating_sub(started) >= window,
+                AggregationWindow::Count(count) => state.pushes >= count,
+            }
+        };
+
+        if ready {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Publishes the summaries of the current window, if anything was pushed,
+    /// and starts a new one.
+    pub async fn flush(&self) -> Result<()> {
+        let groups = {
+            let mut state = self.lock();
+            state.started = None;
+            state.pushes = 0;
+            std::mem::take(&mut state.groups)
+        };
+
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let summaries: Vec<P::Summary> = groups
+            .into_iter()
+            .map(|(key, group)| {
+                let fields = group
+                    .fields
+                    .iter()
+                    .map(|(name, accumulator)| (name.to_string(), accumulator.summary()))
+                    .collect();
+                P::summary(key, group.samples, fields)
+            })
+            .collect();
+        self.telemetry.publish(&self.topic, &summaries).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window<P::Key>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{MemorySink, TelemetryConfig, TelemetryPayload};
+    use std::sync::Arc;
+
+    fn joint(actuator_id: u32, position: f32) -> JointState {
+        JointState {
+            actuator_id,
+            actual_position: Some(position),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn summarizes_each_field_over_the_window() {
+        let sink = Arc::new(MemorySink::new());
+        let telemetry = Telemetry::with_sink(
+            TelemetryConfig::new("test_robot", "localhost", 1883),
+            sink.clone(),
+        );
+        let aggregator =
+            telemetry.aggregator::<JointState>("joints/summary", AggregationWindow::Count(4));
+
+        let positions: [f32; 4] = [1.0, 2.5, -3.0, 7.5];
+        for position in positions {
+            aggregator
+                .push(&[joint(1, position), joint(2, 10.0)])
+                .await
+                .unwrap();
+        }
+
+        let messages = sink.messages();
+        assert_eq!(messages.len(), 1);
+        let payload: TelemetryPayload<Vec<JointSummary>> =
+            serde_json::from_slice(&messages[0].1).unwrap();
+        let summaries = payload.data;
+        assert_eq!(summaries.len(), 2);
+
+        let position = &summaries[0].fields["actual_position"];
+        let total: f64 = positions.iter().map(|&p| p as f64).sum();
+        assert_eq!(summaries[0].actuator_id, 1);
+        assert_eq!(summaries[0].samples, 4);
+        assert_eq!(position.count, 4);
+        assert_eq!(position.mean, total / positions.len() as f64);
+        assert_eq!(position.min, -3.0);
+        assert_eq!(position.max, 7.5);
+        // Fields the actuator did not report are left out.
+        assert!(!summaries[0].fields.contains_key("actual_torque"));
+
+        assert_eq!(summaries[1].fields["actual_position"].mean, 10.0);
+    }
+
+    #[tokio::test]
+    async fn flush_publishes_nothing_for_an_empty_window() {
+        let sink = Arc::new(MemorySink::new());
+        let telemetry = Telemetry::with_sink(
+            TelemetryConfig::new("test_robot", "localhost", 1883),
+            sink.clone(),
+        );
+        let aggregator =
+            telemetry.aggregator::<JointState>("joints/summary", AggregationWindow::Count(10));
+        aggregator.flush().await.unwrap();
+        assert!(sink.messages().is_empty());
+
+        aggregator.push(&[joint(1, 1.0)]).await.unwrap();
+        assert!(sink.messages().is_empty());
+        aggregator.flush().await.unwrap();
+        assert_eq!(sink.messages().len(), 1);
+    }
+}
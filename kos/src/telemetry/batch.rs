@@ -0,0 +1,77 @@
+use super::error::Result;
+use super::Telemetry;
+use serde::Serialize;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+struct Batch<T> {
+    items: Vec<T>,
+    /// Monotonic time the first item was pushed.
+    started: Option<Duration>,
+}
+
+/// Collects payloads for a fixed time window and publishes them as a single
+/// message, trading a little latency for far fewer MQTT publishes.
+///
+/// The window is checked whenever an item is pushed, so callers that stop
+/// pushing should call `flush` to send whatever is left.
+pub struct BatchSink<T> {
+    telemetry: Telemetry,
+    topic: String,
+    window: Duration,
+    batch: Mutex<Batch<T>>,
+}
+
+impl<T: Serialize> BatchSink<T> {
+    pub fn new(telemetry: Telemetry, topic: &str, window: Duration) -> Self {
+        Self {
+            telemetry,
+            topic: topic.to_string(),
+            window,
+            batch: Mutex::new(Batch {
+                items: Vec::new(),
+                started: None,
+            }),
+        }
+    }
+
+    pub async fn push(&self, item: T) -> Result<()> {
+        let ready = {
+            let mut batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = self.telemetry.config.clock.now_monotonic();
+            let started = *batch.started.get_or_insert(now);
+            batch.items.push(item);
+            now.saturating_sub(started) >= self.window
+        };
+
+        if ready {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        let items = {
+            let mut batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+            batch.started = None;
+            std::mem::take(&mut batch.items)
+        };
+
+        if items.is_empty() {
+            return Ok(());
+        }
+        self.telemetry.publish_batch(&self.topic, &items).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .items
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
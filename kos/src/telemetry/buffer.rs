@@ -0,0 +1,451 @@
+use super::client::MqttClient;
+use super::clock::Clock;
+use super::config::{OverflowPolicy, Priority};
+use super::connection::ConnectionTracker;
+use super::error::{Result, TelemetryError};
+use super::inflight::InFlight;
+use super::sink::OutgoingMessage;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::Notify;
+
+/// Queue of serialized messages waiting for the broker, either because it
+/// is unreachable or because the MQTT request channel is full. Messages are
+/// flushed highest priority first and, within a priority, in the order they
+/// arrived.
+pub(crate) struct OfflineBuffer {
+    /// One queue per priority, indexed by `queue_index`.
+    queues: Mutex<[VecDeque<OutgoingMessage>; 3]>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Notified whenever a message leaves the buffer.
+    space: Notify,
+    dropped: AtomicU64,
+    /// Messages dropped on flush because their TTL had passed.
+    expired: AtomicU64,
+    flushing: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+fn queue_index(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+impl OfflineBuffer {
+    pub fn new(capacity: usize, policy: OverflowPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            queues: Mutex::new(Default::default()),
+            capacity,
+            policy,
+            space: Notify::new(),
+            dropped: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            flushing: AtomicBool::new(false),
+            clock,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Queues `message`, applying the overflow policy once full. Fails with
+    /// `QueueFull` if the message was not accepted under `Error`, and under
+    /// `Block` since this cannot wait.
+    pub fn push(&self, message: OutgoingMessage) -> Result<()> {
+        match self.offer(message) {
+            None => Ok(()),
+            Some(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                match self.policy {
+                    OverflowPolicy::Block | OverflowPolicy::Error => Err(TelemetryError::QueueFull),
+                    OverflowPolicy::DropNewest | OverflowPolicy::DropOldest => Ok(()),
+                }
+            }
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// `push` that waits for room instead of failing, for `Block`. Without
+    /// a buffer there is never room, so it fails like `push`.
+    pub async fn push_waiting(&self, mut message: OutgoingMessage) -> Result<()> {
+        if self.capacity == 0 {
+            return self.push(message);
+        }
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            match self.offer(message) {
+                None => return Ok(()),
+                Some(rejected) => message = rejected,
+            }
+            space.await;
+        }
+    }
+
+    /// Queues `message` if the policy makes room for it, and otherwise
+    /// hands it back.
+    fn offer(&self, message: OutgoingMessage) -> Option<OutgoingMessage> {
+        if self.capacity == 0 {
+            return Some(message);
+        }
+
+        let mut queues = self.lock();
+        while queues.iter().map(VecDeque::len).sum::<usize>() >= self.capacity {
+            if self.policy != OverflowPolicy::DropOldest {
+                return Some(message);
+            }
+            let lowest = (0..queues.len())
+                .rev()
+                .find(|&i| !queues[i].is_empty())
+                .unwrap_or_default();
+            if lowest < queue_index(message.priority) {
+                return Some(message);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            queues[lowest].pop_front();
+        }
+        queues[queue_index(message.priority)].push_back(message);
+        None
+    }
+
+    fn pop(&self) -> Option<OutgoingMessage> {
+        let message = self.lock().iter_mut().find_map(VecDeque::pop_front);
+        if message.is_some() {
+            self.space.notify_waiters();
+        }
+        message
+    }
+
+    /// Takes every buffered message, highest priority first.
+    pub fn drain(&self) -> Vec<OutgoingMessage> {
+        let messages: Vec<_> = self
+            .lock()
+            .iter_mut()
+            .flat_map(|queue| queue.drain(..))
+            .collect();
+        if !messages.is_empty() {
+            self.space.notify_waiters();
+        }
+        messages
+    }
+
+    fn push_front(&self, message: OutgoingMessage) {
+        self.lock()[queue_index(message.priority)].push_front(message);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Messages rejected or discarded because the buffer was full, under
+    /// any policy.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Sends buffered messages until the buffer is empty or the connection
+    /// drops again, skipping those past their TTL. Only one flush runs at a
+    /// time.
+    pub async fn flush(
+        &self,
+        client: &MqttClient,
+        connection: &ConnectionTracker,
+        in_flight: &InFlight,
+    ) {
+        if self.flushing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let (mut sent, mut expired) = (0, 0);
+        while connection.is_connected() {
+            let Some(message) = self.pop() else {
+                break;
+            };
+            if message
+                .expires_at
+                .is_some_and(|expires_at| self.clock.now_monotonic() >= expires_at)
+            {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                expired += 1;
+                continue;
+            }
+
+            if let Err(e) = client
+                .publish(
+                    message.topic.clone(),
+                    message.qos,
+                    message.retain,
+                    message.payload.clone(),
+                    message.user_properties.clone(),
+                )
+                .await
+            {
+                tracing::warn!("Failed to flush buffered telemetry: {}", e);
+                self.push_front(message);
+                break;
+            }
+            in_flight.started();
+            sent += 1;
+        }
+
+        if sent > 0 {
+            tracing::debug!("Flushed {} buffered telemetry messages", sent);
+        }
+        if expired > 0 {
+            tracing::debug!("Skipped {} expired buffered telemetry messages", expired);
+        }
+        self.flushing.store(false, Ordering::SeqCst);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, [VecDeque<OutgoingMessage>; 3]> {
+        self.queues.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::{SystemClock, TestClock};
+    use crate::telemetry::test_broker::TestBroker;
+    use crate::telemetry::Telemetry;
+    use rumqttc::QoS;
+    use std::time::Duration;
+
+    fn message(topic: &str, priority: Priority) -> OutgoingMessage {
+        let mut message = OutgoingMessage::new(topic.to_string(), Vec::new(), QoS::AtMostOnce);
+        message.priority = priority;
+        message
+    }
+
+    fn topics(messages: &[OutgoingMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.topic.as_str()).collect()
+    }
+
+    #[test]
+    fn drains_highest_priority_first_in_arrival_order() {
+        let buffer = OfflineBuffer::new(8, OverflowPolicy::DropOldest, Arc::new(SystemClock));
+        buffer.push(message("low1", Priority::Low)).unwrap();
+        buffer.push(message("normal1", Priority::Normal)).unwrap();
+        buffer.push(message("high1", Priority::High)).unwrap();
+        buffer.push(message("low2", Priority::Low)).unwrap();
+        buffer.push(message("high2", Priority::High)).unwrap();
+
+        assert_eq!(
+            topics(&buffer.drain()),
+            ["high1", "high2", "normal1", "low1", "low2"]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_from_the_lowest_priority() {
+        let buffer = OfflineBuffer::new(2, OverflowPolicy::DropOldest, Arc::new(SystemClock));
+        buffer.push(message("low", Priority::Low)).unwrap();
+        buffer.push(message("normal", Priority::Normal)).unwrap();
+        buffer.push(message("high", Priority::High)).unwrap();
+        // Everything left has a higher priority, so the new one is dropped.
+        buffer.push(message("low2", Priority::Low)).unwrap();
+
+        assert_eq!(topics(&buffer.drain()), ["high", "normal"]);
+        assert_eq!(buffer.dropped(), 2);
+    }
+
+    #[test]
+    fn error_policy_rejects_when_full() {
+        let buffer = OfflineBuffer::new(1, OverflowPolicy::Error, Arc::new(SystemClock));
+        buffer.push(message("first", Priority::Normal)).unwrap();
+        assert!(matches!(
+            buffer.push(message("second", Priority::High)),
+            Err(TelemetryError::QueueFull)
+        ));
+        assert_eq!(topics(&buffer.drain()), ["first"]);
+    }
+
+    #[test]
+    fn drop_newest_rejects_the_incoming_message() {
+        let buffer = OfflineBuffer::new(2, OverflowPolicy::DropNewest, Arc::new(SystemClock));
+        buffer.push(message("first", Priority::Low)).unwrap();
+        buffer.push(message("second", Priority::Low)).unwrap();
+        // Not even a higher priority makes room.
+        buffer.push(message("third", Priority::High)).unwrap();
+        assert_eq!(buffer.dropped(), 1);
+
+        assert_eq!(topics(&buffer.drain()), ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let buffer = Arc::new(OfflineBuffer::new(
+            1,
+            OverflowPolicy::Block,
+            Arc::new(SystemClock),
+        ));
+        buffer.push(message("first", Priority::Normal)).unwrap();
+
+        let waiting = tokio::spawn({
+            let buffer = buffer.clone();
+            async move {
+                buffer
+                    .push_waiting(message("second", Priority::Normal))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        assert_eq!(buffer.len(), 1);
+
+        assert_eq!(buffer.pop().unwrap().topic, "first");
+        waiting.await.unwrap().unwrap();
+        let waiting = tokio::spawn({
+            let buffer = buffer.clone();
+            async move {
+                buffer
+                    .push_waiting(message("third", Priority::Normal))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        assert_eq!(topics(&buffer.drain()), ["second"]);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(topics(&buffer.drain()), ["third"]);
+        assert_eq!(buffer.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn flushes_high_priority_first_after_reconnect() {
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        let mut config = broker.config("test_robot");
+        config
+            .topic_priority
+            .insert("alerts".to_string(), Priority::High);
+        config
+            .topic_priority
+            .insert("bulk".to_string(), Priority::Low);
+        let telemetry = Telemetry::new(config).unwrap();
+
+        for topic in ["bulk", "joints", "bulk", "alerts", "joints"] {
+            telemetry.publish(topic, &1.0).await.unwrap();
+        }
+        assert!(telemetry.buffered_count() >= 5);
+
+        broker.set_rejecting(false);
+        let published = broker.wait_for_published(5).await;
+        let topics: Vec<_> = published.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "robots/test_robot/alerts",
+                "robots/test_robot/joints",
+                "robots/test_robot/joints",
+                "robots/test_robot/bulk",
+                "robots/test_robot/bulk",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_counts_every_policy() {
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        for policy in [
+            OverflowPolicy::DropNewest,
+            OverflowPolicy::DropOldest,
+            OverflowPolicy::Error,
+        ] {
+            let mut config = broker.config("test_robot");
+            config.buffer_capacity = 2;
+            config.overflow_policy = policy;
+            config.schema_interval = None;
+            let telemetry = Telemetry::new(config).unwrap();
+
+            for i in 0..5 {
+                let result = telemetry.publish("joints", &i).await;
+                if i >= 2 && policy == OverflowPolicy::Error {
+                    assert!(matches!(result, Err(TelemetryError::QueueFull)));
+                }
+            }
+            assert_eq!(telemetry.overflow_drops(), 3, "{:?}", policy);
+            assert_eq!(telemetry.buffered_count(), 2, "{:?}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_waits_for_room_under_block() {
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        let mut config = broker.config("test_robot");
+        config.buffer_capacity = 2;
+        config.overflow_policy = OverflowPolicy::Block;
+        config.schema_interval = None;
+        let telemetry = Telemetry::new(config).unwrap();
+
+        while telemetry.try_publish("joints", &0).unwrap() {}
+        // `try_publish` cannot wait, so that one was dropped.
+        assert_eq!(telemetry.overflow_drops(), 1);
+        let waiting = tokio::spawn({
+            let telemetry = telemetry.clone();
+            async move { telemetry.publish("joints", &1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        broker.set_rejecting(false);
+        waiting.await.unwrap().unwrap();
+        let published = broker.wait_for_published(3).await;
+        assert_eq!(published.len(), 3);
+        assert_eq!(telemetry.overflow_drops(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_skipped_on_flush() {
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let mut config = broker.config("test_robot");
+        config.clock = clock.clone();
+        config
+            .topic_ttl
+            .insert("imu".to_string(), Duration::from_secs(1));
+        let telemetry = Telemetry::new(config).unwrap();
+
+        for topic in ["imu", "alerts", "imu", "joints"] {
+            telemetry.publish(topic, &1.0).await.unwrap();
+        }
+        assert!(telemetry.buffered_count() >= 4);
+        clock.advance(Duration::from_secs(2));
+
+        broker.set_rejecting(false);
+        let published = broker.wait_for_published(2).await;
+        let topics: Vec<_> = published.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            ["robots/test_robot/alerts", "robots/test_robot/joints"]
+        );
+        assert_eq!(telemetry.expired_count(), 2);
+    }
+}
@@ -0,0 +1,65 @@
+use super::clock::Clock;
+use super::config::Priority;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+struct Bucket {
+    tokens: f64,
+    /// Monotonic time of the last refill.
+    refilled_at: Duration,
+}
+
+/// Token bucket over payload bytes, holding up to one second of budget.
+/// Lower priorities must leave part of the bucket untouched, so when the link
+/// is saturated they are dropped first and higher priority topics keep
+/// flowing.
+pub(crate) struct ByteLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ByteLimiter {
+    pub fn new(max_bytes_per_sec: u64, clock: Arc<dyn Clock>) -> Self {
+        let bytes_per_sec = max_bytes_per_sec as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled_at: clock.now_monotonic(),
+            }),
+            clock,
+        }
+    }
+
+    /// Takes `bytes` from the bucket if the remaining budget stays above the
+    /// reserve for `priority`. Returns false if the message should be
+    /// dropped.
+    pub fn admit(&self, bytes: usize, priority: Priority) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = self.clock.now_monotonic();
+        let elapsed = now.saturating_sub(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        bucket.refilled_at = now;
+
+        let reserve = self.bytes_per_sec * priority.reserved_fraction();
+        let remaining = bucket.tokens - bytes as f64;
+        if remaining < reserve {
+            return false;
+        }
+        bucket.tokens = remaining;
+        true
+    }
+}
+
+impl Priority {
+    /// Fraction of the byte budget that messages of this priority may not
+    /// use.
+    fn reserved_fraction(self) -> f64 {
+        match self {
+            Priority::Low => 0.5,
+            Priority::Normal => 0.25,
+            Priority::High => 0.0,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Last payload sent by `Telemetry::publish_on_change`, per topic.
+#[derive(Default)]
+pub(crate) struct ChangeFilter {
+    /// Hash of the payload and monotonic time it was sent.
+    last: Mutex<HashMap<String, (u64, Duration)>>,
+}
+
+impl ChangeFilter {
+    /// Whether a payload hashing to `hash` should go out: it differs from the
+    /// last one sent to `topic`, or `max_interval` has passed since. If so it
+    /// is remembered as the last one.
+    pub fn admit(
+        &self,
+        topic: &str,
+        hash: u64,
+        now: Duration,
+        max_interval: Option<Duration>,
+    ) -> bool {
+        let mut last = self.lock();
+        if let Some(&(last_hash, sent_at)) = last.get(topic) {
+            let due = max_interval.is_some_and(|max| now.saturating_sub(sent_at) >= max);
+            if last_hash == hash && !due {
+                return false;
+            }
+        }
+        last.insert(topic.to_string(), (hash, now));
+        true
+    }
+
+    /// Forgets the last payload of `topic`, e.g. because sending it failed.
+    pub fn forget(&self, topic: &str) {
+        self.lock().remove(topic);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (u64, Duration)>> {
+        self.last.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Hashes the JSON encoding of `payload` without buffering it.
+pub(crate) fn hash<T: Serialize>(payload: &T) -> serde_json::Result<u64> {
+    let mut writer = HashWriter(DefaultHasher::new());
+    serde_json::to_writer(&mut writer, payload)?;
+    Ok(writer.0.finish())
+}
+
+struct HashWriter(DefaultHasher);
+
+impl io::Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use super::config::{MqttProtocol, TelemetryConfig};
+use super::error::{Result, TelemetryError};
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::{Packet as PacketV5, PublishProperties};
+use rumqttc::{v5, ClientError, ConnectionError, Event, Outgoing, Packet, QoS};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::oneshot;
+
+/// MQTT client speaking either protocol version, so the rest of telemetry
+/// does not need to care which one the broker was configured with.
+#[derive(Clone)]
+pub(crate) struct MqttClient {
+    handle: Handle,
+    tracking: Arc<Tracking>,
+}
+
+#[derive(Clone)]
+enum Handle {
+    V311(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// Matches publishes to their acknowledgement for `publish_tracked`.
+/// rumqttc assigns packet ids only once a publish is written to the socket,
+/// but it writes them in the order they were queued, so every queued publish
+/// gets an entry in `order` that the next `Sent` event claims.
+#[derive(Default)]
+struct Tracking {
+    /// Held while queueing a publish, so that `order` matches the request
+    /// channel.
+    queueing: tokio::sync::Mutex<()>,
+    /// One entry per queued publish, with a sender if it is tracked.
+    order: Mutex<VecDeque<Option<oneshot::Sender<()>>>>,
+    /// QoS 1 and 2 publishes written to the socket and not acknowledged yet.
+    unacked: Mutex<HashMap<u16, Option<oneshot::Sender<()>>>>,
+}
+
+impl Tracking {
+    fn queued(&self, ack: Option<oneshot::Sender<()>>) {
+        lock(&self.order).push_back(ack);
+    }
+
+    /// Takes back the entry of a publish that could not be queued.
+    fn unqueued(&self) {
+        lock(&self.order).pop_back();
+    }
+
+    fn sent(&self, pkid: u16) {
+        let mut unacked = lock(&self.unacked);
+        // Retransmissions after a reconnect reuse their packet id and were
+        // never queued again.
+        if pkid != 0 && unacked.contains_key(&pkid) {
+            return;
+        }
+        let ack = lock(&self.order).pop_front().flatten();
+        if pkid != 0 {
+            unacked.insert(pkid, ack);
+        } else if let Some(ack) = ack {
+            let _ = ack.send(());
+        }
+    }
+
+    fn acked(&self, pkid: u16) {
+        if let Some(Some(ack)) = lock(&self.unacked).remove(&pkid) {
+            let _ = ack.send(());
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Event loop matching an `MqttClient`.
+pub(crate) enum MqttEventLoop {
+    // Boxed, both are hundreds of bytes and the v5 one is almost twice the
+    // size of the v3.1.1 one.
+    V311(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// The subset of MQTT events the event loop task acts on.
+pub(crate) enum Notification {
+    /// `max_packet_size` is the limit advertised in an MQTT v5 CONNACK.
+    Connected {
+        max_packet_size: Option<u32>,
+    },
+    Received {
+        topic: String,
+        payload: Bytes,
+    },
+    /// A publish went out on the socket. `pkid` is 0 for QoS 0.
+    Sent {
+        pkid: u16,
+    },
+    /// A QoS 1 or 2 publish was acknowledged with a PUBACK or PUBCOMP.
+    Acked {
+        pkid: u16,
+    },
+    Disconnected,
+    Other,
+}
+
+pub(crate) enum PollError {
+    /// Every client handle was dropped.
+    RequestsDone,
+    Connection(String),
+}
+
+impl MqttClient {
+    pub fn new(config: &TelemetryConfig, client_id: String) -> Result<(MqttClient, MqttEventLoop)> {
+        match config.protocol {
+            MqttProtocol::V311 => {
+                let (client, eventloop) = rumqttc::AsyncClient::new(
+                    config.mqtt_options(client_id)?,
+                    config.channel_capacity,
+                );
+                Ok((
+                    MqttClient::wrap(Handle::V311(client)),
+                    MqttEventLoop::V311(Box::new(eventloop)),
+                ))
+            }
+            MqttProtocol::V5 => {
+                let (client, eventloop) = v5::AsyncClient::new(
+                    config.mqtt_v5_options(client_id)?,
+                    config.channel_capacity,
+                );
+                Ok((
+                    MqttClient::wrap(Handle::V5(client)),
+                    MqttEventLoop::V5(Box::new(eventloop)),
+                ))
+            }
+        }
+    }
+
+    fn wrap(handle: Handle) -> Self {
+        Self {
+            handle,
+            tracking: Arc::default(),
+        }
+    }
+
+    /// Whether publishes can carry user properties.
+    pub fn supports_user_properties(&self) -> bool {
+        matches!(self.handle, Handle::V5(_))
+    }
+
+    /// Publishes `payload`. `user_properties` are ignored on MQTT 3.1.1.
+    pub async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        self.queue(topic, qos, retain, payload, user_properties, None)
+            .await
+    }
+
+    /// `publish`, sending on `ack` once the broker acknowledged the message
+    /// with a PUBACK or PUBCOMP. QoS 0 messages count as acknowledged once
+    /// they are written to the socket.
+    pub async fn publish_tracked(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+        ack: oneshot::Sender<()>,
+    ) -> Result<()> {
+        self.queue(topic, qos, retain, payload, user_properties, Some(ack))
+            .await
+    }
+
+    async fn queue(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+        ack: Option<oneshot::Sender<()>>,
+    ) -> Result<()> {
+        let _queueing = self.tracking.queueing.lock().await;
+        self.tracking.queued(ack);
+        let result = self
+            .handle
+            .publish(topic, qos, retain, payload, user_properties)
+            .await;
+        if result.is_err() {
+            self.tracking.unqueued();
+        }
+        result
+    }
+
+    /// Publishes without waiting. Returns `Ok(false)` if the request channel
+    /// is full or another publish is being queued.
+    pub fn try_publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<bool> {
+        let Ok(_queueing) = self.tracking.queueing.try_lock() else {
+            return Ok(false);
+        };
+        self.tracking.queued(None);
+        let result = self
+            .handle
+            .try_publish(topic, qos, retain, payload, user_properties);
+        if !matches!(result, Ok(true)) {
+            self.tracking.unqueued();
+        }
+        result
+    }
+
+    /// A publish went out on the socket. `pkid` is 0 for QoS 0.
+    pub fn sent(&self, pkid: u16) {
+        self.tracking.sent(pkid);
+    }
+
+    /// The broker acknowledged `pkid`, with a PUBACK or PUBCOMP.
+    pub fn acked(&self, pkid: u16) {
+        self.tracking.acked(pkid);
+    }
+
+    pub async fn subscribe(&self, topic: String, qos: QoS) -> Result<()> {
+        match &self.handle {
+            Handle::V311(client) => client.subscribe(topic, qos).await?,
+            Handle::V5(client) => client.subscribe(topic, v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    /// Queues a disconnect without waiting, e.g. from `Drop`.
+    pub fn try_disconnect(&self) -> Result<()> {
+        match &self.handle {
+            Handle::V311(client) => client.try_disconnect()?,
+            Handle::V5(client) => client.try_disconnect()?,
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        match &self.handle {
+            Handle::V311(client) => client.disconnect().await?,
+            Handle::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+impl Handle {
+    async fn publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<()> {
+        match self {
+            Handle::V311(client) => client.publish(topic, qos, retain, payload).await?,
+            Handle::V5(client) if user_properties.is_empty() => {
+                client.publish(topic, v5_qos(qos), retain, payload).await?
+            }
+            Handle::V5(client) => {
+                client
+                    .publish_with_properties(
+                        topic,
+                        v5_qos(qos),
+                        retain,
+                        payload,
+                        PublishProperties {
+                            user_properties,
+                            ..Default::default()
+                        },
+                    )
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    fn try_publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        user_properties: Vec<(String, String)>,
+    ) -> Result<bool> {
+        match self {
+            Handle::V311(client) => match client.try_publish(topic, qos, retain, payload) {
+                Ok(()) => Ok(true),
+                Err(ClientError::TryRequest(_)) => Ok(false),
+                Err(e) => Err(TelemetryError::Mqtt(e)),
+            },
+            Handle::V5(client) => {
+                let result = if user_properties.is_empty() {
+                    client.try_publish(topic, v5_qos(qos), retain, payload)
+                } else {
+                    client.try_publish_with_properties(
+                        topic,
+                        v5_qos(qos),
+                        retain,
+                        payload,
+                        PublishProperties {
+                            user_properties,
+                            ..Default::default()
+                        },
+                    )
+                };
+                match result {
+                    Ok(()) => Ok(true),
+                    Err(v5::ClientError::TryRequest(_)) => Ok(false),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+}
+
+impl MqttEventLoop {
+    pub async fn poll(&mut self) -> std::result::Result<Notification, PollError> {
+        match self {
+            MqttEventLoop::V311(eventloop) => match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(Notification::Connected {
+                    max_packet_size: None,
+                }),
+                Ok(Event::Incoming(Packet::Publish(publish))) => Ok(Notification::Received {
+                    topic: publish.topic,
+                    payload: publish.payload,
+                }),
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Ok(Notification::Sent { pkid }),
+                Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                    Ok(Notification::Acked { pkid: ack.pkid })
+                }
+                Ok(Event::Incoming(Packet::PubComp(comp))) => {
+                    Ok(Notification::Acked { pkid: comp.pkid })
+                }
+                Ok(Event::Incoming(Packet::Disconnect))
+                | Ok(Event::Outgoing(Outgoing::Disconnect)) => Ok(Notification::Disconnected),
+                Ok(event) => {
+                    tracing::trace!("MQTT Event: {:?}", event);
+                    Ok(Notification::Other)
+                }
+                Err(ConnectionError::RequestsDone) => Err(PollError::RequestsDone),
+                Err(e) => Err(PollError::Connection(e.to_string())),
+            },
+            MqttEventLoop::V5(eventloop) => match eventloop.poll().await {
+                Ok(v5::Event::Incoming(PacketV5::ConnAck(connack))) => {
+                    Ok(Notification::Connected {
+                        max_packet_size: connack
+                            .properties
+                            .and_then(|properties| properties.max_packet_size),
+                    })
+                }
+                Ok(v5::Event::Incoming(PacketV5::Publish(publish))) => Ok(Notification::Received {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload,
+                }),
+                Ok(v5::Event::Outgoing(Outgoing::Publish(pkid))) => Ok(Notification::Sent { pkid }),
+                Ok(v5::Event::Incoming(PacketV5::PubAck(ack))) => {
+                    Ok(Notification::Acked { pkid: ack.pkid })
+                }
+                Ok(v5::Event::Incoming(PacketV5::PubComp(comp))) => {
+                    Ok(Notification::Acked { pkid: comp.pkid })
+                }
+                Ok(v5::Event::Incoming(PacketV5::Disconnect(_)))
+                | Ok(v5::Event::Outgoing(Outgoing::Disconnect)) => Ok(Notification::Disconnected),
+                Ok(event) => {
+                    tracing::trace!("MQTT Event: {:?}", event);
+                    Ok(Notification::Other)
+                }
+                Err(v5::ConnectionError::RequestsDone) => Err(PollError::RequestsDone),
+                Err(e) => Err(PollError::Connection(e.to_string())),
+            },
+        }
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
@@ -0,0 +1,79 @@
+//! Time source for everything in telemetry that reads the clock, so tests
+//! can drive rate limits, timestamps and uptimes deterministically.
+
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref PROCESS_START: Instant = Instant::now();
+}
+
+/// Source of monotonic and wall-clock time, set with `TelemetryConfig::clock`.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Time since an arbitrary fixed point. Never goes backwards, so it is
+    /// suitable for ordering samples and measuring intervals.
+    fn now_monotonic(&self) -> Duration;
+
+    /// Time since the Unix epoch.
+    fn now_unix(&self) -> Duration;
+}
+
+/// The system clocks. Monotonic time counts from when the process first
+/// asked for the time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_monotonic(&self) -> Duration {
+        PROCESS_START.elapsed()
+    }
+
+    fn now_unix(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Clock that only moves when `advance` is called.
+#[derive(Debug, Default)]
+pub struct TestClock {
+    monotonic_nanos: AtomicU64,
+    unix_nanos: AtomicU64,
+}
+
+impl TestClock {
+    /// Starts at monotonic time 0 and the given Unix time.
+    pub fn new(unix: Duration) -> Self {
+        Self {
+            monotonic_nanos: AtomicU64::new(0),
+            unix_nanos: AtomicU64::new(unix.as_nanos() as u64),
+        }
+    }
+
+    /// Moves both clocks forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let nanos = by.as_nanos() as u64;
+        self.monotonic_nanos.fetch_add(nanos, Ordering::SeqCst);
+        self.unix_nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+
+    /// Jumps the wall clock, e.g. to simulate an NTP correction. Monotonic
+    /// time is unaffected.
+    pub fn set_unix(&self, unix: Duration) {
+        self.unix_nanos
+            .store(unix.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now_monotonic(&self) -> Duration {
+        Duration::from_nanos(self.monotonic_nanos.load(Ordering::SeqCst))
+    }
+
+    fn now_unix(&self) -> Duration {
+        Duration::from_nanos(self.unix_nanos.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,205 @@
+//! NTP-style estimate of the offset between the robot's wall clock and a
+//! time server's. On every connect the robot sends a few `TimeSyncRequest`s
+//! and keeps the offset from the response with the shortest round trip,
+//! assuming the delay is the same both ways.
+
+use super::connection::ConnectionState;
+use super::payloads::{TimeSyncRequest, TimeSyncResponse, TIME_REQUEST_TOPIC, TIME_RESPONSE_TOPIC};
+use super::sink::OutgoingMessage;
+use super::Telemetry;
+use bytes::Bytes;
+use rumqttc::QoS;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Round trips per measurement.
+const SAMPLES: u32 = 5;
+
+/// Latest measured offset in nanoseconds.
+pub(crate) struct ClockOffset(AtomicI64);
+
+impl Default for ClockOffset {
+    fn default() -> Self {
+        Self(AtomicI64::new(i64::MIN))
+    }
+}
+
+impl ClockOffset {
+    pub fn get(&self) -> Option<i64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|&offset| offset != i64::MIN)
+    }
+
+    fn set(&self, offset: i64) {
+        self.0.store(offset, Ordering::Relaxed);
+    }
+}
+
+pub(crate) async fn run(telemetry: Telemetry, timeout: Duration) {
+    let (tx, mut rx) = mpsc::channel::<Bytes>(SAMPLES as usize);
+    let subscribed = telemetry
+        .subscribe(TIME_RESPONSE_TOPIC, move |payload| {
+            let _ = tx.try_send(payload);
+        })
+        .await;
+    if let Err(e) = subscribed {
+        tracing::warn!("Failed to subscribe to clock sync responses: {}", e);
+        return;
+    }
+
+    let mut state = telemetry.connection.subscribe();
+    loop {
+        if *state.borrow_and_update() == ConnectionState::Connected {
+            match measure(&telemetry, &mut rx, timeout).await {
+                Some(offset) => {
+                    tracing::debug!("Clock offset to time server: {} ns", offset);
+                    telemetry.clock_offset.set(offset);
+                }
+                None => tracing::warn!("No clock sync response within {:?}", timeout),
+            }
+        }
+        if state.changed().await.is_err() || telemetry.connection.is_shutting_down() {
+            break;
+        }
+    }
+}
+
+async fn measure(
+    telemetry: &Telemetry,
+    rx: &mut mpsc::Receiver<Bytes>,
+    timeout: Duration,
+) -> Option<i64> {
+    // Uncorrected wall-clock time, so the offset does not feed back on itself.
+    let now = || telemetry.config.clock.now_unix().as_nanos() as u64;
+    let mut best: Option<(u64, i64)> = None;
+
+    for id in 0..SAMPLES {
+        let request = TimeSyncRequest {
+            id,
+            robot_unix_nanos: now(),
+        };
+        let payload = match serde_json::to_vec(&request) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!("Failed to serialize clock sync request: {}", e);
+                return None;
+            }
+        };
+        let message = OutgoingMessage::new(
+            telemetry.robot_topic(TIME_REQUEST_TOPIC),
+            payload,
+            QoS::AtMostOnce,
+        );
+        if let Err(e) = telemetry.sink.send_message(message).await {
+            tracing::warn!("Failed to publish clock sync request: {}", e);
+            return None;
+        }
+
+        let response = async {
+            while let Some(payload) = rx.recv().await {
+                match serde_json::from_slice::<TimeSyncResponse>(&payload) {
+                    Ok(response)
+                        if response.id == request.id
+                            && response.robot_unix_nanos == request.robot_unix_nanos =>
+                    {
+                        return Some(response);
+                    }
+                    // A late answer to an earlier request.
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to decode clock sync response: {}", e),
+                }
+            }
+            None
+        };
+        let Ok(Some(response)) = tokio::time::timeout(timeout, response).await else {
+            continue;
+        };
+
+        let round_trip = now().saturating_sub(request.robot_unix_nanos);
+        let midpoint = request.robot_unix_nanos + round_trip / 2;
+        let offset = response.server_unix_nanos as i64 - midpoint as i64;
+        if best.is_none_or(|(best_round_trip, _)| round_trip < best_round_trip) {
+            best = Some((round_trip, offset));
+        }
+    }
+
+    best.map(|(_, offset)| offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::{Clock, TestClock};
+    use crate::telemetry::config::TelemetryConfig;
+    use crate::telemetry::error::Result;
+    use crate::telemetry::sink::TelemetrySink;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    /// Answers every request right away, moving the robot's clock by the
+    /// delays of the next round trip.
+    struct TimeServer {
+        clock: Arc<TestClock>,
+        /// The server's clock runs this far ahead of the robot's.
+        ahead: Duration,
+        /// Outbound and return delay of each round trip.
+        delays: Mutex<VecDeque<(Duration, Duration)>>,
+        responses: mpsc::Sender<Bytes>,
+    }
+
+    #[async_trait::async_trait]
+    impl TelemetrySink for TimeServer {
+        async fn send(&self, _topic: String, payload: Vec<u8>, _qos: QoS) -> Result<()> {
+            let request: TimeSyncRequest = serde_json::from_slice(&payload).unwrap();
+            let (outbound, back) = self
+                .delays
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop_front()
+                .unwrap();
+            self.clock.advance(outbound);
+            let response = TimeSyncResponse {
+                id: request.id,
+                robot_unix_nanos: request.robot_unix_nanos,
+                server_unix_nanos: (self.clock.now_unix() + self.ahead).as_nanos() as u64,
+            };
+            self.clock.advance(back);
+            self.responses
+                .try_send(serde_json::to_vec(&response).unwrap().into())
+                .unwrap();
+            Ok(())
+        }
+
+        fn try_send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_offset_of_the_shortest_round_trip() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let (tx, mut rx) = mpsc::channel(SAMPLES as usize);
+        let ms = Duration::from_millis;
+        // Only the shortest round trip is symmetric, the others would skew
+        // the estimate.
+        let delays = [
+            (ms(30), ms(10)),
+            (ms(5), ms(25)),
+            (ms(4), ms(4)),
+            (ms(20), ms(2)),
+            (ms(50), ms(50)),
+        ];
+        let server = Arc::new(TimeServer {
+            clock: clock.clone(),
+            ahead: ms(1500),
+            delays: Mutex::new(delays.into()),
+            responses: tx,
+        });
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.clock = clock;
+        let telemetry = Telemetry::with_sink(config, server);
+
+        let offset = measure(&telemetry, &mut rx, Duration::from_secs(1)).await;
+        assert_eq!(offset, Some(ms(1500).as_nanos() as i64));
+    }
+}
@@ -0,0 +1,75 @@
+use super::config::CompressionCodec;
+use super::error::Result;
+#[cfg(not(feature = "zstd"))]
+use super::error::TelemetryError;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+impl CompressionCodec {
+    /// Topic level appended to compressed messages, after any encoding
+    /// suffix, e.g. `robots/{robot_id}/joints/gzip`.
+    pub(crate) fn topic_suffix(self) -> &'static str {
+        match self {
+            CompressionCodec::Gzip => "gzip",
+            CompressionCodec::Zstd => "zstd",
+        }
+    }
+}
+
+pub(crate) fn compress(codec: CompressionCodec, payload: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        CompressionCodec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(payload)?;
+            Ok(encoder.finish()?)
+        }
+        CompressionCodec::Zstd => zstd_compress(payload),
+    }
+}
+
+/// Decompresses `payload` if it starts with a gzip or zstd header. JSON and
+/// MessagePack bodies never do, so uncompressed payloads are returned as is.
+pub(crate) fn decompress(payload: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if payload.starts_with(GZIP_MAGIC) {
+        let mut out = Vec::new();
+        GzDecoder::new(payload).read_to_end(&mut out)?;
+        Ok(Cow::Owned(out))
+    } else if payload.starts_with(ZSTD_MAGIC) {
+        zstd_decompress(payload).map(Cow::Owned)
+    } else {
+        Ok(Cow::Borrowed(payload))
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(payload: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::encode_all(payload, 0)?)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::decode_all(payload)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_payload: &[u8]) -> Result<Vec<u8>> {
+    Err(zstd_unavailable())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_payload: &[u8]) -> Result<Vec<u8>> {
+    Err(zstd_unavailable())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unavailable() -> TelemetryError {
+    TelemetryError::InvalidConfig(
+        "zstd compression requires kos to be built with the `zstd` feature".to_string(),
+    )
+}
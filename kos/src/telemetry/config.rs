@@ -0,0 +1,1148 @@
+use super::clock::{Clock, SystemClock};
+use super::error::{Result, TelemetryError};
+use super::payloads;
+use super::rate_limit;
+use super::topics;
+use rumqttc::v5::mqttbytes::v5::LastWill as LastWillV5;
+use rumqttc::{LastWill, MqttOptions, QoS, Transport};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// PEM encoded certificates used to connect to the broker over TLS. Requires
+/// the `tls` feature.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub ca_cert: Vec<u8>,
+    pub client_cert: Option<Vec<u8>>,
+    pub client_key: Option<Vec<u8>>,
+}
+
+/// Prints `client_key` as `<redacted>`.
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("ca_cert", &self.ca_cert)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key.as_ref().map(|_| Redacted))
+            .finish()
+    }
+}
+
+/// Stands in for secrets in `Debug` output.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls_configuration(tls: &TlsConfig) -> Result<rumqttc::TlsConfiguration> {
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+        (None, None) => None,
+        _ => {
+            return Err(TelemetryError::InvalidConfig(
+                "TLS client_cert and client_key must be set together".to_string(),
+            ))
+        }
+    };
+
+    Ok(rumqttc::TlsConfiguration::Simple {
+        ca: tls.ca_cert.clone(),
+        alpn: None,
+        client_auth,
+    })
+}
+
+#[cfg(feature = "tls")]
+fn tls_transport(tls: &TlsConfig) -> Result<Transport> {
+    Ok(Transport::tls_with_config(tls_configuration(tls)?))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_transport(_tls: &TlsConfig) -> Result<Transport> {
+    Err(TelemetryError::InvalidConfig(
+        "TLS was configured but kos was built without the `tls` feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "websocket")]
+fn ws_transport() -> Result<Transport> {
+    Ok(Transport::Ws)
+}
+
+#[cfg(not(feature = "websocket"))]
+fn ws_transport() -> Result<Transport> {
+    Err(TelemetryError::InvalidConfig(
+        "WebSocket transport requires kos to be built with the `websocket` feature".to_string(),
+    ))
+}
+
+#[cfg(all(feature = "tls", feature = "websocket"))]
+fn wss_transport(tls: &TlsConfig) -> Result<Transport> {
+    Ok(Transport::wss_with_config(tls_configuration(tls)?))
+}
+
+#[cfg(not(all(feature = "tls", feature = "websocket")))]
+fn wss_transport(_tls: &TlsConfig) -> Result<Transport> {
+    Err(TelemetryError::InvalidConfig(
+        "secure WebSocket transport requires kos to be built with the `tls` and `websocket` features"
+            .to_string(),
+    ))
+}
+
+/// Reads `name` with `parse`, or `None` if it is unset or empty.
+fn env_var<T>(name: &str, parse: impl FnOnce(&str) -> Option<T>) -> Result<Option<T>> {
+    let value = match std::env::var(name) {
+        Ok(value) if value.trim().is_empty() => return Ok(None),
+        Ok(value) => value,
+        Err(std::env::VarError::NotPresent) => return Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return Err(TelemetryError::InvalidConfig(format!(
+                "{} is not valid UTF-8",
+                name
+            )))
+        }
+    };
+    parse(value.trim())
+        .map(Some)
+        .ok_or_else(|| TelemetryError::InvalidConfig(format!("invalid {}: {:?}", name, value)))
+}
+
+/// Exponential backoff applied between MQTT reconnection attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl ReconnectBackoff {
+    pub(crate) fn next(&self, delay: Duration) -> Duration {
+        delay.saturating_mul(2).min(self.max)
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Robot status published to `robots/{robot_id}/{topic}`. The broker
+/// publishes `offline_payload` as the last will if the connection drops
+/// without a clean disconnect, and `online_payload` is published on every
+/// connect. Both are retained so late subscribers see the current status.
+#[derive(Clone, Debug)]
+pub struct LastWillConfig {
+    pub topic: String,
+    pub online_payload: Vec<u8>,
+    pub offline_payload: Vec<u8>,
+}
+
+impl Default for LastWillConfig {
+    fn default() -> Self {
+        Self {
+            topic: payloads::STATUS_TOPIC.to_string(),
+            online_payload: br#"{"status":"online"}"#.to_vec(),
+            offline_payload: br#"{"status":"offline"}"#.to_vec(),
+        }
+    }
+}
+
+/// Measures the robot's clock offset against a time server over MQTT, for
+/// robots without NTP. The server must answer every `TimeSyncRequest` on
+/// `robots/{robot_id}/time/request` with a `TimeSyncResponse` on
+/// `robots/{robot_id}/time/response`.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockSyncConfig {
+    /// Add the measured offset to the `unix_nanos` stamp and to line
+    /// protocol timestamps from `TimestampSource::Unix`.
+    pub apply: bool,
+    /// How long to wait for each response.
+    pub timeout: Duration,
+}
+
+impl Default for ClockSyncConfig {
+    fn default() -> Self {
+        Self {
+            apply: false,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Closed-loop throttling of the `rate_limits`, see
+/// `TelemetryConfig::adaptive_rate`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveRateConfig {
+    /// p99 publish latency above which the link counts as congested.
+    pub p99_threshold: Duration,
+    /// How often the latency is checked and the rates adjusted.
+    pub interval: Duration,
+    /// Factor the rates are multiplied by on every congested check.
+    pub decrease: f32,
+    /// Fraction of the configured rates added back on every check that is
+    /// not congested.
+    pub increase: f32,
+    /// Lowest fraction of the configured rates to throttle to.
+    pub min_scale: f32,
+}
+
+impl Default for AdaptiveRateConfig {
+    fn default() -> Self {
+        Self {
+            p99_threshold: Duration::from_millis(50),
+            interval: Duration::from_secs(1),
+            decrease: 0.5,
+            increase: 0.1,
+            min_scale: 0.1,
+        }
+    }
+}
+
+/// Where `Telemetry::new` sends messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TelemetryMode {
+    #[default]
+    Mqtt,
+    /// Log every topic and payload with `tracing::info!` and never connect
+    /// to a broker, e.g. to check the schema when bringing up a robot.
+    DryRun,
+}
+
+/// Recording to disk while the broker is unreachable, see
+/// `TelemetryConfig::local_fallback`.
+#[derive(Clone, Debug)]
+pub struct LocalFallbackConfig {
+    /// How long the broker may be unreachable, at startup or after losing
+    /// the connection, before switching to local-only mode.
+    pub connect_timeout: Duration,
+    /// Directory the recordings are written to and uploaded from.
+    pub dir: PathBuf,
+    pub gzip: bool,
+}
+
+impl LocalFallbackConfig {
+    /// Gzip compressed recordings in `dir`, after 30 seconds without a
+    /// broker.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(30),
+            dir: dir.into(),
+            gzip: true,
+        }
+    }
+}
+
+/// How the MQTT connection to the broker is carried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MqttTransport {
+    /// Plain TCP, or TLS if `TelemetryConfig::tls` is set.
+    #[default]
+    Tcp,
+    /// WebSocket to `ws://{mqtt_host}:{mqtt_port}{ws_path}`, e.g. for
+    /// brokers behind an HTTP load balancer. Requires the `websocket`
+    /// feature.
+    Ws,
+    /// WebSocket over TLS to `wss://{mqtt_host}:{mqtt_port}{ws_path}`, using
+    /// the certificates in `TelemetryConfig::tls`. Requires the `tls` and
+    /// `websocket` features.
+    Wss,
+}
+
+/// MQTT protocol version spoken to the broker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MqttProtocol {
+    #[default]
+    V311,
+    V5,
+}
+
+/// Wire format used by the typed publishers (`publish_joint_state`,
+/// `publish_imu`, ...). The generic `publish` follows `serialization`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TelemetryFormat {
+    #[default]
+    Json,
+    LineProtocol,
+}
+
+/// How line protocol points map onto InfluxDB measurements and tags, for
+/// schemas that differ from the defaults.
+#[derive(Clone, Debug)]
+pub struct MeasurementMap {
+    /// Measurement name of specific subtopics, replacing
+    /// `IntoLineProtocol::measurement`.
+    pub measurements: HashMap<String, String>,
+    /// Static tags attached to every point, e.g. `site` or `firmware`.
+    pub global_tags: Vec<(String, String)>,
+    /// Attach the robot id as a `robot_id` tag. Otherwise it becomes part of
+    /// the measurement instead, as `{robot_id}_{measurement}`.
+    pub robot_id_tag: bool,
+}
+
+impl Default for MeasurementMap {
+    fn default() -> Self {
+        Self {
+            measurements: HashMap::new(),
+            global_tags: Vec::new(),
+            robot_id_tag: true,
+        }
+    }
+}
+
+/// Encoding of the JSON-style envelope produced by `publish`. MessagePack
+/// messages are published under a `msgpack` topic level, e.g.
+/// `robots/{robot_id}/imu/msgpack`, so consumers know how to decode them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SerializationFormat {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+/// Compression codec for large payloads. `Zstd` requires the `zstd` feature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionCodec {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+/// Compresses payloads larger than `threshold_bytes`. Compressed messages
+/// get a trailing topic level naming the codec, e.g.
+/// `robots/{robot_id}/joints/gzip`.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionConfig {
+    pub codec: CompressionCodec,
+    pub threshold_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: CompressionCodec::default(),
+            threshold_bytes: 1024,
+        }
+    }
+}
+
+/// What `publish` does when a payload fails to serialize. Either way the
+/// failure is counted in `Telemetry::serialize_error_count`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SerializeErrorPolicy {
+    /// Log a warning and drop the message, so a bad payload cannot abort the
+    /// caller's control iteration.
+    #[default]
+    SkipAndCount,
+    /// Return the error to the caller.
+    Propagate,
+}
+
+/// What happens to payloads larger than `TelemetryConfig::max_payload_bytes`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Fail the publish with `TelemetryError::PayloadTooLarge`.
+    #[default]
+    Reject,
+    /// Split batches from `publish_batch` and `BatchSink` into several
+    /// messages that fit. Other payloads are still rejected.
+    Split,
+}
+
+/// What happens to a message that arrives while the offline buffer is full.
+/// Messages lost this way are counted in `Telemetry::overflow_drops`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The publisher waits for room. `try_publish` cannot wait and drops the
+    /// message. Backup brokers drop the oldest message instead, so that an
+    /// unreachable backup cannot stall publishing.
+    Block,
+    /// Drop the message.
+    DropNewest,
+    /// Drop the oldest message of the lowest priority to make room, or the
+    /// message itself if everything buffered has a higher priority.
+    #[default]
+    DropOldest,
+    /// Fail the publish with `TelemetryError::QueueFull`. `try_publish`
+    /// returns `Ok(false)`.
+    Error,
+}
+
+/// Relative importance of a subtopic. Under the byte-rate cap, lower
+/// priorities are dropped first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Fields of a subtopic's payload that may leave the robot over MQTT. Applies
+/// to the keys of `data`, or of each element if `data` is an array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldMask {
+    /// Publish only these fields.
+    Allow(Vec<String>),
+    /// Publish everything except these fields.
+    Deny(Vec<String>),
+}
+
+/// Which clock is used as the InfluxDB point timestamp in line protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampSource {
+    /// The video timestamp set through `update_video_timestamp`.
+    #[default]
+    VideoTimestamp,
+    /// Monotonic nanoseconds since process start (`captured_at_nanos`).
+    Monotonic,
+    /// Wall-clock nanoseconds since the Unix epoch.
+    Unix,
+}
+
+/// What `Telemetry::update_video_timestamp` does with a timestamp older than
+/// the current one. Either way it is counted in
+/// `Telemetry::video_timestamp_reset_count`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VideoTimestampPolicy {
+    /// Take it and log a warning, e.g. for intentional stream restarts.
+    #[default]
+    AllowResets,
+    /// Keep the current timestamp until the stream catches up, so the video
+    /// timestamp never goes backwards.
+    Clamp,
+}
+
+/// How unset `Option` fields of the typed payloads appear in JSON. Line
+/// protocol always leaves them out, as InfluxDB has no null fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoneFields {
+    /// Serialized as `null`.
+    #[default]
+    Null,
+    /// Left out of the object.
+    Omit,
+}
+
+/// How `Telemetry::publish_joint_state` maps joints to topics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JointTopicStrategy {
+    /// One message with every joint on `joints`.
+    #[default]
+    Batched,
+    /// One message per actuator on `joints/{actuator_id}`, so subscribers can
+    /// filter on the broker. Each carries a single-element array, the same
+    /// shape as the batched message.
+    PerActuator,
+}
+
+#[derive(Clone)]
+pub struct TelemetryConfig {
+    pub robot_id: String,
+    pub mode: TelemetryMode,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    /// Further `(host, port)` brokers that receive every message as well,
+    /// e.g. an on-prem backup of a cloud broker. They share all other
+    /// settings with the primary broker.
+    pub backup_brokers: Vec<(String, u16)>,
+    /// Opens a second connection to the primary broker for QoS 0 messages,
+    /// i.e. subtopics set to `AtMostOnce` in `topic_qos`, so high-rate
+    /// sensor streams do not queue behind the acknowledgements of QoS 1
+    /// traffic. Worth enabling when IMU or joint streams run at hundreds of
+    /// Hz next to status and commands. The bulk client connects as
+    /// `kos-{robot_id}-bulk`, without a last will or subscriptions.
+    pub dedicated_bulk_client: bool,
+    /// First topic level, so every topic is
+    /// `{topic_prefix}/{robot_id}/{subtopic}`. May span several levels, e.g.
+    /// `fleet-a/robots`.
+    pub topic_prefix: String,
+    /// Capacity of the request channel between publishers and the MQTT event
+    /// loop. Once it is full, messages are queued in the offline buffer, or
+    /// publishers wait if `buffer_capacity` is zero.
+    pub channel_capacity: usize,
+    /// Upper bound on publishes handed to the sink at the same time. Further
+    /// `publish` calls wait for a slot and `try_publish` returns `Ok(false)`,
+    /// which bounds the tasks piling up on a stalled broker. `None` leaves
+    /// it unbounded.
+    pub max_concurrent_publishes: Option<usize>,
+    pub keep_alive: Duration,
+    pub protocol: MqttProtocol,
+    /// Send the `TelemetryPayload` metadata as MQTT v5 user properties and
+    /// keep only `data` in the body, so brokers and consumers can filter
+    /// without decoding it. Ignored on MQTT 3.1.1, where the metadata stays
+    /// embedded in the body. `Telemetry::stream` expects the embedded form.
+    pub metadata_as_user_properties: bool,
+    /// Maximum number of messages queued while the broker is unreachable or
+    /// the request channel is full. Once full, `overflow_policy` applies.
+    pub buffer_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub reconnect_backoff: ReconnectBackoff,
+    pub format: TelemetryFormat,
+    pub serialization: SerializationFormat,
+    pub compression: Option<CompressionConfig>,
+    pub on_serialize_error: SerializeErrorPolicy,
+    /// Largest payload, after compression, handed to the sink. On MQTT v5 the
+    /// maximum packet size advertised by the broker also applies.
+    pub max_payload_bytes: Option<usize>,
+    pub oversize_policy: OversizePolicy,
+    /// Replace NaN and infinite floats in the typed payloads with `null` in
+    /// JSON, and leave them out of line protocol, which InfluxDB rejects.
+    pub sanitize_non_finite: bool,
+    /// Whether unset fields of the typed payloads are `null` or left out in
+    /// JSON. With `Omit`, so are the fields `sanitize_non_finite` nulled.
+    pub none_fields: NoneFields,
+    pub timestamp_source: TimestampSource,
+    pub measurement_map: MeasurementMap,
+    pub joint_topic_strategy: JointTopicStrategy,
+    pub video_timestamp_policy: VideoTimestampPolicy,
+    /// Also stamp JSON payloads with a wall-clock `unix_nanos` field.
+    pub include_unix_nanos: bool,
+    /// Window used by sinks created with `Telemetry::batch_sink`.
+    pub batch_window: Duration,
+    /// QoS used by `publish` for subtopics not listed in `topic_qos`.
+    pub default_qos: QoS,
+    /// QoS used by `publish` for specific subtopics, e.g. `imu` ->
+    /// `AtMostOnce`. Topics not listed use `default_qos`.
+    pub topic_qos: HashMap<String, QoS>,
+    /// Maximum publish rate in Hz for specific subtopics, between 0.001 and
+    /// 100000. Faster samples are dropped, keeping only the most recent one
+    /// in each window.
+    pub rate_limits: HashMap<String, f32>,
+    /// Scale the `rate_limits` down while the p99 publish latency stays
+    /// above a threshold, which means the link is congested, and back up
+    /// once it recovers. Only topics with a rate limit are throttled; see
+    /// `Telemetry::effective_rates`. `None` keeps the rates fixed.
+    pub adaptive_rate: Option<AdaptiveRateConfig>,
+    /// Upper bound on payload bytes per second, measured after compression.
+    /// Messages over budget are dropped, lowest `topic_priority` first.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Priority of specific subtopics, e.g. `imu` -> `Low`, `status` ->
+    /// `High`. Topics not listed are `Normal`. Messages queued while the
+    /// broker is unreachable or the request channel is full are sent highest
+    /// priority first; within a priority they keep their publish order.
+    pub topic_priority: HashMap<String, Priority>,
+    /// Fields removed from specific subtopics before they are published to
+    /// MQTT, e.g. for privacy. Other sinks, such as a `FileRecorder`, still
+    /// get the full payload. Line protocol payloads are not masked.
+    pub field_masks: HashMap<String, FieldMask>,
+    /// Messages still in the offline buffer this long after they were
+    /// captured are dropped instead of sent when the buffer flushes, and
+    /// counted as expired. `None` keeps them until they are sent.
+    pub message_ttl: Option<Duration>,
+    /// `message_ttl` of specific subtopics, e.g. a short one for `imu`.
+    pub topic_ttl: HashMap<String, Duration>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connect over TLS instead of plain TCP.
+    pub tls: Option<TlsConfig>,
+    pub transport: MqttTransport,
+    /// Path of the WebSocket endpoint, for the `Ws` and `Wss` transports.
+    pub ws_path: String,
+    /// Online/offline status messages. `None` disables the last will.
+    pub last_will: Option<LastWillConfig>,
+    /// Publish a heartbeat to `robots/{robot_id}/heartbeat` at this interval.
+    pub heartbeat_interval: Option<Duration>,
+    /// Publish a `WatchdogAlert` to `robots/{robot_id}/alert` when a listed
+    /// subtopic has not been published to for longer than its interval,
+    /// e.g. because the control loop hung. Each stall alerts once; the
+    /// watchdog rearms on the next publish to the topic.
+    pub watchdog: HashMap<String, Duration>,
+    /// Re-publish the retained `robots/{robot_id}/schema` announcement at
+    /// this interval, on top of publishing it on startup and whenever a
+    /// topic is first published to.
+    pub schema_interval: Option<Duration>,
+    /// Time source for timestamps, rate limits and uptimes. Swap in a
+    /// `TestClock` to make them deterministic in tests.
+    pub clock: Arc<dyn Clock>,
+    /// Keep this many of the most recent payloads of each subtopic in memory
+    /// for `Telemetry::recent`, e.g. for a local debug UI.
+    pub recent_cache_size: Option<usize>,
+    /// `Telemetry::publish_on_change` still publishes an unchanged payload
+    /// once this long has passed since the last one, so consumers can tell a
+    /// quiet topic from a dead one. `None` suppresses it indefinitely.
+    pub on_change_max_interval: Option<Duration>,
+    /// Once the broker has been unreachable this long, stop publishing over
+    /// the network and record everything with a `FileRecorder` instead. The
+    /// connection state reads `LocalOnly` until `Telemetry::resume_network`
+    /// is called, which uploads the recordings. `None` keeps buffering.
+    pub local_fallback: Option<LocalFallbackConfig>,
+    /// Accept `ConfigUpdate`s on `robots/{robot_id}/config`, acked on
+    /// `config/ack`. Anyone who can publish there can change the rate
+    /// limits, mutes, format and enabled flag, so restrict it in the broker
+    /// ACLs.
+    pub remote_config: bool,
+    /// Measure the clock offset to a time server on every connect, see
+    /// `Telemetry::clock_offset`. `None` disables it.
+    pub clock_sync: Option<ClockSyncConfig>,
+}
+
+/// Prints `password` as `<redacted>`.
+impl fmt::Debug for TelemetryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Destructured so a new field cannot be left out by accident.
+        let Self {
+            robot_id,
+            mode,
+            mqtt_host,
+            mqtt_port,
+            backup_brokers,
+            dedicated_bulk_client,
+            topic_prefix,
+            channel_capacity,
+            max_concurrent_publishes,
+            keep_alive,
+            protocol,
+            metadata_as_user_properties,
+            buffer_capacity,
+            overflow_policy,
+            reconnect_backoff,
+            format,
+            serialization,
+            compression,
+            on_serialize_error,
+            max_payload_bytes,
+            oversize_policy,
+            sanitize_non_finite,
+            none_fields,
+            timestamp_source,
+            measurement_map,
+            joint_topic_strategy,
+            video_timestamp_policy,
+            include_unix_nanos,
+            batch_window,
+            default_qos,
+            topic_qos,
+            rate_limits,
+            adaptive_rate,
+            max_bytes_per_sec,
+            topic_priority,
+            field_masks,
+            message_ttl,
+            topic_ttl,
+            username,
+            password,
+            tls,
+            transport,
+            ws_path,
+            last_will,
+            heartbeat_interval,
+            watchdog,
+            schema_interval,
+            clock,
+            recent_cache_size,
+            on_change_max_interval,
+            local_fallback,
+            remote_config,
+            clock_sync,
+        } = self;
+        f.debug_struct("TelemetryConfig")
+            .field("robot_id", robot_id)
+            .field("mode", mode)
+            .field("mqtt_host", mqtt_host)
+            .field("mqtt_port", mqtt_port)
+            .field("backup_brokers", backup_brokers)
+            .field("dedicated_bulk_client", dedicated_bulk_client)
+            .field("topic_prefix", topic_prefix)
+            .field("channel_capacity", channel_capacity)
+            .field("max_concurrent_publishes", max_concurrent_publishes)
+            .field("keep_alive", keep_alive)
+            .field("protocol", protocol)
+            .field("metadata_as_user_properties", metadata_as_user_properties)
+            .field("buffer_capacity", buffer_capacity)
+            .field("overflow_policy", overflow_policy)
+            .field("reconnect_backoff", reconnect_backoff)
+            .field("format", format)
+            .field("serialization", serialization)
+            .field("compression", compression)
+            .field("on_serialize_error", on_serialize_error)
+            .field("max_payload_bytes", max_payload_bytes)
+            .field("oversize_policy", oversize_policy)
+            .field("sanitize_non_finite", sanitize_non_finite)
+            .field("none_fields", none_fields)
+            .field("timestamp_source", timestamp_source)
+            .field("measurement_map", measurement_map)
+            .field("joint_topic_strategy", joint_topic_strategy)
+            .field("video_timestamp_policy", video_timestamp_policy)
+            .field("include_unix_nanos", include_unix_nanos)
+            .field("batch_window", batch_window)
+            .field("default_qos", default_qos)
+            .field("topic_qos", topic_qos)
+            .field("rate_limits", rate_limits)
+            .field("adaptive_rate", adaptive_rate)
+            .field("max_bytes_per_sec", max_bytes_per_sec)
+            .field("topic_priority", topic_priority)
+            .field("field_masks", field_masks)
+            .field("message_ttl", message_ttl)
+            .field("topic_ttl", topic_ttl)
+            .field("username", username)
+            .field("password", &password.as_ref().map(|_| Redacted))
+            .field("tls", tls)
+            .field("transport", transport)
+            .field("ws_path", ws_path)
+            .field("last_will", last_will)
+            .field("heartbeat_interval", heartbeat_interval)
+            .field("watchdog", watchdog)
+            .field("schema_interval", schema_interval)
+            .field("clock", clock)
+            .field("recent_cache_size", recent_cache_size)
+            .field("on_change_max_interval", on_change_max_interval)
+            .field("local_fallback", local_fallback)
+            .field("remote_config", remote_config)
+            .field("clock_sync", clock_sync)
+            .finish()
+    }
+}
+
+impl TelemetryConfig {
+    pub fn new(robot_id: &str, mqtt_host: &str, mqtt_port: u16) -> Self {
+        Self {
+            robot_id: robot_id.to_string(),
+            mqtt_host: mqtt_host.to_string(),
+            mqtt_port,
+            ..Default::default()
+        }
+    }
+
+    /// Defaults overridden by whichever of these environment variables are
+    /// set:
+    ///
+    /// | variable                      | field                                |
+    /// |-------------------------------|--------------------------------------|
+    /// | `KOS_ROBOT_ID`                | `robot_id`                           |
+    /// | `KOS_MQTT_HOST`               | `mqtt_host`                          |
+    /// | `KOS_MQTT_PORT`               | `mqtt_port`                          |
+    /// | `KOS_MQTT_USERNAME`           | `username`                           |
+    /// | `KOS_MQTT_PASSWORD`           | `password`                           |
+    /// | `KOS_MQTT_PROTOCOL`           | `protocol`: `3.1.1` or `5`           |
+    /// | `KOS_TOPIC_PREFIX`            | `topic_prefix`                       |
+    /// | `KOS_TELEMETRY_MODE`          | `mode`: `mqtt` or `dry_run`          |
+    /// | `KOS_TELEMETRY_FORMAT`        | `format`: `json` or `line_protocol`  |
+    /// | `KOS_TELEMETRY_SERIALIZATION` | `serialization`: `json` or `msgpack` |
+    /// | `KOS_TELEMETRY_QOS`           | `default_qos`: `0`, `1` or `2`       |
+    /// | `KOS_KEEP_ALIVE_SECS`         | `keep_alive`                         |
+    /// | `KOS_BUFFER_CAPACITY`         | `buffer_capacity`                    |
+    /// | `KOS_HEARTBEAT_SECS`          | `heartbeat_interval`                 |
+    ///
+    /// Empty variables count as unset. Fails with `InvalidConfig` on values
+    /// that do not parse. Set fields afterwards to override the environment,
+    /// e.g. `TelemetryConfig { robot_id, ..TelemetryConfig::from_env()? }`.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(robot_id) = env_var("KOS_ROBOT_ID", |v| Some(v.to_string()))? {
+            config.robot_id = robot_id;
+        }
+        if let Some(host) = env_var("KOS_MQTT_HOST", |v| Some(v.to_string()))? {
+            config.mqtt_host = host;
+        }
+        if let Some(port) = env_var("KOS_MQTT_PORT", |v| v.parse().ok())? {
+            config.mqtt_port = port;
+        }
+        if let Some(username) = env_var("KOS_MQTT_USERNAME", |v| Some(v.to_string()))? {
+            config.username = Some(username);
+        }
+        if let Some(password) = env_var("KOS_MQTT_PASSWORD", |v| Some(v.to_string()))? {
+            config.password = Some(password);
+        }
+        if let Some(protocol) = env_var("KOS_MQTT_PROTOCOL", |v| match v {
+            "3.1.1" | "311" | "v311" => Some(MqttProtocol::V311),
+            "5" | "v5" => Some(MqttProtocol::V5),
+            _ => None,
+        })? {
+            config.protocol = protocol;
+        }
+        if let Some(prefix) = env_var("KOS_TOPIC_PREFIX", |v| Some(v.to_string()))? {
+            config.topic_prefix = prefix;
+        }
+        if let Some(mode) = env_var("KOS_TELEMETRY_MODE", |v| match v {
+            "mqtt" => Some(TelemetryMode::Mqtt),
+            "dry_run" => Some(TelemetryMode::DryRun),
+            _ => None,
+        })? {
+            config.mode = mode;
+        }
+        if let Some(format) = env_var("KOS_TELEMETRY_FORMAT", |v| match v {
+            "json" => Some(TelemetryFormat::Json),
+            "line_protocol" => Some(TelemetryFormat::LineProtocol),
+            _ => None,
+        })? {
+            config.format = format;
+        }
+        if let Some(serialization) = env_var("KOS_TELEMETRY_SERIALIZATION", |v| match v {
+            "json" => Some(SerializationFormat::Json),
+            "msgpack" => Some(SerializationFormat::MessagePack),
+            _ => None,
+        })? {
+            config.serialization = serialization;
+        }
+        if let Some(qos) = env_var("KOS_TELEMETRY_QOS", |v| match v {
+            "0" => Some(QoS::AtMostOnce),
+            "1" => Some(QoS::AtLeastOnce),
+            "2" => Some(QoS::ExactlyOnce),
+            _ => None,
+        })? {
+            config.default_qos = qos;
+        }
+        if let Some(secs) = env_var("KOS_KEEP_ALIVE_SECS", |v| v.parse().ok())? {
+            config.keep_alive = Duration::from_secs(secs);
+        }
+        if let Some(capacity) = env_var("KOS_BUFFER_CAPACITY", |v| v.parse().ok())? {
+            config.buffer_capacity = capacity;
+        }
+        if let Some(secs) = env_var("KOS_HEARTBEAT_SECS", |v| v.parse::<f64>().ok())? {
+            config.heartbeat_interval = Some(Duration::try_from_secs_f64(secs).map_err(|_| {
+                TelemetryError::InvalidConfig(format!("invalid KOS_HEARTBEAT_SECS: {}", secs))
+            })?);
+        }
+        // The caller may still set the robot id, see above.
+        config.validate_settings()?;
+        Ok(config)
+    }
+
+    pub(crate) fn mqtt_options(&self, client_id: String) -> Result<MqttOptions> {
+        self.validate()?;
+
+        let mut mqtt_options = MqttOptions::new(client_id, self.broker_addr(), self.mqtt_port);
+        mqtt_options.set_keep_alive(self.keep_alive);
+
+        if let Some(username) = &self.username {
+            mqtt_options.set_credentials(
+                username.as_str(),
+                self.password.as_deref().unwrap_or_default(),
+            );
+        }
+
+        if let Some(transport) = self.transport()? {
+            mqtt_options.set_transport(transport);
+        }
+
+        if let Some((topic, payload)) = self.status_message(false) {
+            mqtt_options.set_last_will(LastWill::new(topic, payload, QoS::AtLeastOnce, true));
+        }
+
+        Ok(mqtt_options)
+    }
+
+    pub(crate) fn mqtt_v5_options(&self, client_id: String) -> Result<rumqttc::v5::MqttOptions> {
+        self.validate()?;
+
+        let mut mqtt_options =
+            rumqttc::v5::MqttOptions::new(client_id, self.broker_addr(), self.mqtt_port);
+        mqtt_options.set_keep_alive(self.keep_alive);
+
+        if let Some(username) = &self.username {
+            mqtt_options.set_credentials(
+                username.as_str(),
+                self.password.as_deref().unwrap_or_default(),
+            );
+        }
+
+        if let Some(transport) = self.transport()? {
+            mqtt_options.set_transport(transport);
+        }
+
+        if let Some((topic, payload)) = self.status_message(false) {
+            mqtt_options.set_last_will(LastWillV5::new(
+                topic,
+                payload,
+                rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+                true,
+                None,
+            ));
+        }
+
+        Ok(mqtt_options)
+    }
+
+    /// Broker address as rumqttc expects it, a URL for WebSockets.
+    fn broker_addr(&self) -> String {
+        match self.transport {
+            MqttTransport::Tcp => self.mqtt_host.clone(),
+            MqttTransport::Ws => {
+                format!("ws://{}:{}{}", self.mqtt_host, self.mqtt_port, self.ws_path)
+            }
+            MqttTransport::Wss => {
+                format!(
+                    "wss://{}:{}{}",
+                    self.mqtt_host, self.mqtt_port, self.ws_path
+                )
+            }
+        }
+    }
+
+    /// Transport to set on the MQTT options, or `None` for plain TCP.
+    fn transport(&self) -> Result<Option<Transport>> {
+        match (self.transport, &self.tls) {
+            (MqttTransport::Tcp, None) => Ok(None),
+            (MqttTransport::Tcp, Some(tls)) => tls_transport(tls).map(Some),
+            (MqttTransport::Ws, None) => ws_transport().map(Some),
+            (MqttTransport::Ws, Some(_)) => Err(TelemetryError::InvalidConfig(
+                "TLS over WebSockets is the `Wss` transport".to_string(),
+            )),
+            (MqttTransport::Wss, Some(tls)) => wss_transport(tls).map(Some),
+            (MqttTransport::Wss, None) => Err(TelemetryError::InvalidConfig(
+                "the `Wss` transport needs `tls` to be set".to_string(),
+            )),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        topics::validate_robot_id(&self.robot_id)?;
+        self.validate_settings()
+    }
+
+    /// `validate` without the robot id.
+    fn validate_settings(&self) -> Result<()> {
+        topics::validate(&self.topic_prefix)
+            .map_err(|e| TelemetryError::InvalidConfig(format!("invalid topic prefix: {}", e)))?;
+        if self.topic_prefix.starts_with('/') || self.topic_prefix.ends_with('/') {
+            return Err(TelemetryError::InvalidConfig(
+                "topic prefix must not start or end with '/'".to_string(),
+            ));
+        }
+
+        if self.transport != MqttTransport::Tcp && !self.ws_path.starts_with('/') {
+            return Err(TelemetryError::InvalidConfig(
+                "WebSocket path must start with '/'".to_string(),
+            ));
+        }
+
+        // rumqttc panics on keep-alives below one second.
+        if !self.keep_alive.is_zero() && self.keep_alive < Duration::from_secs(1) {
+            return Err(TelemetryError::InvalidConfig(
+                "MQTT keep-alive must be zero or at least one second".to_string(),
+            ));
+        }
+
+        #[cfg(not(feature = "zstd"))]
+        {
+            if self
+                .compression
+                .is_some_and(|compression| compression.codec == CompressionCodec::Zstd)
+            {
+                return Err(TelemetryError::InvalidConfig(
+                    "zstd compression requires kos to be built with the `zstd` feature".to_string(),
+                ));
+            }
+        }
+
+        if let Some(adaptive) = &self.adaptive_rate {
+            let valid = !adaptive.interval.is_zero()
+                && !adaptive.p99_threshold.is_zero()
+                && adaptive.decrease > 0.0
+                && adaptive.decrease < 1.0
+                && adaptive.increase > 0.0
+                && adaptive.min_scale > 0.0
+                && adaptive.min_scale <= 1.0;
+            if !valid {
+                return Err(TelemetryError::InvalidConfig(format!(
+                    "invalid adaptive rate config: {:?}",
+                    adaptive
+                )));
+            }
+        }
+
+        for (topic, hz) in &self.rate_limits {
+            rate_limit::validate_rate(topic, *hz)?;
+        }
+
+        if self.max_concurrent_publishes == Some(0) {
+            return Err(TelemetryError::InvalidConfig(
+                "max_concurrent_publishes must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.max_bytes_per_sec == Some(0) {
+            return Err(TelemetryError::InvalidConfig(
+                "max_bytes_per_sec must be greater than zero".to_string(),
+            ));
+        }
+
+        if self
+            .heartbeat_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(TelemetryError::InvalidConfig(
+                "heartbeat interval must be greater than zero".to_string(),
+            ));
+        }
+
+        if let Some(topic) = self
+            .watchdog
+            .iter()
+            .find(|(_, interval)| interval.is_zero())
+            .map(|(topic, _)| topic)
+        {
+            return Err(TelemetryError::InvalidConfig(format!(
+                "watchdog interval of {} must be greater than zero",
+                topic
+            )));
+        }
+
+        if self
+            .schema_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(TelemetryError::InvalidConfig(
+                "schema interval must be greater than zero".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// MQTT client id of the main connection.
+    pub(crate) fn client_id(&self) -> String {
+        format!("kos-{}", self.robot_id)
+    }
+
+    /// TTL of messages published to `subtopic`.
+    pub(crate) fn ttl(&self, subtopic: &str) -> Option<Duration> {
+        self.topic_ttl.get(subtopic).copied().or(self.message_ttl)
+    }
+
+    /// `{topic_prefix}/{robot_id}/{subtopic}`.
+    pub(crate) fn robot_topic(&self, subtopic: &str) -> String {
+        format!("{}/{}/{}", self.topic_prefix, self.robot_id, subtopic)
+    }
+
+    /// Full status topic and the online or offline payload, if the last
+    /// will is enabled.
+    pub(crate) fn status_message(&self, online: bool) -> Option<(String, Vec<u8>)> {
+        self.status_message_under(&self.robot_topic(""), online)
+    }
+
+    /// `status_message` for the robot topics under `topic_root`.
+    pub(crate) fn status_message_under(
+        &self,
+        topic_root: &str,
+        online: bool,
+    ) -> Option<(String, Vec<u8>)> {
+        self.last_will.as_ref().map(|last_will| {
+            let payload = if online {
+                &last_will.online_payload
+            } else {
+                &last_will.offline_payload
+            };
+            (
+                format!("{}{}", topic_root, last_will.topic),
+                payload.clone(),
+            )
+        })
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            robot_id: String::new(),
+            mode: TelemetryMode::default(),
+            mqtt_host: "localhost".to_string(),
+            mqtt_port: 1883,
+            backup_brokers: Vec::new(),
+            dedicated_bulk_client: false,
+            topic_prefix: "robots".to_string(),
+            channel_capacity: 10,
+            max_concurrent_publishes: None,
+            keep_alive: Duration::from_secs(5),
+            protocol: MqttProtocol::default(),
+            metadata_as_user_properties: false,
+            buffer_capacity: 1000,
+            overflow_policy: OverflowPolicy::default(),
+            reconnect_backoff: ReconnectBackoff::default(),
+            format: TelemetryFormat::default(),
+            serialization: SerializationFormat::default(),
+            compression: None,
+            on_serialize_error: SerializeErrorPolicy::default(),
+            max_payload_bytes: None,
+            oversize_policy: OversizePolicy::default(),
+            sanitize_non_finite: false,
+            none_fields: NoneFields::default(),
+            timestamp_source: TimestampSource::default(),
+            measurement_map: MeasurementMap::default(),
+            joint_topic_strategy: JointTopicStrategy::default(),
+            video_timestamp_policy: VideoTimestampPolicy::default(),
+            include_unix_nanos: false,
+            batch_window: Duration::from_millis(100),
+            default_qos: QoS::AtLeastOnce,
+            topic_qos: HashMap::new(),
+            rate_limits: HashMap::new(),
+            adaptive_rate: None,
+            max_bytes_per_sec: None,
+            topic_priority: HashMap::new(),
+            field_masks: HashMap::new(),
+            message_ttl: None,
+            topic_ttl: HashMap::new(),
+            username: None,
+            password: None,
+            tls: None,
+            transport: MqttTransport::default(),
+            ws_path: "/mqtt".to_string(),
+            last_will: Some(LastWillConfig::default()),
+            heartbeat_interval: None,
+            watchdog: HashMap::new(),
+            schema_interval: Some(Duration::from_secs(60)),
+            clock: Arc::new(SystemClock),
+            recent_cache_size: None,
+            on_change_max_interval: Some(Duration::from_secs(60)),
+            local_fallback: None,
+            remote_config: false,
+            clock_sync: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_an_empty_robot_id() {
+        let config = TelemetryConfig::new("", "localhost", 1883);
+        assert!(matches!(
+            config.validate(),
+            Err(TelemetryError::InvalidConfig(_))
+        ));
+        assert!(TelemetryConfig::new("robot_1", "localhost", 1883)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_rates_out_of_range() {
+        let mut config = TelemetryConfig::new("robot_1", "localhost", 1883);
+        config.rate_limits.insert("imu".to_string(), 1e-20);
+        assert!(matches!(
+            config.validate(),
+            Err(TelemetryError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.username = Some("operator".to_string());
+        config.password = Some("hunter2".to_string());
+        config.tls = Some(TlsConfig {
+            ca_cert: Vec::new(),
+            client_cert: None,
+            client_key: Some(b"PRIVATE KEY".to_vec()),
+        });
+
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains(r#"username: Some("operator")"#));
+        assert!(debug.contains("password: Some(<redacted>)"));
+        assert!(debug.contains("client_key: Some(<redacted>)"));
+        assert!(!debug.contains(&format!("{:?}", b"PRIVATE KEY".to_vec())));
+    }
+}
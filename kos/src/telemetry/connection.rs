@@ -0,0 +1,151 @@
+use super::clock::Clock;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConnectionState {
+    Connecting = 0,
+    Connected = 1,
+    Disconnected = 2,
+    /// The broker stayed unreachable past
+    /// `LocalFallbackConfig::connect_timeout`, so telemetry is recorded to
+    /// disk until `Telemetry::resume_network` is called.
+    LocalOnly = 3,
+}
+
+impl ConnectionState {
+    /// Decodes the stored MQTT link state, which is never `LocalOnly`.
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ConnectionState::Connected,
+            2 => ConnectionState::Disconnected,
+            _ => ConnectionState::Connecting,
+        }
+    }
+}
+
+/// Connection state shared between the event loop and publishers. The atomic
+/// is the source of truth for cheap reads; the watch channel lets callers
+/// await transitions.
+pub(crate) struct ConnectionTracker {
+    state: AtomicU8,
+    tx: watch::Sender<ConnectionState>,
+    shutting_down: AtomicBool,
+    /// Reports `LocalOnly` over whatever the MQTT link is doing.
+    local_only: AtomicBool,
+    ever_connected: AtomicBool,
+    reconnects: AtomicU64,
+    /// Monotonic time the current connection was established.
+    connected_at: Mutex<Option<Duration>>,
+    last_error: Mutex<Option<String>>,
+    /// Maximum packet size advertised by the broker, 0 if none.
+    max_packet_size: AtomicU32,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConnectionTracker {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let (tx, _rx) = watch::channel(ConnectionState::Connecting);
+        Self {
+            state: AtomicU8::new(ConnectionState::Connecting as u8),
+            tx,
+            shutting_down: AtomicBool::new(false),
+            local_only: AtomicBool::new(false),
+            ever_connected: AtomicBool::new(false),
+            reconnects: AtomicU64::new(0),
+            connected_at: Mutex::new(None),
+            last_error: Mutex::new(None),
+            max_packet_size: AtomicU32::new(0),
+            clock,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        if self.local_only.load(Ordering::SeqCst) {
+            return ConnectionState::LocalOnly;
+        }
+        ConnectionState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Connected
+    }
+
+    pub fn set(&self, state: ConnectionState) {
+        let previous = self.state.swap(state as u8, Ordering::SeqCst);
+        if previous != state as u8 {
+            tracing::debug!("MQTT connection state: {:?}", state);
+            *self
+                .connected_at
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) =
+                (state == ConnectionState::Connected).then(|| self.clock.now_monotonic());
+            if state == ConnectionState::Connected
+                && self.ever_connected.swap(true, Ordering::SeqCst)
+            {
+                self.reconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            self.tx.send_replace(self.state());
+        }
+    }
+
+    pub fn set_local_only(&self, local_only: bool) {
+        if self.local_only.swap(local_only, Ordering::SeqCst) != local_only {
+            tracing::debug!("Telemetry local-only mode: {}", local_only);
+            self.tx.send_replace(self.state());
+        }
+    }
+
+    /// Time since the current connection was established, or `None` while
+    /// disconnected.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.connected_at
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|connected_at| self.clock.now_monotonic().saturating_sub(connected_at))
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn set_error(&self, error: String) {
+        *self
+            .last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(error);
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set_max_packet_size(&self, max: Option<u32>) {
+        self.max_packet_size
+            .store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn max_packet_size(&self) -> Option<u32> {
+        Some(self.max_packet_size.load(Ordering::Relaxed)).filter(|&max| max > 0)
+    }
+
+    /// Marks the connection as closing so the event loop stops instead of
+    /// reconnecting once the disconnect goes out.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.tx.subscribe()
+    }
+}
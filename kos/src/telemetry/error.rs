@@ -0,0 +1,133 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum TelemetryError {
+    /// `Telemetry::initialize` has not been called yet.
+    NotInitialized,
+    /// Telemetry has been turned off with `ENABLE_TELEMETRY` or
+    /// `Telemetry::set_enabled`.
+    Disabled,
+    /// The configuration passed to `initialize_with` is invalid.
+    InvalidConfig(String),
+    /// The topic passed to a publish method is not a valid MQTT topic.
+    InvalidTopic(String),
+    Serialize(serde_json::Error),
+    SerializeMessagePack(rmp_serde::encode::Error),
+    /// A received message, or the body passed to `publish_raw`, could not be
+    /// decoded.
+    Deserialize(serde_json::Error),
+    DeserializeMessagePack(rmp_serde::decode::Error),
+    Mqtt(rumqttc::ClientError),
+    /// Boxed to keep `Result<T>` small, it is returned on every publish.
+    MqttV5(Box<rumqttc::v5::ClientError>),
+    Io(std::io::Error),
+    /// Exporting to an OpenTelemetry collector failed.
+    #[cfg(feature = "otlp")]
+    Otlp(Box<dyn std::error::Error + Send + Sync>),
+    /// The outbound queue is full and the message was not accepted.
+    QueueFull,
+    /// Telemetry was created outside a Tokio runtime, which it needs for the
+    /// MQTT event loop and its background tasks.
+    NoRuntime,
+    /// The encoded payload is larger than `TelemetryConfig::max_payload_bytes`
+    /// or the broker's maximum packet size.
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// `Telemetry::publish_tracked` was called while the broker is
+    /// unreachable, or the connection went away before the acknowledgement.
+    NotConnected,
+    /// The broker did not acknowledge a tracked publish in time.
+    AckTimeout,
+}
+
+pub type Result<T> = std::result::Result<T, TelemetryError>;
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelemetryError::NotInitialized => write!(f, "telemetry is not initialized"),
+            TelemetryError::Disabled => write!(f, "telemetry is disabled"),
+            TelemetryError::InvalidConfig(reason) => {
+                write!(f, "invalid telemetry config: {}", reason)
+            }
+            TelemetryError::InvalidTopic(reason) => {
+                write!(f, "invalid telemetry topic: {}", reason)
+            }
+            TelemetryError::Serialize(e) => write!(f, "failed to serialize payload: {}", e),
+            TelemetryError::SerializeMessagePack(e) => {
+                write!(f, "failed to serialize payload as MessagePack: {}", e)
+            }
+            TelemetryError::Deserialize(e) => write!(f, "failed to decode payload: {}", e),
+            TelemetryError::DeserializeMessagePack(e) => {
+                write!(f, "failed to decode MessagePack payload: {}", e)
+            }
+            TelemetryError::Mqtt(e) => write!(f, "MQTT client error: {}", e),
+            TelemetryError::MqttV5(e) => write!(f, "MQTT v5 client error: {}", e),
+            TelemetryError::Io(e) => write!(f, "telemetry I/O error: {}", e),
+            #[cfg(feature = "otlp")]
+            TelemetryError::Otlp(e) => write!(f, "OTLP export failed: {}", e),
+            TelemetryError::QueueFull => write!(f, "telemetry queue is full"),
+            TelemetryError::NoRuntime => write!(f, "no Tokio runtime is running"),
+            TelemetryError::PayloadTooLarge { size, max } => {
+                write!(
+                    f,
+                    "payload of {} bytes exceeds the {} byte limit",
+                    size, max
+                )
+            }
+            TelemetryError::NotConnected => write!(f, "not connected to the MQTT broker"),
+            TelemetryError::AckTimeout => {
+                write!(f, "timed out waiting for the broker to acknowledge")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TelemetryError::Serialize(e) => Some(e),
+            TelemetryError::SerializeMessagePack(e) => Some(e),
+            TelemetryError::Deserialize(e) => Some(e),
+            TelemetryError::DeserializeMessagePack(e) => Some(e),
+            TelemetryError::Mqtt(e) => Some(e),
+            TelemetryError::MqttV5(e) => Some(e.as_ref()),
+            TelemetryError::Io(e) => Some(e),
+            #[cfg(feature = "otlp")]
+            TelemetryError::Otlp(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for TelemetryError {
+    fn from(e: serde_json::Error) -> Self {
+        TelemetryError::Serialize(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for TelemetryError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        TelemetryError::SerializeMessagePack(e)
+    }
+}
+
+impl From<rumqttc::ClientError> for TelemetryError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        TelemetryError::Mqtt(e)
+    }
+}
+
+impl From<rumqttc::v5::ClientError> for TelemetryError {
+    fn from(e: rumqttc::v5::ClientError) -> Self {
+        TelemetryError::MqttV5(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for TelemetryError {
+    fn from(e: std::io::Error) -> Self {
+        TelemetryError::Io(e)
+    }
+}
@@ -0,0 +1,210 @@
+use super::buffer::OfflineBuffer;
+use super::client::{MqttClient, MqttEventLoop, Notification, PollError};
+use super::config::ReconnectBackoff;
+use super::connection::{ConnectionState, ConnectionTracker};
+use super::inflight::InFlight;
+use super::routing::RoutingCell;
+use super::subscriptions::Subscriptions;
+use rumqttc::QoS;
+use std::sync::Arc;
+
+pub(crate) struct EventLoopContext {
+    pub client: MqttClient,
+    pub connection: Arc<ConnectionTracker>,
+    pub buffer: Arc<OfflineBuffer>,
+    pub in_flight: Arc<InFlight>,
+    pub backoff: ReconnectBackoff,
+    /// Publish the retained online status of `routing` on every connect.
+    pub owns_status: bool,
+    pub routing: Arc<RoutingCell>,
+    pub subscriptions: Arc<Subscriptions>,
+}
+
+/// Drives the MQTT event loop. Polling the same `EventLoop` again after an
+/// error makes rumqttc reconnect, so errors are followed by a backoff delay
+/// rather than ending the task.
+pub(crate) async fn run(mut eventloop: MqttEventLoop, ctx: EventLoopContext) {
+    let mut delay = ctx.backoff.initial;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Notification::Connected { max_packet_size }) => {
+                delay = ctx.backoff.initial;
+                ctx.connection.set_max_packet_size(max_packet_size);
+                ctx.connection.set(ConnectionState::Connected);
+
+                // Publish the online status, restore subscriptions and flush
+                // from a separate task, since requests wait on the channel
+                // that this loop drains.
+                let client = ctx.client.clone();
+                let connection = ctx.connection.clone();
+                let buffer = ctx.buffer.clone();
+                let in_flight = ctx.in_flight.clone();
+                let online_status = ctx
+                    .owns_status
+                    .then(|| ctx.routing.get().online_status.clone())
+                    .flatten();
+                let topics = ctx.subscriptions.topics();
+                tokio::spawn(async move {
+                    if let Some((topic, payload)) = online_status {
+                        match client
+                            .publish(topic, QoS::AtLeastOnce, true, payload, Vec::new())
+                            .await
+                        {
+                            Ok(()) => in_flight.started(),
+                            Err(e) => tracing::warn!("Failed to publish online status: {}", e),
+                        }
+                    }
+                    // The broker forgets subscriptions of clean sessions.
+                    for topic in topics {
+                        if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce).await {
+                            tracing::warn!("Failed to restore subscription: {}", e);
+                        }
+                    }
+                    buffer.flush(&client, &connection, &in_flight).await;
+                });
+            }
+            Ok(Notification::Received { topic, payload }) => {
+                if !ctx.subscriptions.dispatch(&topic, payload) {
+                    tracing::trace!("No handler for MQTT topic {}", topic);
+                }
+            }
+            Ok(Notification::Sent { pkid }) => {
+                ctx.in_flight.sent(pkid);
+                ctx.client.sent(pkid);
+            }
+            Ok(Notification::Acked { pkid }) => {
+                ctx.in_flight.acked(pkid);
+                ctx.client.acked(pkid);
+            }
+            Ok(Notification::Disconnected) => {
+                ctx.connection.set(ConnectionState::Disconnected);
+                if ctx.connection.is_shutting_down() {
+                    tracing::debug!("MQTT disconnected, stopping event loop");
+                    break;
+                }
+            }
+            Ok(Notification::Other) => {}
+            Err(PollError::RequestsDone) => {
+                ctx.connection.set(ConnectionState::Disconnected);
+                tracing::debug!("MQTT client dropped, stopping event loop");
+                break;
+            }
+            Err(PollError::Connection(e)) if ctx.connection.is_shutting_down() => {
+                ctx.connection.set(ConnectionState::Disconnected);
+                tracing::debug!("MQTT error during shutdown, stopping event loop: {}", e);
+                break;
+            }
+            Err(PollError::Connection(e)) => {
+                ctx.connection.set(ConnectionState::Disconnected);
+                ctx.connection.set_error(e.clone());
+                tracing::warn!("MQTT connection error: {}, retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                delay = ctx.backoff.next(delay);
+                ctx.connection.set(ConnectionState::Connecting);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::SystemClock;
+    use crate::telemetry::config::{OverflowPolicy, TelemetryConfig};
+    use crate::telemetry::routing::Routing;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    fn start(
+        port: u16,
+        backoff: ReconnectBackoff,
+    ) -> (tokio::task::JoinHandle<()>, Arc<ConnectionTracker>) {
+        let mut config = TelemetryConfig::new("test_robot", "127.0.0.1", port);
+        config.reconnect_backoff = backoff;
+        let (client, eventloop) = MqttClient::new(&config, "test_client".to_string()).unwrap();
+        let clock = Arc::new(SystemClock);
+        let connection = Arc::new(ConnectionTracker::new(clock.clone()));
+        let ctx = EventLoopContext {
+            client,
+            connection: connection.clone(),
+            buffer: Arc::new(OfflineBuffer::new(16, OverflowPolicy::DropOldest, clock)),
+            in_flight: Arc::new(InFlight::default()),
+            backoff,
+            owns_status: false,
+            routing: Arc::new(RoutingCell::new(Routing::new(
+                &config,
+                config.robot_id.clone(),
+            ))),
+            subscriptions: Arc::new(Subscriptions::default()),
+        };
+        (tokio::spawn(run(eventloop, ctx)), connection)
+    }
+
+    #[tokio::test]
+    async fn unreachable_broker_keeps_retrying() {
+        // Bind and drop a listener to get a port nothing listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(5),
+            max: Duration::from_millis(20),
+        };
+        let (task, connection) = start(port, backoff);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!task.is_finished());
+        assert!(!connection.is_connected());
+        assert!(connection.last_error().is_some());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn reconnect_delay_grows_up_to_the_maximum() {
+        // A "broker" that hangs up on every connection.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(20),
+            max: Duration::from_millis(80),
+        };
+        let (task, _connection) = start(port, backoff);
+
+        let mut attempts = Vec::new();
+        while attempts.len() < 5 {
+            let (stream, _) = listener.accept().await.unwrap();
+            attempts.push(Instant::now());
+            drop(stream);
+        }
+
+        let expected = [20, 40, 80, 80].map(Duration::from_millis);
+        for (gap, expected) in attempts.windows(2).map(|w| w[1] - w[0]).zip(expected) {
+            assert!(
+                gap >= expected,
+                "retried after {:?}, expected {:?}",
+                gap,
+                expected
+            );
+        }
+        assert!(!task.is_finished());
+        task.abort();
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(250),
+        };
+        assert_eq!(backoff.next(backoff.initial), Duration::from_millis(200));
+        assert_eq!(
+            backoff.next(Duration::from_millis(200)),
+            Duration::from_millis(250)
+        );
+        assert_eq!(backoff.next(Duration::MAX), Duration::from_millis(250));
+    }
+}
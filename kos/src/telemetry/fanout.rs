@@ -0,0 +1,151 @@
+use super::error::Result;
+use super::sink::{OutgoingMessage, TelemetrySink};
+use async_trait::async_trait;
+use futures::future::join_all;
+use rumqttc::QoS;
+use std::sync::Arc;
+
+/// Sink that hands every message to all of `sinks`, e.g. a primary and a
+/// backup broker. A send only fails if it fails on every sink, in which case
+/// the first error is returned.
+pub struct FanoutSink {
+    sinks: Vec<Arc<dyn TelemetrySink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Arc<dyn TelemetrySink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+/// Succeeds if any result succeeded, or there were none, and otherwise
+/// returns the first error.
+fn any_ok<T>(results: Vec<Result<T>>) -> Result<()> {
+    let succeeded = results.is_empty() || results.iter().any(Result::is_ok);
+    let mut first_error = None;
+    for e in results.into_iter().filter_map(Result::err) {
+        tracing::warn!("Failed to send telemetry to one of several sinks: {}", e);
+        first_error.get_or_insert(e);
+    }
+    match first_error {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for FanoutSink {
+    async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+        self.send_message(OutgoingMessage::new(topic, payload, qos))
+            .await
+    }
+
+    fn try_send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<bool> {
+        self.try_send_message(OutgoingMessage::new(topic, payload, qos))
+    }
+
+    fn supports_user_properties(&self) -> bool {
+        !self.sinks.is_empty()
+            && self
+                .sinks
+                .iter()
+                .all(|sink| sink.supports_user_properties())
+    }
+
+    async fn send_message(&self, message: OutgoingMessage) -> Result<()> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.send_message(message.clone())),
+        )
+        .await;
+        any_ok(results)
+    }
+
+    /// Returns `Ok(true)` if any sink accepted the message.
+    fn try_send_message(&self, message: OutgoingMessage) -> Result<bool> {
+        let results: Vec<_> = self
+            .sinks
+            .iter()
+            .map(|sink| sink.try_send_message(message.clone()))
+            .collect();
+        let sent = results.iter().any(|result| matches!(result, Ok(true)));
+        any_ok(results).map(|()| sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::sink::MemorySink;
+    use crate::telemetry::TelemetryError;
+
+    struct FailingSink;
+
+    #[async_trait]
+    impl TelemetrySink for FailingSink {
+        async fn send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<()> {
+            Err(TelemetryError::NotConnected)
+        }
+
+        fn try_send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+            Err(TelemetryError::NotConnected)
+        }
+    }
+
+    #[tokio::test]
+    async fn every_sink_receives_the_same_messages() {
+        let (a, b) = (Arc::new(MemorySink::new()), Arc::new(MemorySink::new()));
+        let fanout = FanoutSink::new(vec![a.clone(), b.clone()]);
+        fanout
+            .send(
+                "robots/r/joints".to_string(),
+                b"1".to_vec(),
+                QoS::AtMostOnce,
+            )
+            .await
+            .unwrap();
+        assert!(fanout
+            .try_send("robots/r/imu".to_string(), b"2".to_vec(), QoS::AtMostOnce)
+            .unwrap());
+
+        assert_eq!(a.messages().len(), 2);
+        assert_eq!(a.messages(), b.messages());
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_does_not_block_the_others() {
+        let working = Arc::new(MemorySink::new());
+        let fanout = FanoutSink::new(vec![Arc::new(FailingSink), working.clone()]);
+        fanout
+            .send(
+                "robots/r/joints".to_string(),
+                b"1".to_vec(),
+                QoS::AtMostOnce,
+            )
+            .await
+            .unwrap();
+        assert!(fanout
+            .try_send(
+                "robots/r/joints".to_string(),
+                b"2".to_vec(),
+                QoS::AtMostOnce
+            )
+            .unwrap());
+        assert_eq!(working.messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_only_if_every_sink_fails() {
+        let fanout = FanoutSink::new(vec![Arc::new(FailingSink), Arc::new(FailingSink)]);
+        assert!(matches!(
+            fanout
+                .send("robots/r/joints".to_string(), Vec::new(), QoS::AtMostOnce)
+                .await,
+            Err(TelemetryError::NotConnected)
+        ));
+        assert!(fanout
+            .try_send("robots/r/joints".to_string(), Vec::new(), QoS::AtMostOnce)
+            .is_err());
+    }
+}
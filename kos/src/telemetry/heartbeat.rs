@@ -0,0 +1,110 @@
+use super::error::Result;
+use super::payloads::{Heartbeat, HEARTBEAT_TOPIC};
+use super::Telemetry;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Publishes a heartbeat every `interval` while connected. Heartbeats are
+/// skipped rather than buffered while the broker is unreachable, since a stale
+/// heartbeat says nothing about the robot being alive.
+pub(crate) async fn run(telemetry: Telemetry, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        if telemetry.connection.is_shutting_down() {
+            break;
+        }
+        if let Err(e) = publish(&telemetry).await {
+            tracing::warn!("Failed to publish heartbeat: {}", e);
+        }
+    }
+}
+
+/// Publishes one heartbeat with the current counters, unless disconnected.
+pub(crate) async fn publish(telemetry: &Telemetry) -> Result<()> {
+    let Some(uptime) = telemetry.connection.uptime() else {
+        return Ok(());
+    };
+
+    let counters = telemetry.counters();
+    let heartbeat = Heartbeat {
+        frame_number: counters.frame_number,
+        inference_step: counters.inference_step,
+        connection_uptime_secs: uptime.as_secs_f64(),
+    };
+    telemetry
+        .publish_versioned(HEARTBEAT_TOPIC, &heartbeat)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::TestClock;
+    use crate::telemetry::config::TelemetryConfig;
+    use crate::telemetry::connection::ConnectionState;
+    use crate::telemetry::sink::MemorySink;
+    use crate::telemetry::TelemetryPayload;
+    use std::sync::Arc;
+
+    fn telemetry(clock: Arc<TestClock>) -> (Telemetry, Arc<MemorySink>) {
+        let sink = Arc::new(MemorySink::new());
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.clock = clock;
+        (Telemetry::with_sink(config, sink.clone()), sink)
+    }
+
+    fn heartbeats(sink: &MemorySink) -> Vec<Heartbeat> {
+        sink.messages()
+            .into_iter()
+            .filter(|(topic, _)| topic == "robots/test_robot/heartbeat")
+            .map(|(_, payload)| {
+                serde_json::from_slice::<TelemetryPayload<Heartbeat>>(&payload)
+                    .unwrap()
+                    .data
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn uptime_is_read_from_the_configured_clock() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let (telemetry, sink) = telemetry(clock.clone());
+        telemetry.update_frame_number(42);
+        clock.advance(Duration::from_secs(5));
+
+        publish(&telemetry).await.unwrap();
+        assert_eq!(
+            heartbeats(&sink),
+            vec![Heartbeat {
+                frame_number: 42,
+                inference_step: 0,
+                connection_uptime_secs: 5.0,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn skipped_while_disconnected() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let (telemetry, sink) = telemetry(clock);
+        telemetry.connection.set(ConnectionState::Disconnected);
+
+        publish(&telemetry).await.unwrap();
+        assert!(heartbeats(&sink).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_publishes_once_per_interval() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let (telemetry, sink) = telemetry(clock);
+        let task = tokio::spawn(run(telemetry.clone(), Duration::from_secs(1)));
+
+        // Ticks at 0, 1 and 2 seconds.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(heartbeats(&sink).len(), 3);
+        task.abort();
+    }
+}
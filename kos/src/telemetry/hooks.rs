@@ -0,0 +1,37 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Callback run after each successful publish with the full topic and the
+/// payload bytes as sent.
+pub type PublishHook = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct PublishHooks {
+    hooks: RwLock<Vec<PublishHook>>,
+}
+
+impl PublishHooks {
+    pub fn add(&self, hook: PublishHook) {
+        self.hooks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    pub fn run(&self, topic: &str, payload: &[u8]) {
+        for hook in self
+            .hooks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            hook(topic, payload);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use tokio::sync::Notify;
+
+/// Number of messages handed to rumqttc that have not been completed yet.
+/// QoS 0 messages complete once written to the socket, QoS 1 on PUBACK and
+/// QoS 2 on PUBCOMP. QoS 1 and 2 publishes are matched to their
+/// acknowledgement by packet id.
+#[derive(Default)]
+pub(crate) struct InFlight {
+    count: AtomicUsize,
+    total: AtomicU64,
+    /// Packet ids written to the socket and not acknowledged yet.
+    unacked: Mutex<HashSet<u16>>,
+    idle: Notify,
+}
+
+impl InFlight {
+    pub fn started(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A publish went out on the socket. `pkid` is 0 for QoS 0, which is
+    /// complete at this point. Retransmissions reuse their packet id and are
+    /// only counted once.
+    pub fn sent(&self, pkid: u16) {
+        if pkid == 0 {
+            self.completed();
+        } else {
+            self.lock().insert(pkid);
+        }
+    }
+
+    /// The broker acknowledged `pkid`, with a PUBACK or PUBCOMP.
+    pub fn acked(&self, pkid: u16) {
+        if self.lock().remove(&pkid) {
+            self.completed();
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Total number of messages ever handed to rumqttc.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Waits until nothing is in flight.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.count() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    fn completed(&self) {
+        let previous = self
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if previous == Ok(1) {
+            self.idle.notify_waiters();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<u16>> {
+        self.unacked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
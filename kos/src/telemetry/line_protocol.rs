@@ -0,0 +1,172 @@
+//! Minimal InfluxDB line protocol encoder.
+//!
+//! Each point becomes one line of the form
+//! `measurement,tag=value field=value timestamp`. Fields that are `None` are
+//! omitted, and points without any fields are skipped entirely since InfluxDB
+//! rejects them.
+
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+impl From<f32> for FieldValue {
+    fn from(value: f32) -> Self {
+        FieldValue::Float(value as f64)
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(value: f64) -> Self {
+        FieldValue::Float(value)
+    }
+}
+
+impl From<u32> for FieldValue {
+    fn from(value: u32) -> Self {
+        FieldValue::Integer(value as i64)
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::Integer(value)
+    }
+}
+
+impl From<u64> for FieldValue {
+    fn from(value: u64) -> Self {
+        FieldValue::Integer(value.min(i64::MAX as u64) as i64)
+    }
+}
+
+impl From<bool> for FieldValue {
+    fn from(value: bool) -> Self {
+        FieldValue::Boolean(value)
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        FieldValue::String(value)
+    }
+}
+
+pub trait IntoLineProtocol {
+    fn measurement(&self) -> &'static str;
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)>;
+}
+
+/// Encodes `points` as newline separated lines. `global_tags` and
+/// `global_fields` are attached to every line, and `timestamp` (in
+/// nanoseconds) is used as the point time when set.
+pub fn encode<P: IntoLineProtocol>(
+    points: &[P],
+    global_tags: &[(&str, &str)],
+    global_fields: &[(&str, FieldValue)],
+    timestamp: Option<u64>,
+) -> String {
+    encode_with(
+        points,
+        |point| point.measurement(),
+        global_tags,
+        global_fields,
+        timestamp,
+    )
+}
+
+/// Like `encode`, with the measurement of each point given by
+/// `measurement` instead of `IntoLineProtocol::measurement`.
+pub fn encode_with<P: IntoLineProtocol, M: AsRef<str>>(
+    points: &[P],
+    measurement: impl Fn(&P) -> M,
+    global_tags: &[(&str, &str)],
+    global_fields: &[(&str, FieldValue)],
+    timestamp: Option<u64>,
+) -> String {
+    let mut out = String::new();
+
+    for point in points {
+        let fields: Vec<(&str, FieldValue)> = point
+            .fields()
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        escape_into(&mut out, measurement(point).as_ref(), &[',', ' ']);
+
+        let tags = point.tags();
+        let tags = global_tags
+            .iter()
+            .map(|(key, value)| (*key, *value))
+            .chain(tags.iter().map(|(key, value)| (*key, value.as_str())));
+        for (key, value) in tags {
+            out.push(',');
+            escape_into(&mut out, key, &[',', '=', ' ']);
+            out.push('=');
+            escape_into(&mut out, value, &[',', '=', ' ']);
+        }
+
+        let fields = fields
+            .iter()
+            .map(|(key, value)| (*key, value))
+            .chain(global_fields.iter().map(|(key, value)| (*key, value)));
+        for (i, (key, value)) in fields.enumerate() {
+            out.push(if i == 0 { ' ' } else { ',' });
+            escape_into(&mut out, key, &[',', '=', ' ']);
+            out.push('=');
+            write_field_value(&mut out, value);
+        }
+
+        if let Some(timestamp) = timestamp {
+            let _ = write!(out, " {}", timestamp);
+        }
+    }
+
+    out
+}
+
+fn escape_into(out: &mut String, value: &str, special: &[char]) {
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+fn write_field_value(out: &mut String, value: &FieldValue) {
+    match value {
+        FieldValue::Float(v) => {
+            let _ = write!(out, "{}", v);
+        }
+        FieldValue::Integer(v) => {
+            let _ = write!(out, "{}i", v);
+        }
+        FieldValue::Boolean(v) => {
+            let _ = write!(out, "{}", v);
+        }
+        FieldValue::String(v) => {
+            out.push('"');
+            escape_into(out, v, &['"']);
+            out.push('"');
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Fallback to recording on disk while the broker stays unreachable, see
+//! `TelemetryConfig::local_fallback`.
+
+use super::config::LocalFallbackConfig;
+use super::connection::ConnectionState;
+use super::error::{Result, TelemetryError};
+use super::inflight::InFlight;
+use super::recorder::{self, FileRecorder, RecordingReader};
+use super::sink::{OutgoingMessage, TelemetrySink};
+use super::{Broker, Telemetry};
+use chrono::NaiveDateTime;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The recording telemetry goes to while in `ConnectionState::LocalOnly`.
+#[derive(Default)]
+pub(crate) struct LocalRecording {
+    recorder: Mutex<Option<Arc<FileRecorder>>>,
+}
+
+impl LocalRecording {
+    pub fn recorder(&self) -> Option<Arc<FileRecorder>> {
+        self.lock().clone()
+    }
+
+    fn set(&self, recorder: FileRecorder) {
+        *self.lock() = Some(Arc::new(recorder));
+    }
+
+    pub fn take(&self) -> Option<Arc<FileRecorder>> {
+        self.lock().take()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Arc<FileRecorder>>> {
+        self.recorder.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Switches to local-only mode whenever the broker has been unreachable for
+/// `connect_timeout`, at startup or after losing the connection.
+pub(crate) async fn run(telemetry: Telemetry, fallback: LocalFallbackConfig) {
+    let mut state = telemetry.connection.subscribe();
+    loop {
+        let connected = state.wait_for(|state| *state == ConnectionState::Connected);
+        match tokio::time::timeout(fallback.connect_timeout, connected).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => break,
+            Err(_) if telemetry.connection.is_shutting_down() => break,
+            Err(_) => enter(&telemetry, &fallback),
+        }
+
+        // Wait for the connection to drop, or for `resume_network`.
+        let left = state
+            .wait_for(|state| {
+                !matches!(
+                    state,
+                    ConnectionState::Connected | ConnectionState::LocalOnly
+                )
+            })
+            .await
+            .is_ok();
+        if !left || telemetry.connection.is_shutting_down() {
+            break;
+        }
+    }
+}
+
+fn enter(telemetry: &Telemetry, fallback: &LocalFallbackConfig) {
+    let recorder = match FileRecorder::with_clock(
+        &fallback.dir,
+        &telemetry.config.robot_id,
+        fallback.gzip,
+        telemetry.config.clock.clone(),
+    ) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            tracing::warn!("Failed to start local telemetry recording: {}", e);
+            return;
+        }
+    };
+    tracing::warn!(
+        "MQTT broker unreachable for {:?}, recording telemetry to {}",
+        fallback.connect_timeout,
+        recorder.path().display()
+    );
+    telemetry.local.set(recorder);
+    telemetry.connection.set_local_only(true);
+
+    // What was waiting for the broker goes into the recording as well.
+    let Some(recorder) = telemetry.local.recorder() else {
+        return;
+    };
+    for message in telemetry.buffer.drain() {
+        if let Err(e) = recorder.try_send(message.topic, message.payload, message.qos) {
+            tracing::warn!("Failed to record buffered telemetry: {}", e);
+        }
+    }
+}
+
+/// Finishes `recorder` and, once the broker is connected, uploads every
+/// recording in `dir` to it. Each message waits for room in the MQTT request
+/// channel rather than going through the offline buffer, where it could be
+/// dropped. A recording is deleted only if every message in it was accepted
+/// and the buffer dropped nothing meanwhile. Otherwise it is kept, and sent
+/// again in full by the next `resume_network`.
+pub(crate) async fn upload(telemetry: Telemetry, mut recorder: Arc<FileRecorder>, dir: PathBuf) {
+    // Publishes that picked up the recorder just before it was taken may
+    // still be writing to it. The file is complete once it is dropped.
+    let recorder = loop {
+        match Arc::try_unwrap(recorder) {
+            Ok(recorder) => break recorder,
+            Err(shared) => {
+                recorder = shared;
+                tokio::task::yield_now().await;
+            }
+        }
+    };
+    drop(recorder);
+
+    // Resuming during a connection flap must not upload into the buffer.
+    // Back in local-only mode first, the recordings wait for the next
+    // resume.
+    let state = telemetry
+        .connection
+        .subscribe()
+        .wait_for(|state| {
+            matches!(
+                state,
+                ConnectionState::Connected | ConnectionState::LocalOnly
+            )
+        })
+        .await
+        .map(|state| *state);
+    if !matches!(state, Ok(ConnectionState::Connected)) {
+        return;
+    }
+    let Some(broker) = telemetry.brokers.first() else {
+        return;
+    };
+    telemetry
+        .buffer
+        .flush(&broker.client, &broker.connection, &telemetry.in_flight)
+        .await;
+
+    let recordings = match recordings(&dir, &telemetry.config.robot_id) {
+        Ok(recordings) => recordings,
+        Err(e) => {
+            tracing::warn!("Failed to list local telemetry recordings: {}", e);
+            return;
+        }
+    };
+    let dropped = telemetry.buffer.dropped();
+    let active = telemetry.local.recorder();
+    for path in recordings {
+        // Back in local-only mode since, so this one is still being written.
+        if active.as_ref().is_some_and(|active| active.path() == path) {
+            continue;
+        }
+        match upload_recording(&path, broker, &telemetry.in_flight).await {
+            Ok(_) if telemetry.buffer.dropped() != dropped => {
+                tracing::warn!(
+                    "Keeping uploaded recording {} since buffered telemetry was dropped meanwhile",
+                    path.display()
+                );
+                return;
+            }
+            Ok(uploaded) => {
+                tracing::info!(
+                    "Uploaded {} messages recorded locally in {}",
+                    uploaded,
+                    path.display()
+                );
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!("Failed to remove uploaded recording: {}", e);
+                }
+            }
+            Err(e) => {
+                // Later recordings are newer, so they wait for this one.
+                tracing::warn!("Failed to upload recording {}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+}
+
+/// Publishes the messages recorded in `path` to `broker` in order, and
+/// returns how many there were. Fails once the connection drops.
+async fn upload_recording(path: &Path, broker: &Broker, in_flight: &InFlight) -> Result<usize> {
+    let mut uploaded = 0;
+    for recorded in RecordingReader::open(path)? {
+        let recorded = recorded?;
+        if !broker.connection.is_connected() {
+            return Err(TelemetryError::NotConnected);
+        }
+        // Recordings are written before masking, see `Telemetry::sink_for`.
+        let mut message = OutgoingMessage::new(recorded.topic, recorded.payload, recorded.qos);
+        broker.masks.apply(&mut message)?;
+        broker
+            .client
+            .publish(
+                message.topic,
+                message.qos,
+                message.retain,
+                message.payload,
+                message.user_properties,
+            )
+            .await?;
+        in_flight.started();
+        uploaded += 1;
+    }
+    Ok(uploaded)
+}
+
+/// Recordings of `robot_id` in `dir`, oldest first.
+fn recordings(dir: &Path, robot_id: &str) -> std::io::Result<Vec<PathBuf>> {
+    let mut recordings: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| {
+            let start = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| recording_start(name, robot_id))?;
+            Some((start, path))
+        })
+        .collect();
+    // By start time, then by the counter of recordings started in the same
+    // second, which does not sort as text past 9.
+    recordings.sort();
+    Ok(recordings.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The start time and same-second counter of `name` if it is exactly
+/// `{robot_id}_{start}.kosrec` or `{robot_id}_{start}_{n}.kosrec`, or either
+/// with `.kosrec.gz`, as named by `FileRecorder::create`. Checking the whole
+/// name keeps robot `a` from picking up the recordings of robot `a_b`.
+fn recording_start(name: &str, robot_id: &str) -> Option<(NaiveDateTime, u32)> {
+    let rest = name.strip_prefix(robot_id)?.strip_prefix('_')?;
+    let rest = rest
+        .strip_suffix(".kosrec")
+        .or_else(|| rest.strip_suffix(".kosrec.gz"))?;
+    // `%Y` would also accept a longer or signed year.
+    let len = "20240101_000000".len();
+    let start = rest.get(..len)?;
+    let counter = match &rest[len..] {
+        "" => 0,
+        counter => {
+            let counter = counter.strip_prefix('_')?;
+            if !counter.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            counter.parse().ok().filter(|&counter| counter > 0)?
+        }
+    };
+    let start = NaiveDateTime::parse_from_str(start, recorder::START_FORMAT).ok()?;
+    Some((start, counter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::test_broker::TestBroker;
+    use std::time::Duration;
+
+    #[test]
+    fn matches_only_the_robots_own_recordings() {
+        for name in [
+            "a_20240101_120000.kosrec",
+            "a_20240101_120000.kosrec.gz",
+            "a_20240101_120000_2.kosrec",
+        ] {
+            assert!(recording_start(name, "a").is_some(), "{}", name);
+        }
+        for name in [
+            "a_b_20240101_120000.kosrec",
+            "a_20240101_120000.kosrec.tmp",
+            "a_20240101.kosrec",
+            "a_+20240101_120000.kosrec",
+            "a_20240101_120000_.kosrec",
+            "a_20240101_120000_0.kosrec",
+            "a_20240101_120000_+1.kosrec",
+            "ab_20240101_120000.kosrec",
+            "b_20240101_120000.kosrec",
+        ] {
+            assert!(recording_start(name, "a").is_none(), "{}", name);
+        }
+        assert!(recording_start("a_b_20240101_120000.kosrec", "a_b").is_some());
+    }
+
+    #[test]
+    fn recordings_are_sorted_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("kos-local-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "a_20240102_000000.kosrec",
+            "a_b_20240101_000000.kosrec",
+            "a_20240101_000000_10.kosrec",
+            "a_20240101_000000_2.kosrec",
+            "a_20240101_000000.kosrec.gz",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let names: Vec<_> = recordings(&dir, "a")
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "a_20240101_000000.kosrec.gz",
+                "a_20240101_000000_2.kosrec",
+                "a_20240101_000000_10.kosrec",
+                "a_20240102_000000.kosrec",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_while_local_only_and_uploads_on_resume() {
+        let dir = std::env::temp_dir().join(format!("kos-local-upload-{}", std::process::id()));
+        let broker = TestBroker::start().await;
+        broker.set_rejecting(true);
+        let mut config = broker.config("test_robot");
+        config.local_fallback = Some(LocalFallbackConfig {
+            connect_timeout: Duration::from_millis(500),
+            dir: dir.clone(),
+            gzip: false,
+        });
+        let telemetry = Telemetry::new(config).unwrap();
+
+        let mut state = telemetry.connection_state_changed();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            state.wait_for(|state| *state == ConnectionState::LocalOnly),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(telemetry.connection_state(), ConnectionState::LocalOnly);
+
+        for i in 0..3 {
+            telemetry.publish("joints", &i).await.unwrap();
+        }
+        telemetry.local.recorder().unwrap().flush().unwrap();
+        let paths = recordings(&dir, "test_robot").unwrap();
+        assert_eq!(paths.len(), 1);
+        let recorded: Vec<_> = RecordingReader::open(&paths[0])
+            .unwrap()
+            .map(|message| message.unwrap())
+            .filter(|message| message.topic == "robots/test_robot/joints")
+            .collect();
+        assert_eq!(recorded.len(), 3);
+
+        // Still unreachable, so nothing is uploaded or deleted yet.
+        telemetry.resume_network();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(paths[0].exists());
+        assert!(broker.published().is_empty());
+
+        broker.set_rejecting(false);
+        let published = broker.wait_for_published(3).await;
+        let payloads: Vec<_> = published
+            .iter()
+            .filter(|published| published.topic == "robots/test_robot/joints")
+            .map(|published| published.payload.clone())
+            .collect();
+        let recorded: Vec<_> = recorded
+            .into_iter()
+            .map(|message| message.payload)
+            .collect();
+        assert_eq!(payloads, recorded);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while paths[0].exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the uploaded recording was not removed");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
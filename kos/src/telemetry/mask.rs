@@ -0,0 +1,207 @@
+use super::compression;
+use super::config::{CompressionCodec, FieldMask, TelemetryConfig};
+use super::error::Result;
+use super::routing::RoutingCell;
+use super::sink::OutgoingMessage;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Applies `TelemetryConfig::field_masks` to messages on their way to the
+/// broker.
+pub(crate) struct FieldMasks {
+    /// Its topic root is stripped from topics to get the subtopic.
+    routing: Arc<RoutingCell>,
+    masks: HashMap<String, FieldMask>,
+}
+
+impl FieldMasks {
+    pub fn new(config: &TelemetryConfig, routing: Arc<RoutingCell>) -> Self {
+        Self {
+            routing,
+            masks: config.field_masks.clone(),
+        }
+    }
+
+    /// Removes the masked fields from `message`, re-encoding and
+    /// re-compressing it the same way it was. Payloads that are neither JSON
+    /// nor MessagePack are left as they are.
+    pub fn apply(&self, message: &mut OutgoingMessage) -> Result<()> {
+        if self.masks.is_empty() {
+            return Ok(());
+        }
+        let routing = self.routing.get();
+        let Some(subtopic) = message.topic.strip_prefix(&*routing.topic_root) else {
+            return Ok(());
+        };
+
+        let (subtopic, codec) = if let Some(subtopic) = subtopic.strip_suffix("/gzip") {
+            (subtopic, Some(CompressionCodec::Gzip))
+        } else if let Some(subtopic) = subtopic.strip_suffix("/zstd") {
+            (subtopic, Some(CompressionCodec::Zstd))
+        } else {
+            (subtopic, None)
+        };
+        let (subtopic, msgpack) = match subtopic.strip_suffix("/msgpack") {
+            Some(subtopic) => (subtopic, true),
+            None => (subtopic, false),
+        };
+        let Some(mask) = self.masks.get(subtopic) else {
+            return Ok(());
+        };
+
+        let body = compression::decompress(&message.payload)?;
+        let value = if msgpack {
+            rmp_serde::from_slice::<Value>(&body).ok()
+        } else {
+            serde_json::from_slice::<Value>(&body).ok()
+        };
+        let Some(mut value) = value else {
+            return Ok(());
+        };
+
+        // With the metadata in user properties the body is the data itself.
+        let data = match &mut value {
+            Value::Object(envelope) if message.user_properties.is_empty() => {
+                match envelope.get_mut("data") {
+                    Some(data) => data,
+                    None => return Ok(()),
+                }
+            }
+            data => data,
+        };
+        match data {
+            Value::Object(fields) => mask.retain(fields),
+            Value::Array(items) => {
+                for item in items {
+                    if let Value::Object(fields) = item {
+                        mask.retain(fields);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let body = if msgpack {
+            rmp_serde::to_vec_named(&value)?
+        } else {
+            serde_json::to_vec(&value)?
+        };
+        message.payload = match codec {
+            Some(codec) => compression::compress(codec, &body)?,
+            None => body,
+        };
+        Ok(())
+    }
+}
+
+impl FieldMask {
+    fn retain(&self, fields: &mut serde_json::Map<String, Value>) {
+        match self {
+            FieldMask::Allow(allowed) => fields.retain(|key, _| allowed.contains(key)),
+            FieldMask::Deny(denied) => fields.retain(|key, _| !denied.contains(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::recorder::{FileRecorder, RecordingReader};
+    use crate::telemetry::routing::Routing;
+    use crate::telemetry::test_broker::TestBroker;
+    use crate::telemetry::{Telemetry, TelemetryPayload};
+    use rumqttc::QoS;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn masks(subtopic: &str, mask: FieldMask) -> FieldMasks {
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.field_masks.insert(subtopic.to_string(), mask);
+        let routing = Arc::new(RoutingCell::new(Routing::new(
+            &config,
+            config.robot_id.clone(),
+        )));
+        FieldMasks::new(&config, routing)
+    }
+
+    fn masked(masks: &FieldMasks, topic: &str, data: Value) -> Value {
+        let body = serde_json::to_vec(&json!({ "sequence": 0, "data": data })).unwrap();
+        let mut message = OutgoingMessage::new(topic.to_string(), body, QoS::AtMostOnce);
+        masks.apply(&mut message).unwrap();
+        serde_json::from_slice::<Value>(&message.payload).unwrap()["data"].take()
+    }
+
+    #[test]
+    fn deny_removes_fields_of_objects_and_array_elements() {
+        let masks = masks("gps", FieldMask::Deny(vec!["lat".to_string()]));
+        assert_eq!(
+            masked(
+                &masks,
+                "robots/test_robot/gps",
+                json!({ "lat": 1, "speed": 2 })
+            ),
+            json!({ "speed": 2 })
+        );
+        assert_eq!(
+            masked(
+                &masks,
+                "robots/test_robot/gps",
+                json!([{ "lat": 1 }, { "speed": 2 }])
+            ),
+            json!([{}, { "speed": 2 }])
+        );
+        // Other subtopics are untouched.
+        assert_eq!(
+            masked(&masks, "robots/test_robot/imu", json!({ "lat": 1 })),
+            json!({ "lat": 1 })
+        );
+    }
+
+    #[test]
+    fn allow_keeps_only_listed_fields() {
+        let masks = masks("gps", FieldMask::Allow(vec!["speed".to_string()]));
+        assert_eq!(
+            masked(
+                &masks,
+                "robots/test_robot/gps",
+                json!({ "lat": 1, "speed": 2 })
+            ),
+            json!({ "speed": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn masked_fields_still_reach_other_sinks() {
+        let dir = std::env::temp_dir().join(format!("kos-mask-test-{}", std::process::id()));
+        let broker = TestBroker::start().await;
+        let mut config = broker.config("test_robot");
+        config
+            .field_masks
+            .insert("gps".to_string(), FieldMask::Deny(vec!["lat".to_string()]));
+        let telemetry = Telemetry::new(config).unwrap();
+        let recorder = FileRecorder::create(&dir, "test_robot", false).unwrap();
+        let path = recorder.path().to_path_buf();
+        telemetry.add_sink(Box::new(recorder));
+        assert!(telemetry.wait_connected(Duration::from_secs(5)).await);
+
+        let data = json!({ "lat": 47.5, "speed": 1.25 });
+        telemetry.publish("gps", &data).await.unwrap();
+        let published = broker.wait_for_published(1).await;
+        telemetry.shutdown(Duration::from_secs(1)).await.unwrap();
+        drop(telemetry);
+
+        let sent: TelemetryPayload<Value> = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(sent.data, json!({ "speed": 1.25 }));
+
+        let recorded: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .filter(|message| message.topic == "robots/test_robot/gps")
+            .collect();
+        let recorded: TelemetryPayload<Value> =
+            serde_json::from_slice(&recorded[0].payload).unwrap();
+        assert_eq!(recorded.data, data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
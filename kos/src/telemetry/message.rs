@@ -0,0 +1,153 @@
+use super::config::SerializationFormat;
+use rumqttc::QoS;
+use serde::Serialize;
+use std::cell::Cell;
+use std::io;
+
+/// Largest capacity `serialize_with` preallocates, so one huge payload does
+/// not make every later one allocate as much.
+const MAX_CAPACITY_HINT: usize = 64 * 1024;
+
+thread_local! {
+    /// Length of the last payload serialized on this thread.
+    static LAST_LEN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Serializes into a `Vec` sized after the last payload serialized on this
+/// thread. Consecutive messages tend to be of similar size, so the hot
+/// publish path usually makes a single allocation, which becomes the
+/// payload without being copied.
+fn serialize_with<E>(
+    write: impl FnOnce(&mut Vec<u8>) -> std::result::Result<(), E>,
+) -> std::result::Result<Vec<u8>, E> {
+    let hint = LAST_LEN.get().min(MAX_CAPACITY_HINT);
+    // Some slack so a slightly larger payload does not double the buffer.
+    let mut buffer = Vec::with_capacity(hint + hint / 8);
+    write(&mut buffer)?;
+    LAST_LEN.set(buffer.len());
+    Ok(buffer)
+}
+
+pub(crate) fn to_json<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<Vec<u8>> {
+    serialize_with(|buffer| serde_json::to_writer(buffer, value))
+}
+
+pub(crate) fn to_msgpack<T: Serialize + ?Sized>(
+    value: &T,
+) -> std::result::Result<Vec<u8>, rmp_serde::encode::Error> {
+    serialize_with(|buffer| rmp_serde::encode::write_named(buffer, value))
+}
+
+/// Length of the JSON encoding of `value`, counted without buffering it.
+pub(crate) fn json_len<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<usize> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value)?;
+    Ok(counter.0)
+}
+
+/// Length of the MessagePack encoding of `value`, counted without buffering
+/// it.
+pub(crate) fn msgpack_len<T: Serialize + ?Sized>(
+    value: &T,
+) -> std::result::Result<usize, rmp_serde::encode::Error> {
+    let mut counter = ByteCounter(0);
+    rmp_serde::encode::write_named(&mut counter, value)?;
+    Ok(counter.0)
+}
+
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How a message body is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Json,
+    MessagePack,
+    LineProtocol,
+    /// A fixed layout without the envelope, e.g. `ImuReading::to_binary`.
+    Binary,
+}
+
+impl Encoding {
+    /// Topic level appended after the subtopic so consumers know how to
+    /// decode the body. JSON and line protocol are self-describing text and
+    /// keep the plain topic.
+    pub fn topic_suffix(self) -> Option<&'static str> {
+        match self {
+            Encoding::MessagePack => Some("msgpack"),
+            Encoding::Binary => Some("bin"),
+            Encoding::Json | Encoding::LineProtocol => None,
+        }
+    }
+}
+
+impl From<SerializationFormat> for Encoding {
+    fn from(format: SerializationFormat) -> Self {
+        match format {
+            SerializationFormat::Json => Encoding::Json,
+            SerializationFormat::MessagePack => Encoding::MessagePack,
+        }
+    }
+}
+
+/// An encoded message on its way to the broker. The topic is kept separately
+/// by the publish path since rate limits and QoS are keyed on it.
+pub(crate) struct Message {
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub encoding: Encoding,
+    /// MQTT v5 user properties, empty unless the metadata travels outside
+    /// the body.
+    pub user_properties: Vec<(String, String)>,
+    /// Monotonic capture time, by `TelemetryConfig::clock`, used for the TTL.
+    pub captured_at_nanos: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn encodes_like_serde() {
+        let value = json!({ "q": [0.5, 1.5], "name": "joint" });
+        assert_eq!(
+            to_json(&value).unwrap(),
+            serde_json::to_vec(&value).unwrap()
+        );
+        assert_eq!(
+            to_msgpack(&value).unwrap(),
+            rmp_serde::to_vec_named(&value).unwrap()
+        );
+        assert_eq!(json_len(&value).unwrap(), to_json(&value).unwrap().len());
+        assert_eq!(
+            msgpack_len(&value).unwrap(),
+            to_msgpack(&value).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn capacity_follows_the_last_payload() {
+        let large = json!({ "data": "x".repeat(1000) });
+        let small = json!({ "data": 1 });
+        let first = to_json(&large).unwrap();
+        let second = to_json(&large).unwrap();
+        assert_eq!(first, second);
+        assert!(second.capacity() < 2 * second.len());
+
+        // A huge payload does not inflate the next allocations past the cap.
+        to_json(&json!({ "data": "x".repeat(4 * MAX_CAPACITY_HINT) })).unwrap();
+        let after = to_json(&small).unwrap();
+        assert!(after.capacity() <= MAX_CAPACITY_HINT + MAX_CAPACITY_HINT / 8);
+    }
+}
@@ -0,0 +1,373 @@
+//! Health metrics for the telemetry subsystem itself, rendered in the
+//! Prometheus text exposition format.
+
+use super::clock::Clock;
+use super::{ConnectionState, Telemetry};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counters that are not owned by a more specific component.
+pub(crate) struct Counters {
+    publish_errors: AtomicU64,
+    serialize_errors: AtomicU64,
+    rejected_commands: AtomicU64,
+    video_timestamp_resets: AtomicU64,
+    sanitized_fields: AtomicU64,
+    bytes_published: AtomicU64,
+    bytes_dropped: AtomicU64,
+    /// Monotonic time of the last publish in nanoseconds, or 0 before the
+    /// first.
+    last_published_nanos: AtomicU64,
+    publish_latency: LatencyHistogram,
+    /// Same samples as `publish_latency`, read by the adaptive rate control
+    /// so that it does not compete with `take_publish_latency`.
+    control_latency: LatencyHistogram,
+    clock: Arc<dyn Clock>,
+}
+
+impl Counters {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            publish_errors: AtomicU64::new(0),
+            serialize_errors: AtomicU64::new(0),
+            rejected_commands: AtomicU64::new(0),
+            video_timestamp_resets: AtomicU64::new(0),
+            sanitized_fields: AtomicU64::new(0),
+            bytes_published: AtomicU64::new(0),
+            bytes_dropped: AtomicU64::new(0),
+            last_published_nanos: AtomicU64::new(0),
+            publish_latency: LatencyHistogram::default(),
+            control_latency: LatencyHistogram::default(),
+            clock,
+        }
+    }
+
+    pub fn publish_error(&self) {
+        self.publish_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn publish_errors(&self) -> u64 {
+        self.publish_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn serialize_error(&self) {
+        self.serialize_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn serialize_errors(&self) -> u64 {
+        self.serialize_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_command(&self) {
+        self.rejected_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected_commands(&self) -> u64 {
+        self.rejected_commands.load(Ordering::Relaxed)
+    }
+
+    pub fn video_timestamp_reset(&self) {
+        self.video_timestamp_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn video_timestamp_resets(&self) -> u64 {
+        self.video_timestamp_resets.load(Ordering::Relaxed)
+    }
+
+    pub fn add_sanitized_fields(&self, count: u64) {
+        if count > 0 {
+            self.sanitized_fields.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sanitized_fields(&self) -> u64 {
+        self.sanitized_fields.load(Ordering::Relaxed)
+    }
+
+    /// Records a message of `bytes` handed to the sink.
+    pub fn record_published(&self, bytes: usize) {
+        self.bytes_published
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_published_nanos
+            .store(self.monotonic_nanos().max(1), Ordering::Relaxed);
+    }
+
+    pub fn last_published_ago(&self) -> Option<Duration> {
+        match self.last_published_nanos.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(
+                self.monotonic_nanos().saturating_sub(nanos),
+            )),
+        }
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        self.clock.now_monotonic().as_nanos() as u64
+    }
+
+    pub fn bytes_published(&self) -> u64 {
+        self.bytes_published.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bytes_dropped(&self, bytes: usize) {
+        self.bytes_dropped
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_dropped(&self) -> u64 {
+        self.bytes_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn record_publish_latency(&self, elapsed: Duration) {
+        self.publish_latency.record(elapsed);
+        self.control_latency.record(elapsed);
+    }
+
+    pub fn take_publish_latency(&self) -> LatencyPercentiles {
+        self.publish_latency.take()
+    }
+
+    pub fn take_control_latency(&self) -> LatencyPercentiles {
+        self.control_latency.take()
+    }
+}
+
+/// Summary of the telemetry subsystem returned by `Telemetry::health`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TelemetryHealth {
+    pub connected: bool,
+    /// Messages sent but not yet acknowledged by the broker.
+    pub queued: usize,
+    /// Messages held while the broker is unreachable or busy.
+    pub buffered: usize,
+    /// Time since a message was last handed to the sink, `None` if nothing
+    /// was published yet.
+    pub last_publish_ago: Option<Duration>,
+    pub reconnects: u64,
+    /// Most recent connection error, kept after the connection recovers.
+    pub last_error: Option<String>,
+    /// Messages dropped by the rate limits or because the buffer was full.
+    pub dropped: u64,
+}
+
+/// Publish latency percentiles since the previous read. Each value is the
+/// upper bound of a power-of-two microsecond bucket, so it overestimates by
+/// at most a factor of two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+const LATENCY_BUCKETS: usize = 32;
+
+/// Lock-free histogram with power-of-two microsecond buckets. Bucket `i`
+/// counts durations below `2^i` microseconds (and at least `2^(i-1)`).
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let index = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[index.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads and resets the histogram.
+    fn take(&self) -> LatencyPercentiles {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.swap(0, Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+
+        let percentile = |p: f64| {
+            if count == 0 {
+                return Duration::ZERO;
+            }
+            let rank = ((count as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (i, bucket) in counts.iter().enumerate() {
+                seen += bucket;
+                if seen >= rank {
+                    return Duration::from_micros(1 << i);
+                }
+            }
+            Duration::from_micros(1 << (LATENCY_BUCKETS - 1))
+        };
+
+        LatencyPercentiles {
+            count,
+            p50: percentile(0.50),
+            p90: percentile(0.90),
+            p99: percentile(0.99),
+        }
+    }
+}
+
+struct Renderer<'a> {
+    out: String,
+    labels: &'a str,
+}
+
+impl Renderer<'_> {
+    fn metric(&mut self, name: &str, kind: &str, help: &str, value: u64) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.out, "{}{{{}}} {}", name, self.labels, value);
+    }
+
+    fn labelled(&mut self, name: &str, kind: &str, help: &str, values: &[(&str, u64)]) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        for (label, value) in values {
+            let _ = writeln!(self.out, "{}{{{},{}}} {}", name, self.labels, label, value);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn metrics_text(telemetry: &Telemetry) -> String {
+    let labels = format!("robot_id=\"{}\"", escape_label(&telemetry.robot_id()));
+    let mut renderer = Renderer {
+        out: String::new(),
+        labels: &labels,
+    };
+
+    renderer.metric(
+        "kos_telemetry_published_total",
+        "counter",
+        "Messages handed to the MQTT client.",
+        telemetry.published_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_publish_errors_total",
+        "counter",
+        "Publishes that failed with an error.",
+        telemetry.counters.publish_errors(),
+    );
+    renderer.metric(
+        "kos_telemetry_serialize_errors_total",
+        "counter",
+        "Payloads that failed to serialize.",
+        telemetry.serialize_error_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_rejected_commands_total",
+        "counter",
+        "Received commands that failed to decode.",
+        telemetry.rejected_command_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_video_timestamp_resets_total",
+        "counter",
+        "Video timestamp updates that went backwards.",
+        telemetry.video_timestamp_reset_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_sanitized_fields_total",
+        "counter",
+        "NaN or infinite float fields replaced or dropped.",
+        telemetry.sanitized_field_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_published_bytes_total",
+        "counter",
+        "Payload bytes handed to the sink, after compression.",
+        telemetry.bytes_published(),
+    );
+    renderer.metric(
+        "kos_telemetry_dropped_bytes_total",
+        "counter",
+        "Payload bytes dropped by the byte-rate cap.",
+        telemetry.bytes_dropped(),
+    );
+    renderer.labelled(
+        "kos_telemetry_dropped_total",
+        "counter",
+        "Messages dropped before reaching the broker.",
+        &[
+            ("reason=\"rate_limit\"", telemetry.dropped_by_rate_limit()),
+            ("reason=\"buffer_full\"", telemetry.buffer_dropped_count()),
+            ("reason=\"expired\"", telemetry.expired_count()),
+        ],
+    );
+    renderer.metric(
+        "kos_telemetry_buffered",
+        "gauge",
+        "Messages held while the broker is unreachable.",
+        telemetry.buffered_count() as u64,
+    );
+    renderer.metric(
+        "kos_telemetry_in_flight",
+        "gauge",
+        "Messages sent but not yet acknowledged.",
+        telemetry.in_flight_count() as u64,
+    );
+    renderer.metric(
+        "kos_telemetry_reconnects_total",
+        "counter",
+        "Successful reconnections to the broker.",
+        telemetry.reconnect_count(),
+    );
+    renderer.metric(
+        "kos_telemetry_connected",
+        "gauge",
+        "Whether the MQTT client is currently connected.",
+        (telemetry.connection_state() == ConnectionState::Connected) as u64,
+    );
+
+    renderer.out
+}
+
+/// Serves `metrics_text` on `GET /metrics` until the server fails.
+#[cfg(feature = "prometheus")]
+pub async fn serve(addr: std::net::SocketAddr, telemetry: Telemetry) -> eyre::Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+
+    let make_service = make_service_fn(move |_| {
+        let telemetry = telemetry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let telemetry = telemetry.clone();
+                async move {
+                    if request.uri().path() == "/metrics" {
+                        Response::builder()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .body(Body::from(metrics_text(&telemetry)))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                    }
+                }
+            }))
+        }
+    });
+
+    tracing::info!("Serving telemetry metrics on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
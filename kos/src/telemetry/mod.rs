@@ -0,0 +1,2792 @@
+// TODO: Implement telemetry.
+// General idea - MQTT for the robot, where serial of the robot is a topic.
+// Mosquitto is the broker which will pass messages to InfluxDB
+// We log desired vs actual joint angles (torque/velocity/position if applicable),
+// as well as IMU data.
+
+mod ack;
+mod adaptive;
+mod aggregate;
+mod batch;
+mod buffer;
+mod byte_limit;
+mod changes;
+mod client;
+mod clock;
+mod clock_sync;
+mod compression;
+mod config;
+mod connection;
+mod error;
+mod eventloop;
+mod fanout;
+mod heartbeat;
+mod hooks;
+mod inflight;
+pub mod line_protocol;
+mod local;
+mod mask;
+mod message;
+pub mod metrics;
+mod mqtt_sink;
+mod mute;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod payloads;
+mod rate_limit;
+mod recent;
+pub mod recorder;
+mod registry;
+mod remote_config;
+mod retained;
+mod routing;
+mod sanitize;
+mod schema;
+mod sequence;
+mod sink;
+mod stream;
+mod subscriptions;
+mod sync_handle;
+#[cfg(test)]
+mod test_broker;
+mod topic_stats;
+mod topics;
+pub mod tracing_bridge;
+mod watchdog;
+
+pub use ack::PublishAck;
+pub use aggregate::{AggregationWindow, Aggregator, Summarize};
+pub use batch::BatchSink;
+pub use clock::{Clock, SystemClock, TestClock};
+pub use config::*;
+pub use connection::ConnectionState;
+pub use error::TelemetryError;
+pub use fanout::FanoutSink;
+pub use hooks::PublishHook;
+pub use kos_telemetry_derive::TelemetryPayload;
+#[cfg(feature = "otlp")]
+pub use otlp::OtlpSink;
+pub use recorder::{replay, replay_with, FileRecorder, ReplayControl, ReplaySpeed};
+pub use sink::{DryRunSink, MemorySink, OutgoingMessage, TelemetrySink};
+pub use stream::TelemetryStream;
+pub use sync_handle::SyncTelemetry;
+pub use topic_stats::TopicStats;
+pub use topics::Topic;
+
+use buffer::OfflineBuffer;
+use byte_limit::ByteLimiter;
+use bytes::Bytes;
+use changes::ChangeFilter;
+use client::MqttClient;
+use clock_sync::ClockOffset;
+use connection::ConnectionTracker;
+use error::Result;
+use eventloop::EventLoopContext;
+use hooks::PublishHooks;
+use inflight::InFlight;
+use lazy_static::lazy_static;
+use line_protocol::{FieldValue, IntoLineProtocol};
+use local::LocalRecording;
+use mask::FieldMasks;
+use message::{Encoding, Message};
+use mqtt_sink::MqttSink;
+use mute::Mutes;
+use payloads::{
+    Diagnostics, FleetPayload, ImuReading, JointState, SchemaVersion, SyncFrame, VideoFrameMeta,
+    COMMAND_TOPIC, FLEET_TOPIC,
+};
+use rate_limit::{Admission, RateLimiter, Wake};
+use recent::RecentCache;
+use registry::SinkRegistry;
+use routing::{Routing, RoutingCell};
+use rumqttc::QoS;
+use schema::Schema;
+use sequence::Sequences;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use subscriptions::Subscriptions;
+use tokio::sync::{mpsc, watch, Mutex, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+use topic_stats::TopicCounters;
+
+// All counters use SeqCst so that an update made on one thread is seen by
+// every later read, regardless of which counter is touched.
+const COUNTER_ORDERING: Ordering = Ordering::SeqCst;
+
+/// Number of received messages a `Telemetry::stream` buffers for its
+/// consumer.
+pub const STREAM_CAPACITY: usize = 256;
+
+/// Bytes of an MQTT packet besides the payload, i.e. the header, topic and
+/// properties, reserved when applying the broker's maximum packet size.
+const PACKET_OVERHEAD: usize = 1024;
+
+/// Upper bound on what the envelope adds around a batch's `data`.
+const ENVELOPE_OVERHEAD: usize = 256;
+
+#[derive(Clone)]
+pub struct Telemetry {
+    sink: Arc<dyn TelemetrySink>,
+    /// MQTT connections, the primary broker first. Empty for custom sinks.
+    brokers: Arc<Vec<Broker>>,
+    /// Connection for QoS 0 messages, with `dedicated_bulk_client`.
+    bulk: Option<Arc<BulkLane>>,
+    /// The robot id and its topics, see `set_robot_id`.
+    routing: Arc<RoutingCell>,
+    frame_number: Arc<AtomicU64>,
+    video_timestamp: Arc<AtomicU64>,
+    inference_step: Arc<AtomicU64>,
+    /// Mirrors `inference_step` for `inference_step_changed`.
+    inference_step_tx: Arc<watch::Sender<u64>>,
+    episode_id: Arc<AtomicU64>,
+    connection: Arc<ConnectionTracker>,
+    buffer: Arc<OfflineBuffer>,
+    config: Arc<TelemetryConfig>,
+    /// `TelemetryConfig::format`, changed with `set_format`.
+    format: Arc<RwLock<TelemetryFormat>>,
+    rate_limiter: Arc<RateLimiter>,
+    byte_limiter: Option<Arc<ByteLimiter>>,
+    in_flight: Arc<InFlight>,
+    /// Slots for `TelemetryConfig::max_concurrent_publishes`.
+    publish_permits: Option<Arc<Semaphore>>,
+    counters: Arc<metrics::Counters>,
+    sequences: Arc<Sequences>,
+    changes: Arc<ChangeFilter>,
+    topic_stats: Arc<TopicCounters>,
+    schema: Arc<Schema>,
+    /// Heartbeat and schema tasks, aborted on shutdown.
+    tasks: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+    subscriptions: Arc<Subscriptions>,
+    hooks: Arc<PublishHooks>,
+    registry: Arc<SinkRegistry>,
+    mutes: Arc<Mutes>,
+    /// Shared by every clone handed out to callers, and `None` in the clones
+    /// held by background tasks, so those do not keep the instance alive.
+    teardown: Option<Arc<Teardown>>,
+    clock_offset: Arc<ClockOffset>,
+    /// Where telemetry goes in `ConnectionState::LocalOnly`.
+    local: Arc<LocalRecording>,
+    recent: Option<Arc<RecentCache>>,
+    /// Runtime the instance was created on, for background tasks. `None`
+    /// only for custom sinks created outside a runtime.
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+lazy_static! {
+    // Optional process-wide instance used by `initialize`, `get` and
+    // friends. Instances created with `Telemetry::new` work without it.
+    static ref TELEMETRY: Arc<Mutex<Option<Telemetry>>> = Arc::new(Mutex::new(None));
+    // ENABLE_TELEMETRY only sets the initial value; it can be flipped at
+    // runtime with `Telemetry::set_enabled`.
+    static ref TELEMETRY_ENABLED: AtomicBool = AtomicBool::new(
+        std::env::var("ENABLE_TELEMETRY")
+            .map(|v| v.to_lowercase() != "false")
+            .unwrap_or(true)
+    );
+}
+
+/// State shared between a `Telemetry`, its MQTT sink and the event loop.
+struct Shared {
+    routing: Arc<RoutingCell>,
+    connection: Arc<ConnectionTracker>,
+    buffer: Arc<OfflineBuffer>,
+    in_flight: Arc<InFlight>,
+    counters: Arc<metrics::Counters>,
+    subscriptions: Arc<Subscriptions>,
+}
+
+impl Shared {
+    fn new(config: &TelemetryConfig) -> Self {
+        let clock = &config.clock;
+        Self {
+            routing: Arc::new(RoutingCell::new(Routing::new(
+                config,
+                config.robot_id.clone(),
+            ))),
+            connection: Arc::new(ConnectionTracker::new(clock.clone())),
+            buffer: Arc::new(OfflineBuffer::new(
+                config.buffer_capacity,
+                config.overflow_policy,
+                clock.clone(),
+            )),
+            in_flight: Arc::new(InFlight::default()),
+            counters: Arc::new(metrics::Counters::new(clock.clone())),
+            subscriptions: Arc::new(Subscriptions::default()),
+        }
+    }
+}
+
+/// One MQTT connection of a `Telemetry`.
+struct Broker {
+    client: Arc<MqttClient>,
+    connection: Arc<ConnectionTracker>,
+    masks: Arc<FieldMasks>,
+}
+
+/// Closes the connections once the last caller-held clone of a `Telemetry`
+/// is dropped: background tasks are aborted and every client publishes the
+/// offline status and disconnects, which ends its event loop. Instances
+/// that were shut down are left alone.
+struct Teardown {
+    /// For the offline status, since a clean disconnect does not trigger the
+    /// last will.
+    routing: Arc<RoutingCell>,
+    brokers: Arc<Vec<Broker>>,
+    bulk: Option<Arc<BulkLane>>,
+    tasks: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        for task in self
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain(..)
+        {
+            task.abort();
+        }
+
+        // The bulk client has no status of its own.
+        let routing = self.routing.get();
+        let brokers = self
+            .brokers
+            .iter()
+            .map(|broker| (broker, routing.offline_status.as_ref()))
+            .chain(self.bulk.iter().map(|bulk| (&bulk.broker, None)));
+        for (broker, offline_status) in brokers {
+            if broker.connection.is_shutting_down() {
+                continue;
+            }
+            broker.connection.begin_shutdown();
+            if let Some((topic, payload)) = offline_status {
+                let published = broker.client.try_publish(
+                    topic.clone(),
+                    QoS::AtLeastOnce,
+                    true,
+                    payload.clone(),
+                    Vec::new(),
+                );
+                if let Err(e) = published {
+                    tracing::warn!("Failed to publish offline status: {}", e);
+                }
+            }
+            if let Err(e) = broker.client.try_disconnect() {
+                tracing::warn!("Failed to disconnect dropped telemetry: {}", e);
+            }
+        }
+    }
+}
+
+/// Second client to the primary broker that carries the QoS 0 messages.
+struct BulkLane {
+    broker: Broker,
+    /// The bulk client, mirrored to the backup brokers.
+    sink: Arc<dyn TelemetrySink>,
+}
+
+/// Envelope wrapped around every payload sent with `publish`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TelemetryPayload<T> {
+    /// Counts up from 0 per topic. Gaps mean messages were lost, or dropped
+    /// by the rate limits or byte cap.
+    #[serde(default)]
+    pub sequence: u64,
+    pub frame_number: u64,
+    pub video_timestamp: u64,
+    pub inference_step: u64,
+    /// Set with `Telemetry::start_episode`.
+    #[serde(default)]
+    pub episode_id: u64,
+    /// `SchemaVersion` of `data`, or 0 if it is unversioned. Consumers can
+    /// branch on it as payload structs evolve.
+    #[serde(default)]
+    pub schema_version: u16,
+    /// Monotonic capture time, so consumers can order and space samples
+    /// correctly even when the network delays delivery.
+    pub captured_at_nanos: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix_nanos: Option<u64>,
+    pub data: T,
+}
+
+/// Snapshot of the frame, video, inference and episode counters returned by
+/// `Telemetry::counters`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub frame_number: u64,
+    pub video_timestamp: u64,
+    pub inference_step: u64,
+    pub episode_id: u64,
+}
+
+/// Metadata overrides for `Telemetry::publish_with_meta`. Fields left as
+/// `None` take the current value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PayloadMeta {
+    pub frame_number: Option<u64>,
+    pub video_timestamp: Option<u64>,
+    pub inference_step: Option<u64>,
+    pub episode_id: Option<u64>,
+    pub captured_at_nanos: Option<u64>,
+    /// Set by `publish_versioned` and the typed publishers.
+    pub schema_version: Option<u16>,
+}
+
+impl<T> TelemetryPayload<T> {
+    /// The metadata as MQTT v5 user properties.
+    fn user_properties(&self) -> Vec<(String, String)> {
+        let mut properties = vec![
+            ("sequence".to_string(), self.sequence.to_string()),
+            ("frame_number".to_string(), self.frame_number.to_string()),
+            (
+                "video_timestamp".to_string(),
+                self.video_timestamp.to_string(),
+            ),
+            (
+                "inference_step".to_string(),
+                self.inference_step.to_string(),
+            ),
+            ("episode_id".to_string(), self.episode_id.to_string()),
+            (
+                "schema_version".to_string(),
+                self.schema_version.to_string(),
+            ),
+            (
+                "captured_at_nanos".to_string(),
+                self.captured_at_nanos.to_string(),
+            ),
+        ];
+        if let Some(unix_nanos) = self.unix_nanos {
+            properties.push(("unix_nanos".to_string(), unix_nanos.to_string()));
+        }
+        properties
+    }
+}
+
+impl Telemetry {
+    /// Creates and installs the global instance. This never waits for the
+    /// broker: as long as the config is valid it returns `Ok` straight away
+    /// with the connection `Connecting`, and the event loop keeps trying to
+    /// connect in the background, so an unreachable broker cannot hold up
+    /// boot. Messages published meanwhile go to the offline buffer. Callers
+    /// that do want to block until connected can follow up with
+    /// `wait_connected`.
+    pub async fn initialize(robot_id: &str, mqtt_host: &str, mqtt_port: u16) -> Result<()> {
+        Self::initialize_with(TelemetryConfig::new(robot_id, mqtt_host, mqtt_port)).await
+    }
+
+    /// Creates an instance and installs it as the global one. Like
+    /// `initialize`, this does not wait for the broker.
+    pub async fn initialize_with(config: TelemetryConfig) -> Result<()> {
+        Self::new(config)?.install().await;
+        Ok(())
+    }
+
+    /// Startup convenience for code that is not running inside a Tokio
+    /// runtime, e.g. a synchronous `main`. Runs `initialize_with` to
+    /// completion on `handle`, or on a small runtime owned by telemetry that
+    /// lives for the rest of the process. The runtime behind `handle` must
+    /// keep running, since the MQTT event loop is spawned onto it.
+    ///
+    /// Only meant for bring-up; panics if called from within an async
+    /// context.
+    pub fn initialize_blocking(
+        config: TelemetryConfig,
+        handle: Option<&tokio::runtime::Handle>,
+    ) -> Result<()> {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+
+        if let Some(handle) = handle {
+            return handle.block_on(Self::initialize_with(config));
+        }
+
+        let runtime = match RUNTIME.get() {
+            Some(runtime) => runtime,
+            None => {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .thread_name("kos-telemetry")
+                    .enable_all()
+                    .build()?;
+                RUNTIME.get_or_init(|| runtime)
+            }
+        };
+        runtime.block_on(Self::initialize_with(config))
+    }
+
+    /// Creates a standalone instance with its own MQTT connection. It is not
+    /// visible through `get` unless `install` is called, so several can
+    /// coexist in one process, e.g. one per robot on a test bench. Must be
+    /// called from within a Tokio runtime and returns
+    /// `TelemetryError::NoRuntime` otherwise. The runtime is remembered, so
+    /// the instance can then be used from any thread.
+    ///
+    /// With `TelemetryConfig::backup_brokers`, every message is also sent to
+    /// each backup, and a publish only fails if it fails on all of them. In
+    /// `TelemetryMode::DryRun` messages are logged and no connection is made.
+    pub fn new(mut config: TelemetryConfig) -> Result<Telemetry> {
+        let runtime =
+            tokio::runtime::Handle::try_current().map_err(|_| TelemetryError::NoRuntime)?;
+        if config.mode == TelemetryMode::DryRun {
+            tracing::info!("Telemetry dry run for robot {}", config.robot_id);
+            return Ok(Self::with_sink(config, Arc::new(DryRunSink)));
+        }
+
+        config.robot_id = topics::sanitize_robot_id(&config.robot_id);
+        let config = Arc::new(config);
+        let shared = Shared::new(&config);
+
+        let (primary, primary_sink) =
+            Self::connect(&config, config.client_id(), &shared, &runtime)?;
+        let mut brokers = vec![primary];
+        let mut backups: Vec<Arc<dyn TelemetrySink>> = Vec::new();
+        for (host, port) in &config.backup_brokers {
+            let backup_config = TelemetryConfig {
+                mqtt_host: host.clone(),
+                mqtt_port: *port,
+                overflow_policy: match config.overflow_policy {
+                    OverflowPolicy::Block => OverflowPolicy::DropOldest,
+                    policy => policy,
+                },
+                ..(*config).clone()
+            };
+            // Backups only publish; received messages come from the primary.
+            let backup_shared = Shared {
+                routing: shared.routing.clone(),
+                counters: shared.counters.clone(),
+                ..Shared::new(&backup_config)
+            };
+            let (backup, backup_sink) = Self::connect(
+                &backup_config,
+                backup_config.client_id(),
+                &backup_shared,
+                &runtime,
+            )?;
+            brokers.push(backup);
+            backups.push(Arc::new(backup_sink));
+        }
+
+        let bulk = if config.dedicated_bulk_client {
+            // The main connection owns the status and its last will.
+            let bulk_config = TelemetryConfig {
+                last_will: None,
+                ..(*config).clone()
+            };
+            let bulk_shared = Shared {
+                routing: shared.routing.clone(),
+                counters: shared.counters.clone(),
+                ..Shared::new(&bulk_config)
+            };
+            let (broker, bulk_sink) = Self::connect(
+                &bulk_config,
+                format!("{}-bulk", config.client_id()),
+                &bulk_shared,
+                &runtime,
+            )?;
+            Some(BulkLane {
+                broker,
+                sink: Self::fan_out(Arc::new(bulk_sink), &backups),
+            })
+        } else {
+            None
+        };
+        let sink = Self::fan_out(Arc::new(primary_sink), &backups);
+
+        tracing::debug!("Initializing telemetry for robot {}", config.robot_id);
+        Ok(Self::build(
+            config,
+            sink,
+            brokers,
+            bulk,
+            shared,
+            Some(runtime),
+        ))
+    }
+
+    /// `primary`, mirrored to `backups` if there are any.
+    fn fan_out(
+        primary: Arc<dyn TelemetrySink>,
+        backups: &[Arc<dyn TelemetrySink>],
+    ) -> Arc<dyn TelemetrySink> {
+        if backups.is_empty() {
+            return primary;
+        }
+        let sinks = std::iter::once(primary)
+            .chain(backups.iter().cloned())
+            .collect();
+        Arc::new(FanoutSink::new(sinks))
+    }
+
+    /// Creates the client for the broker in `config`, connecting as
+    /// `client_id`, and spawns its event loop.
+    fn connect(
+        config: &TelemetryConfig,
+        client_id: String,
+        shared: &Shared,
+        runtime: &tokio::runtime::Handle,
+    ) -> Result<(Broker, MqttSink)> {
+        let (client, eventloop) = MqttClient::new(config, client_id)?;
+
+        // Spawn a task to handle MQTT connection events
+        runtime.spawn(eventloop::run(
+            eventloop,
+            EventLoopContext {
+                client: client.clone(),
+                connection: shared.connection.clone(),
+                buffer: shared.buffer.clone(),
+                in_flight: shared.in_flight.clone(),
+                backoff: config.reconnect_backoff,
+                owns_status: config.last_will.is_some(),
+                routing: shared.routing.clone(),
+                subscriptions: shared.subscriptions.clone(),
+            },
+        ));
+
+        let client = Arc::new(client);
+        let masks = Arc::new(FieldMasks::new(config, shared.routing.clone()));
+        let sink = MqttSink {
+            client: client.clone(),
+            connection: shared.connection.clone(),
+            buffer: shared.buffer.clone(),
+            in_flight: shared.in_flight.clone(),
+            counters: shared.counters.clone(),
+            masks: masks.clone(),
+            runtime: runtime.clone(),
+        };
+        let broker = Broker {
+            client,
+            connection: shared.connection.clone(),
+            masks,
+        };
+        Ok((broker, sink))
+    }
+
+    /// Creates a standalone instance that hands every message to `sink`
+    /// instead of MQTT, e.g. a `MemorySink` in tests. The instance always
+    /// reports itself as connected. Outside a Tokio runtime it works without
+    /// its background tasks: no heartbeats or schema announcements are
+    /// published, and samples held by the rate limits are superseded by the
+    /// next one instead of being sent when the window ends.
+    pub fn with_sink(mut config: TelemetryConfig, sink: Arc<dyn TelemetrySink>) -> Telemetry {
+        config.robot_id = topics::sanitize_robot_id(&config.robot_id);
+        // The buffer stays empty, it only sits in front of MQTT.
+        let shared = Shared::new(&config);
+        shared.connection.set(ConnectionState::Connected);
+
+        let runtime = tokio::runtime::Handle::try_current().ok();
+        Self::build(Arc::new(config), sink, Vec::new(), None, shared, runtime)
+    }
+
+    fn build(
+        config: Arc<TelemetryConfig>,
+        sink: Arc<dyn TelemetrySink>,
+        brokers: Vec<Broker>,
+        bulk: Option<BulkLane>,
+        shared: Shared,
+        runtime: Option<tokio::runtime::Handle>,
+    ) -> Telemetry {
+        let telemetry = Telemetry {
+            sink,
+            brokers: Arc::new(brokers),
+            bulk: bulk.map(Arc::new),
+            routing: shared.routing,
+            frame_number: Arc::new(AtomicU64::new(0)),
+            video_timestamp: Arc::new(AtomicU64::new(0)),
+            inference_step: Arc::new(AtomicU64::new(0)),
+            inference_step_tx: Arc::new(watch::channel(0).0),
+            episode_id: Arc::new(AtomicU64::new(0)),
+            connection: shared.connection,
+            buffer: shared.buffer,
+            format: Arc::new(RwLock::new(config.format)),
+            rate_limiter: Arc::new(RateLimiter::new(&config.rate_limits, config.clock.clone())),
+            byte_limiter: config
+                .max_bytes_per_sec
+                .map(|max| Arc::new(ByteLimiter::new(max, config.clock.clone()))),
+            in_flight: shared.in_flight,
+            publish_permits: config
+                .max_concurrent_publishes
+                .map(|max| Arc::new(Semaphore::new(max))),
+            counters: shared.counters,
+            sequences: Arc::new(Sequences::default()),
+            changes: Arc::new(ChangeFilter::default()),
+            topic_stats: Arc::new(TopicCounters::default()),
+            schema: Arc::new(Schema::default()),
+            tasks: Arc::new(std::sync::Mutex::new(Vec::new())),
+            subscriptions: shared.subscriptions,
+            hooks: Arc::new(PublishHooks::default()),
+            registry: Arc::new(SinkRegistry::default()),
+            mutes: Arc::new(Mutes::default()),
+            teardown: None,
+            clock_offset: Arc::new(ClockOffset::default()),
+            local: Arc::new(LocalRecording::default()),
+            recent: config
+                .recent_cache_size
+                .map(|size| Arc::new(RecentCache::new(size))),
+            config: config.clone(),
+            runtime,
+        };
+
+        let Some(runtime) = &telemetry.runtime else {
+            return telemetry.with_teardown();
+        };
+        let mut tasks = telemetry
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(interval) = config.heartbeat_interval {
+            tasks.push(runtime.spawn(heartbeat::run(telemetry.clone(), interval)));
+        }
+        tasks.push(runtime.spawn(schema::run(telemetry.clone(), config.schema_interval)));
+        tasks.push(runtime.spawn(retained::run(telemetry.clone())));
+        if let Some(clock_sync) = config.clock_sync {
+            if !telemetry.brokers.is_empty() {
+                tasks.push(runtime.spawn(clock_sync::run(telemetry.clone(), clock_sync.timeout)));
+            }
+        }
+        if !config.watchdog.is_empty() {
+            tasks.push(runtime.spawn(watchdog::run(telemetry.clone(), config.watchdog.clone())));
+        }
+        if let Some(adaptive) = config.adaptive_rate {
+            tasks.push(runtime.spawn(adaptive::run(telemetry.clone(), adaptive)));
+        }
+        if config.remote_config && !telemetry.brokers.is_empty() {
+            tasks.push(runtime.spawn(remote_config::run(telemetry.clone())));
+        }
+        if let Some(fallback) = config.local_fallback.clone() {
+            if !telemetry.brokers.is_empty() {
+                tasks.push(runtime.spawn(local::run(telemetry.clone(), fallback)));
+            }
+        }
+        drop(tasks);
+
+        telemetry.with_teardown()
+    }
+
+    /// Attaches the `Teardown` to an instance built without one, after the
+    /// background tasks took their clones.
+    fn with_teardown(mut self) -> Telemetry {
+        self.teardown = Some(Arc::new(Teardown {
+            routing: self.routing.clone(),
+            brokers: self.brokers.clone(),
+            bulk: self.bulk.clone(),
+            tasks: self.tasks.clone(),
+        }));
+        self
+    }
+
+    /// Clone for a background task, which does not keep the instance alive.
+    fn background(&self) -> Telemetry {
+        Telemetry {
+            teardown: None,
+            ..self.clone()
+        }
+    }
+
+    /// Makes this instance the global one returned by `get`, replacing any
+    /// previously installed instance.
+    pub async fn install(self) {
+        *TELEMETRY.lock().await = Some(self);
+    }
+
+    /// Removes the global instance and returns it. The instance keeps working
+    /// for anyone still holding a clone.
+    pub async fn uninstall() -> Option<Telemetry> {
+        TELEMETRY.lock().await.take()
+    }
+
+    fn is_same_instance(&self, other: &Telemetry) -> bool {
+        Arc::ptr_eq(&self.connection, &other.connection)
+    }
+
+    /// Returns the global instance, first creating and installing one from
+    /// `config` if none is installed yet. The global lock is held throughout,
+    /// so concurrent callers end up sharing a single instance.
+    pub async fn get_or_init(config: TelemetryConfig) -> Result<Telemetry> {
+        if !Self::is_enabled() {
+            return Err(TelemetryError::Disabled);
+        }
+
+        let mut global = TELEMETRY.lock().await;
+        if let Some(telemetry) = global.as_ref() {
+            return Ok(telemetry.clone());
+        }
+        let telemetry = Self::new(config)?;
+        *global = Some(telemetry.clone());
+        Ok(telemetry)
+    }
+
+    pub fn set_enabled(enabled: bool) {
+        TELEMETRY_ENABLED.store(enabled, Ordering::SeqCst);
+        tracing::info!("Telemetry {}", if enabled { "enabled" } else { "disabled" });
+    }
+
+    pub fn is_enabled() -> bool {
+        TELEMETRY_ENABLED.load(Ordering::SeqCst)
+    }
+
+    pub async fn get() -> Option<Telemetry> {
+        if !Self::is_enabled() {
+            return None;
+        }
+        TELEMETRY.lock().await.clone()
+    }
+
+    /// Like `get`, but says why no instance is available.
+    pub async fn instance() -> Result<Telemetry> {
+        if !Self::is_enabled() {
+            return Err(TelemetryError::Disabled);
+        }
+        TELEMETRY
+            .lock()
+            .await
+            .clone()
+            .ok_or(TelemetryError::NotInitialized)
+    }
+
+    pub async fn publish<T: Serialize>(&self, topic: &str, payload: &T) -> Result<()> {
+        self.publish_with_qos(topic, payload, self.topic_qos(topic))
+            .await
+    }
+
+    /// Publishes only on frames whose `frame_number` is a multiple of
+    /// `every_n`, so the subset that gets through is the same on every run
+    /// and replay. Returns whether the payload was published. `every_n` of 0
+    /// or 1 publishes every frame.
+    pub async fn publish_sampled<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        every_n: u64,
+    ) -> Result<bool> {
+        if !self.get_frame_number().is_multiple_of(every_n.max(1)) {
+            return Ok(false);
+        }
+        self.publish(topic, payload).await?;
+        Ok(true)
+    }
+
+    /// Publishes only if `payload` differs from the last one sent this way
+    /// to `topic`, or `TelemetryConfig::on_change_max_interval` has passed
+    /// since, e.g. for firmware versions or joints that are not moving.
+    /// Returns whether the payload was published, which it is not while the
+    /// topic is muted.
+    pub async fn publish_on_change<T: Serialize>(&self, topic: &str, payload: &T) -> Result<bool> {
+        // Before the change filter, so the payload is still published once
+        // the topic is unmuted.
+        if self.mutes.check(topic) {
+            return Ok(false);
+        }
+        let hash = changes::hash(payload)?;
+        let now = self.config.clock.now_monotonic();
+        if !self
+            .changes
+            .admit(topic, hash, now, self.config.on_change_max_interval)
+        {
+            return Ok(false);
+        }
+        if let Err(e) = self.publish(topic, payload).await {
+            self.changes.forget(topic);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Like `publish`, but returns the approximate number of messages queued
+    /// or awaiting acknowledgement afterwards, so producers can throttle
+    /// before publishes start to wait.
+    pub async fn publish_with_depth<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+    ) -> Result<usize> {
+        self.publish(topic, payload).await?;
+        Ok(self.queue_depth())
+    }
+
+    /// Like `publish`, for one of the canonical topics.
+    pub async fn publish_to<T: Serialize>(&self, topic: Topic, payload: &T) -> Result<()> {
+        self.publish(topic.as_str(), payload).await
+    }
+
+    pub async fn publish_with_qos<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        qos: QoS,
+    ) -> Result<()> {
+        self.publish_inner(topic, payload, qos, &PayloadMeta::default())
+            .await
+    }
+
+    /// Like `publish`, but stamps the payload with the metadata set in
+    /// `meta` instead of the current counters, e.g. to keep the original
+    /// frame numbers when reprocessing recorded data.
+    pub async fn publish_with_meta<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        meta: PayloadMeta,
+    ) -> Result<()> {
+        self.publish_inner(topic, payload, self.topic_qos(topic), &meta)
+            .await
+    }
+
+    /// Publishes `payload` to `fleet/{subtopic}`, outside the per-robot topic
+    /// tree and without the topic prefix, so one subscription to `fleet/#`
+    /// sees every robot. The body is a `FleetPayload` carrying the robot id.
+    /// Meant for low-rate status, so rate limits do not apply; otherwise it
+    /// goes the way of `publish`, keyed on `fleet/{subtopic}` for mutes, QoS
+    /// and the byte cap.
+    pub async fn publish_fleet<T: Serialize>(&self, subtopic: &str, payload: &T) -> Result<()> {
+        topics::validate(subtopic)?;
+        let topic = format!("{}/{}", FLEET_TOPIC, subtopic);
+        if self.mutes.check(&topic) {
+            return Ok(());
+        }
+        let payload = FleetPayload {
+            robot_id: self.robot_id(),
+            data: payload,
+        };
+        let qos = self.topic_qos(&topic);
+        let message = self.encode_payload(&topic, &payload, qos, &PayloadMeta::default())?;
+
+        let full_topic = match message.encoding.topic_suffix() {
+            Some(suffix) => format!("{}/{}", topic, suffix),
+            None => topic.clone(),
+        };
+        self.dispatch_to(&topic, full_topic, message).await
+    }
+
+    /// Like `publish`, for a `data` body that is already serialized JSON, e.g.
+    /// forwarded from another service. With JSON serialization the body is
+    /// only validated and spliced into the envelope as is; MessagePack has to
+    /// parse it to re-encode it. A body that is not valid JSON fails with
+    /// `TelemetryError::Deserialize`.
+    pub async fn publish_raw(&self, topic: &str, body: Bytes) -> Result<()> {
+        match self.config.serialization {
+            SerializationFormat::Json => {
+                let data: &serde_json::value::RawValue =
+                    serde_json::from_slice(&body).map_err(TelemetryError::Deserialize)?;
+                self.publish(topic, &data).await
+            }
+            SerializationFormat::MessagePack => {
+                let data: serde_json::Value =
+                    serde_json::from_slice(&body).map_err(TelemetryError::Deserialize)?;
+                self.publish(topic, &data).await
+            }
+        }
+    }
+
+    /// Like `publish`, stamping the envelope with `T::SCHEMA_VERSION`.
+    pub async fn publish_versioned<T: Serialize + SchemaVersion>(
+        &self,
+        topic: &str,
+        payload: &T,
+    ) -> Result<()> {
+        let meta = PayloadMeta {
+            schema_version: Some(T::SCHEMA_VERSION),
+            ..Default::default()
+        };
+        self.publish_inner(topic, payload, self.topic_qos(topic), &meta)
+            .await
+    }
+
+    async fn publish_inner<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        qos: QoS,
+        meta: &PayloadMeta,
+    ) -> Result<()> {
+        if self.mutes.check(topic) {
+            return Ok(());
+        }
+        let started = self.config.clock.now_monotonic();
+        let Some(message) = self.encode(topic, payload, qos, meta)? else {
+            return Ok(());
+        };
+        let result = self.send(topic, message).await;
+        self.counters
+            .record_publish_latency(self.config.clock.now_monotonic().saturating_sub(started));
+        result
+    }
+
+    /// Publishes without ever waiting, which makes it safe to call from a
+    /// real-time control loop. Returns `Ok(false)` if the message was
+    /// dropped, either because the MQTT request channel or every
+    /// `max_concurrent_publishes` slot is taken, because the topic is muted,
+    /// or because the payload failed to serialize and the policy is to skip
+    /// it.
+    ///
+    /// Delivery is best-effort: messages can be dropped under load, and
+    /// while a reconnection backlog is being flushed they may overtake
+    /// buffered ones.
+    pub fn try_publish<T: Serialize>(&self, topic: &str, payload: &T) -> Result<bool> {
+        if self.mutes.check(topic) {
+            return Ok(false);
+        }
+        let started = self.config.clock.now_monotonic();
+        topics::validate(topic)?;
+        let Some(message) = self.encode(
+            topic,
+            payload,
+            self.topic_qos(topic),
+            &PayloadMeta::default(),
+        )?
+        else {
+            return Ok(false);
+        };
+        let result = match self.rate_limiter.admit(topic, message) {
+            Admission::Send(message) => {
+                let permit = self
+                    .publish_permits
+                    .as_ref()
+                    .map(|permits| permits.try_acquire());
+                match permit {
+                    Some(Err(_)) => {
+                        // The message already took a sequence number, so
+                        // count the gap.
+                        self.topic_stats.dropped(topic);
+                        Ok(false)
+                    }
+                    _ => self.try_dispatch(topic, message),
+                }
+            }
+            Admission::Held { wake_in } => {
+                self.schedule_held(topic, wake_in);
+                Ok(true)
+            }
+            Admission::Replaced => Ok(true),
+        };
+        self.counters
+            .record_publish_latency(self.config.clock.now_monotonic().saturating_sub(started));
+        result
+    }
+
+    /// Publishes straight to the primary broker and returns a handle that
+    /// resolves once the broker acknowledged this message, e.g. to know when
+    /// a command response was delivered. Unlike `publish` it bypasses the
+    /// rate limits and the offline buffer, so it fails with `NotConnected`
+    /// while the broker is unreachable. Backup brokers and sinks added with
+    /// `add_sink` do not get a copy. Muted topics are skipped as by
+    /// `publish`, and their handle resolves right away.
+    pub async fn publish_tracked<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+    ) -> Result<PublishAck> {
+        if self.mutes.check(topic) {
+            return Ok(PublishAck::skipped());
+        }
+        topics::validate(topic)?;
+        let Some(broker) = self
+            .brokers
+            .first()
+            .filter(|broker| broker.connection.is_connected())
+        else {
+            return Err(TelemetryError::NotConnected);
+        };
+
+        self.schema.record(topic, std::any::type_name::<T>(), 0);
+        let message = self
+            .encode_payload(
+                topic,
+                payload,
+                self.topic_qos(topic),
+                &PayloadMeta::default(),
+            )
+            .inspect_err(|_| self.counters.serialize_error())?;
+        let recent = self.recent_copy(&message);
+        let mut message = self.finish(topic, message)?;
+        broker.masks.apply(&mut message)?;
+        let bytes = message.payload.len();
+        let published = self.published(&message);
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let OutgoingMessage {
+            topic: full_topic,
+            payload,
+            qos,
+            user_properties,
+            retain,
+            ..
+        } = message;
+        if let Err(e) = broker
+            .client
+            .publish_tracked(full_topic, qos, retain, payload, user_properties, tx)
+            .await
+        {
+            self.counters.publish_error();
+            return Err(e);
+        }
+        self.in_flight.started();
+        self.after_publish(topic, bytes, published, recent);
+        Ok(PublishAck::new(rx))
+    }
+
+    /// Up to `n` of the most recent payloads published to `subtopic`, oldest
+    /// first, with the `TelemetryConfig::clock` monotonic time they were
+    /// published at. Payloads are encoded but not compressed. Always empty
+    /// unless `TelemetryConfig::recent_cache_size` is set.
+    pub fn recent(&self, subtopic: &str, n: usize) -> Vec<(Duration, Bytes)> {
+        match &self.recent {
+            Some(cache) => cache.recent(subtopic, n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sends every message published from now on to `sink` as well, e.g. a
+    /// `FileRecorder` next to MQTT. Its failures are logged and neither fail
+    /// the publish nor affect other sinks. Field masks, the bulk client and
+    /// retained status and schema messages only concern the main sink.
+    pub fn add_sink(&self, sink: Box<dyn TelemetrySink>) {
+        self.registry.add(Arc::from(sink));
+    }
+
+    /// Stops publishing to subtopics matching `pattern` until `unmute` is
+    /// called with the same pattern, e.g. to silence a noisy topic while
+    /// debugging. `*` matches anything within one topic level, so
+    /// `joints/*` mutes every per-actuator joint topic. Publishes to muted
+    /// topics are dropped and counted in `muted_count`: `publish` returns
+    /// `Ok(())`, `publish_tracked` an already resolved handle, while
+    /// `try_publish` and `publish_on_change` return `Ok(false)` as for any
+    /// message they did not send.
+    pub fn mute(&self, pattern: &str) {
+        self.mutes.mute(pattern);
+    }
+
+    /// Lifts a `mute`. Returns whether `pattern` was muted.
+    pub fn unmute(&self, pattern: &str) -> bool {
+        self.mutes.unmute(pattern)
+    }
+
+    /// Limits `subtopic` to `hz` messages per second like
+    /// `TelemetryConfig::rate_limits`, or lifts its limit with `None`. A rate
+    /// outside the range the config accepts is ignored with a warning.
+    pub fn set_rate_limit(&self, subtopic: &str, hz: Option<f32>) {
+        self.rate_limiter.set_rate(subtopic, hz);
+    }
+
+    /// Messages per second currently allowed on each rate limited subtopic,
+    /// lower than configured while `TelemetryConfig::adaptive_rate` is
+    /// throttling.
+    pub fn effective_rates(&self) -> Vec<(String, f32)> {
+        self.rate_limiter.effective_rates()
+    }
+
+    /// Switches the encoding of the typed payloads, see
+    /// `TelemetryConfig::format`.
+    pub fn set_format(&self, format: TelemetryFormat) {
+        *self.format.write().unwrap_or_else(PoisonError::into_inner) = format;
+    }
+
+    fn format(&self) -> TelemetryFormat {
+        *self.format.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of publishes skipped because their topic was muted.
+    pub fn muted_count(&self) -> u64 {
+        self.mutes.muted()
+    }
+
+    /// Registers `hook` to run after every successful publish, with the full
+    /// topic and the payload as sent. Hooks run synchronously on the
+    /// publishing task in the order they were added, so they should be cheap.
+    pub fn on_publish(&self, hook: PublishHook) {
+        self.hooks.add(hook);
+    }
+
+    /// Calls `handler` with the payload of every message received on
+    /// `robots/{robot_id}/{subtopic}`, replacing any handler already
+    /// registered for it. `subtopic` may contain the `+` and `#` wildcards.
+    /// Handlers run on the MQTT event loop task, so they should return
+    /// quickly and hand longer work off elsewhere.
+    pub async fn subscribe(
+        &self,
+        subtopic: &str,
+        handler: impl Fn(Bytes) + Send + 'static,
+    ) -> Result<()> {
+        topics::validate_filter(subtopic)?;
+        let topic = self.robot_topic(subtopic);
+        self.subscriptions.insert(topic.clone(), Box::new(handler));
+
+        if let Some(primary) = self.brokers.first() {
+            primary.client.subscribe(topic, QoS::AtLeastOnce).await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribes to `subtopic` and yields every message received on it,
+    /// decompressed if needed and decoded according to
+    /// `TelemetryConfig::serialization`. Messages are
+    /// dropped if the consumer falls more than `STREAM_CAPACITY` behind.
+    /// Like `subscribe`, this replaces any existing handler for `subtopic`.
+    pub async fn stream<T: DeserializeOwned>(&self, subtopic: &str) -> Result<TelemetryStream<T>> {
+        let serialization = self.config.serialization;
+        let (tx, rx) = mpsc::channel::<Bytes>(STREAM_CAPACITY);
+        for filter in self.decoded_filters(subtopic) {
+            let tx = tx.clone();
+            self.subscribe(&filter, move |payload| {
+                if tx.try_send(payload).is_err() {
+                    tracing::trace!("Telemetry stream is full, dropping message");
+                }
+            })
+            .await?;
+        }
+
+        Ok(TelemetryStream::new(rx, serialization))
+    }
+
+    /// Calls `handler` with every command received on
+    /// `robots/{robot_id}/command`, decoded like `stream` does. Messages that
+    /// fail to decode into `T` are logged, counted in
+    /// `rejected_command_count` and dropped. `Command` covers the built-in
+    /// commands. Like `subscribe`, `handler` runs on the MQTT event loop task.
+    pub async fn subscribe_commands<T: DeserializeOwned>(
+        &self,
+        handler: impl Fn(TelemetryPayload<T>) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let handler = Arc::new(handler);
+        let serialization = self.config.serialization;
+        for filter in self.decoded_filters(COMMAND_TOPIC) {
+            let handler = handler.clone();
+            let counters = self.counters.clone();
+            self.subscribe(&filter, move |payload| {
+                match stream::decode::<T>(&payload, serialization) {
+                    Ok(command) => handler(command),
+                    Err(e) => {
+                        counters.rejected_command();
+                        tracing::warn!("Failed to decode command: {}", e);
+                    }
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Filters matching `subtopic` as published with the configured
+    /// serialization, with and without compression.
+    fn decoded_filters(&self, subtopic: &str) -> Vec<String> {
+        let filter = match Encoding::from(self.config.serialization).topic_suffix() {
+            Some(suffix) if !subtopic.ends_with('#') => format!("{}/{}", subtopic, suffix),
+            _ => subtopic.to_string(),
+        };
+        let mut filters = vec![filter.clone()];
+        if let Some(compression) = &self.config.compression {
+            if !filter.ends_with('#') {
+                filters.push(format!("{}/{}", filter, compression.codec.topic_suffix()));
+            }
+        }
+        filters
+    }
+
+    /// Returns a handle that can publish from threads without an async
+    /// runtime.
+    pub fn sync_handle(&self) -> SyncTelemetry {
+        SyncTelemetry::new(self.clone())
+    }
+
+    /// Encodes `payload`, or returns `None` if serialization failed and the
+    /// configured policy is to skip the message.
+    fn encode<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        qos: QoS,
+        meta: &PayloadMeta,
+    ) -> Result<Option<Message>> {
+        self.schema.record(
+            topic,
+            std::any::type_name::<T>(),
+            meta.schema_version.unwrap_or(0),
+        );
+        match self.encode_payload(topic, payload, qos, meta) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) => {
+                self.counters.serialize_error();
+                match self.config.on_serialize_error {
+                    SerializeErrorPolicy::SkipAndCount => {
+                        tracing::warn!("Skipping telemetry for topic {}: {}", topic, e);
+                        Ok(None)
+                    }
+                    SerializeErrorPolicy::Propagate => Err(e),
+                }
+            }
+        }
+    }
+
+    fn encode_payload<T: Serialize>(
+        &self,
+        topic: &str,
+        payload: &T,
+        qos: QoS,
+        meta: &PayloadMeta,
+    ) -> Result<Message> {
+        let telemetry_payload = self.envelope(self.sequences.next(topic), payload, meta);
+
+        let user_properties = self.metadata_in_properties();
+        let (payload, user_properties) = match (self.config.serialization, user_properties) {
+            (SerializationFormat::Json, false) => {
+                (message::to_json(&telemetry_payload)?, Vec::new())
+            }
+            (SerializationFormat::MessagePack, false) => {
+                (message::to_msgpack(&telemetry_payload)?, Vec::new())
+            }
+            (SerializationFormat::Json, true) => (
+                message::to_json(payload)?,
+                telemetry_payload.user_properties(),
+            ),
+            (SerializationFormat::MessagePack, true) => (
+                message::to_msgpack(payload)?,
+                telemetry_payload.user_properties(),
+            ),
+        };
+
+        Ok(Message {
+            payload,
+            qos,
+            encoding: self.config.serialization.into(),
+            user_properties,
+            captured_at_nanos: telemetry_payload.captured_at_nanos,
+        })
+    }
+
+    /// Whether the envelope metadata travels as MQTT v5 user properties
+    /// rather than in the body.
+    fn metadata_in_properties(&self) -> bool {
+        self.config.metadata_as_user_properties && self.sink.supports_user_properties()
+    }
+
+    fn envelope<'a, T>(
+        &self,
+        sequence: u64,
+        payload: &'a T,
+        meta: &PayloadMeta,
+    ) -> TelemetryPayload<&'a T> {
+        let counters = self.counters();
+        TelemetryPayload {
+            sequence,
+            frame_number: meta.frame_number.unwrap_or(counters.frame_number),
+            video_timestamp: meta.video_timestamp.unwrap_or(counters.video_timestamp),
+            inference_step: meta.inference_step.unwrap_or(counters.inference_step),
+            episode_id: meta.episode_id.unwrap_or(counters.episode_id),
+            schema_version: meta.schema_version.unwrap_or(0),
+            captured_at_nanos: meta
+                .captured_at_nanos
+                .unwrap_or_else(|| self.monotonic_nanos()),
+            unix_nanos: self.config.include_unix_nanos.then(|| self.unix_nanos()),
+            data: payload,
+        }
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        self.config.clock.now_monotonic().as_nanos() as u64
+    }
+
+    fn unix_nanos(&self) -> u64 {
+        let nanos = self.config.clock.now_unix().as_nanos() as u64;
+        match (self.config.clock_sync, self.clock_offset.get()) {
+            (Some(clock_sync), Some(offset)) if clock_sync.apply => {
+                nanos.saturating_add_signed(offset)
+            }
+            _ => nanos,
+        }
+    }
+
+    /// Offset of the time server's wall clock from the robot's in
+    /// nanoseconds, positive when the robot is behind. `None` until
+    /// `TelemetryConfig::clock_sync` has measured it.
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.clock_offset.get()
+    }
+
+    /// `{topic_prefix}/{robot_id}/{subtopic}` with the current robot id.
+    pub(crate) fn robot_topic(&self, subtopic: &str) -> String {
+        self.routing.get().topic(subtopic)
+    }
+
+    fn full_topic(&self, topic: &str, encoding: Encoding) -> String {
+        let suffix = encoding.topic_suffix();
+        let topic_root = self.routing.get().topic_root.clone();
+        // Leave room for a compression level so `finish` does not reallocate.
+        let mut full_topic = String::with_capacity(
+            topic_root.len() + topic.len() + suffix.map_or(0, |suffix| suffix.len() + 1) + 5,
+        );
+        full_topic.push_str(&topic_root);
+        full_topic.push_str(topic);
+        if let Some(suffix) = suffix {
+            full_topic.push('/');
+            full_topic.push_str(suffix);
+        }
+        full_topic
+    }
+
+    fn topic_qos(&self, topic: &str) -> QoS {
+        self.config
+            .topic_qos
+            .get(topic)
+            .copied()
+            .unwrap_or(self.config.default_qos)
+    }
+
+    /// Publishes all of `items` as a single message, with one set of frame
+    /// metadata for the whole batch. With `OversizePolicy::Split`, batches
+    /// over the payload limit are halved until each part fits, judged by
+    /// their size before compression.
+    pub async fn publish_batch<T: Serialize>(&self, topic: &str, items: &[T]) -> Result<()> {
+        let max = self
+            .max_payload_bytes()
+            .filter(|_| self.config.oversize_policy == OversizePolicy::Split);
+        let Some(max) = max else {
+            return self.publish(topic, &items).await;
+        };
+
+        // Split before publishing, so oversized attempts do not use up
+        // sequence numbers.
+        let mut pending = vec![items];
+        while let Some(items) = pending.pop() {
+            if items.len() > 1 && self.encoded_len(&items)? + ENVELOPE_OVERHEAD > max {
+                let (first, second) = items.split_at(items.len() / 2);
+                pending.push(second);
+                pending.push(first);
+                continue;
+            }
+            self.publish(topic, &items).await?;
+        }
+        Ok(())
+    }
+
+    fn encoded_len<T: Serialize + ?Sized>(&self, data: &T) -> Result<usize> {
+        Ok(match self.config.serialization {
+            SerializationFormat::Json => message::json_len(data)?,
+            SerializationFormat::MessagePack => message::msgpack_len(data)?,
+        })
+    }
+
+    /// Approximate size in bytes of the body `publish` would send for
+    /// `payload` right now, before compression. Nothing is published.
+    pub fn estimate_payload_size<T: Serialize>(&self, payload: &T) -> Result<usize> {
+        if self.metadata_in_properties() {
+            return self.encoded_len(payload);
+        }
+        self.encoded_len(&self.envelope(0, payload, &PayloadMeta::default()))
+    }
+
+    /// `estimate_payload_size` times `rate_hz`, in bytes per second, e.g. to
+    /// check a topic against `TelemetryConfig::max_bytes_per_sec` before
+    /// enabling it.
+    pub fn estimate_bandwidth<T: Serialize>(&self, payload: &T, rate_hz: f64) -> Result<f64> {
+        Ok(self.estimate_payload_size(payload)? as f64 * rate_hz)
+    }
+
+    /// The smaller of `TelemetryConfig::max_payload_bytes` and the primary
+    /// broker's maximum packet size, less room for the rest of the packet.
+    fn max_payload_bytes(&self) -> Option<usize> {
+        let broker = self
+            .connection
+            .max_packet_size()
+            .map(|max| (max as usize).saturating_sub(PACKET_OVERHEAD));
+        match (self.config.max_payload_bytes, broker) {
+            (Some(configured), Some(broker)) => Some(configured.min(broker)),
+            (configured, broker) => configured.or(broker),
+        }
+    }
+
+    /// Creates a `BatchSink` for `topic` using the configured batch window.
+    pub fn batch_sink<T: Serialize>(&self, topic: &str) -> BatchSink<T> {
+        BatchSink::new(self.clone(), topic, self.config.batch_window)
+    }
+
+    /// Creates an `Aggregator` publishing summaries of `P` to `topic`, e.g.
+    /// `joints/summary`.
+    pub fn aggregator<P: Summarize>(
+        &self,
+        topic: &str,
+        window: AggregationWindow,
+    ) -> Aggregator<P> {
+        Aggregator::new(self.clone(), topic, window)
+    }
+
+    /// Publishes `points` as InfluxDB line protocol, one line per point,
+    /// timestamped according to `TelemetryConfig::timestamp_source`, with
+    /// measurements and tags following `TelemetryConfig::measurement_map`.
+    pub async fn publish_line_protocol<P: IntoLineProtocol>(
+        &self,
+        topic: &str,
+        points: &[P],
+    ) -> Result<()> {
+        if self.mutes.check(topic) {
+            return Ok(());
+        }
+        let counters = self.counters();
+        let captured_at_nanos = self.monotonic_nanos();
+        let timestamp = match self.config.timestamp_source {
+            TimestampSource::VideoTimestamp => counters.video_timestamp,
+            TimestampSource::Monotonic => captured_at_nanos,
+            TimestampSource::Unix => self.unix_nanos(),
+        };
+
+        let routing = self.routing.get();
+        let map = &self.config.measurement_map;
+        let measurement = map.measurements.get(topic);
+        let global_tags: Vec<(&str, &str)> = map
+            .robot_id_tag
+            .then_some(("robot_id", routing.robot_id.as_str()))
+            .into_iter()
+            .chain(
+                map.global_tags
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str())),
+            )
+            .collect();
+
+        let payload = line_protocol::encode_with(
+            points,
+            |point| {
+                let measurement = measurement.map_or(point.measurement(), String::as_str);
+                if map.robot_id_tag {
+                    Cow::Borrowed(measurement)
+                } else {
+                    Cow::Owned(format!("{}_{}", routing.robot_id, measurement))
+                }
+            },
+            &global_tags,
+            &[
+                ("frame_number", FieldValue::from(counters.frame_number)),
+                (
+                    "video_timestamp",
+                    FieldValue::from(counters.video_timestamp),
+                ),
+                ("inference_step", FieldValue::from(counters.inference_step)),
+                ("episode_id", FieldValue::from(counters.episode_id)),
+                ("captured_at_nanos", FieldValue::from(captured_at_nanos)),
+            ],
+            Some(timestamp),
+        );
+        let message = Message {
+            payload: payload.into_bytes(),
+            qos: self.topic_qos(topic),
+            encoding: Encoding::LineProtocol,
+            user_properties: Vec::new(),
+            captured_at_nanos,
+        };
+        self.send(topic, message).await
+    }
+
+    async fn send(&self, topic: &str, message: Message) -> Result<()> {
+        topics::validate(topic)?;
+        match self.rate_limiter.admit(topic, message) {
+            Admission::Send(message) => self.dispatch(topic, message).await,
+            Admission::Held { wake_in } => {
+                self.schedule_held(topic, wake_in);
+                Ok(())
+            }
+            Admission::Replaced => Ok(()),
+        }
+    }
+
+    /// Waits for a slot with `TelemetryConfig::max_concurrent_publishes`.
+    async fn publish_permit(&self) -> Option<SemaphorePermit<'_>> {
+        // The semaphore is never closed.
+        self.publish_permits.as_ref()?.acquire().await.ok()
+    }
+
+    /// Sends the held sample for `topic` once its rate limit window closes.
+    /// Without a runtime the sample is simply superseded by the next one.
+    fn schedule_held(&self, topic: &str, wake_in: Duration) {
+        if let Some(handle) = &self.runtime {
+            let telemetry = self.background();
+            let topic = topic.to_string();
+            handle.spawn(async move {
+                telemetry.send_held(&topic, wake_in).await;
+            });
+        }
+    }
+
+    async fn send_held(&self, topic: &str, mut wake_in: Duration) {
+        loop {
+            tokio::time::sleep(wake_in).await;
+            match self.rate_limiter.take_pending(topic) {
+                Wake::Ready(Some(message)) => {
+                    if let Err(e) = self.dispatch(topic, message).await {
+                        tracing::warn!("Failed to publish rate limited telemetry: {}", e);
+                    }
+                    return;
+                }
+                Wake::Ready(None) => return,
+                Wake::Retry(delay) => wake_in = delay,
+            }
+        }
+    }
+
+    async fn dispatch(&self, topic: &str, message: Message) -> Result<()> {
+        let full_topic = self.full_topic(topic, message.encoding);
+        self.dispatch_to(topic, full_topic, message).await
+    }
+
+    /// `dispatch` to `full_topic` instead of the topic under the robot's
+    /// prefix.
+    async fn dispatch_to(&self, topic: &str, full_topic: String, message: Message) -> Result<()> {
+        // Also bounds held samples and fleet messages, not just `publish`.
+        let _permit = self.publish_permit().await;
+        let recent = self.recent_copy(&message);
+        let message = self.finish_to(topic, full_topic, message)?;
+        let bytes = message.payload.len();
+        if !self.admit_bytes(topic, bytes, message.priority) {
+            return Ok(());
+        }
+        let published = self.published(&message);
+        let registered = self.registered_copy(&message);
+        let sink = self.sink_for(message.qos);
+        let sent = sink.send_message(message);
+        let result = match registered {
+            Some(copy) => futures::join!(sent, self.registry.send(copy)).0,
+            None => sent.await,
+        };
+        result?;
+        self.after_publish(topic, bytes, published, recent);
+        Ok(())
+    }
+
+    fn try_dispatch(&self, topic: &str, message: Message) -> Result<bool> {
+        let recent = self.recent_copy(&message);
+        let message = self.finish(topic, message)?;
+        let bytes = message.payload.len();
+        if !self.admit_bytes(topic, bytes, message.priority) {
+            return Ok(false);
+        }
+        let published = self.published(&message);
+        if let Some(copy) = self.registered_copy(&message) {
+            self.registry.try_send(copy);
+        }
+        let sent = self.sink_for(message.qos).try_send_message(message)?;
+        if sent {
+            self.after_publish(topic, bytes, published, recent);
+        }
+        Ok(sent)
+    }
+
+    /// The local recording in local-only mode, otherwise the bulk client for
+    /// QoS 0 messages if there is one and the main sink for the rest.
+    fn sink_for(&self, qos: QoS) -> Arc<dyn TelemetrySink> {
+        if let Some(recorder) = self.local.recorder() {
+            return recorder;
+        }
+        match &self.bulk {
+            Some(bulk) if qos == QoS::AtMostOnce => bulk.sink.clone(),
+            _ => self.sink.clone(),
+        }
+    }
+
+    /// Leaves `ConnectionState::LocalOnly` and, once the broker is
+    /// connected, uploads what was recorded in `LocalFallbackConfig::dir` in
+    /// the background, oldest recording first. Each recording is deleted
+    /// once the broker client accepted all of it, and is otherwise kept for
+    /// the next call. Does nothing outside local-only mode.
+    pub fn resume_network(&self) {
+        let Some(recorder) = self.local.take() else {
+            return;
+        };
+        self.connection.set_local_only(false);
+        tracing::info!("Resuming telemetry over MQTT");
+        match (&self.runtime, &self.config.local_fallback) {
+            (Some(runtime), Some(fallback)) => {
+                runtime.spawn(local::upload(
+                    self.background(),
+                    recorder,
+                    fallback.dir.clone(),
+                ));
+            }
+            _ => {
+                if let Err(e) = recorder.flush() {
+                    tracing::warn!("Failed to flush local telemetry recording: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Copy of `message` for the sinks added with `add_sink`, if there are
+    /// any.
+    fn registered_copy(&self, message: &OutgoingMessage) -> Option<OutgoingMessage> {
+        (!self.registry.is_empty()).then(|| message.clone())
+    }
+
+    /// Copy of the topic and payload for the publish hooks, if there are any.
+    fn published(&self, message: &OutgoingMessage) -> Option<(String, Vec<u8>)> {
+        (!self.hooks.is_empty()).then(|| (message.topic.clone(), message.payload.clone()))
+    }
+
+    /// Copy of the uncompressed payload for the recent cache, if enabled.
+    fn recent_copy(&self, message: &Message) -> Option<Bytes> {
+        self.recent
+            .as_ref()
+            .map(|_| Bytes::copy_from_slice(&message.payload))
+    }
+
+    fn after_publish(
+        &self,
+        topic: &str,
+        bytes: usize,
+        published: Option<(String, Vec<u8>)>,
+        recent: Option<Bytes>,
+    ) {
+        let now = self.config.clock.now_monotonic();
+        self.counters.record_published(bytes);
+        self.topic_stats.published(topic, bytes, now);
+        if let (Some(cache), Some(payload)) = (&self.recent, recent) {
+            cache.push(topic, now, payload);
+        }
+        if let Some((topic, payload)) = published {
+            self.hooks.run(&topic, &payload);
+        }
+    }
+
+    fn topic_priority(&self, topic: &str) -> Priority {
+        self.config
+            .topic_priority
+            .get(topic)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Checks `bytes` against the byte-rate cap, counting them as dropped if
+    /// they do not fit.
+    fn admit_bytes(&self, topic: &str, bytes: usize, priority: Priority) -> bool {
+        let Some(byte_limiter) = &self.byte_limiter else {
+            return true;
+        };
+        if byte_limiter.admit(bytes, priority) {
+            true
+        } else {
+            self.counters.add_bytes_dropped(bytes);
+            self.topic_stats.dropped(topic);
+            false
+        }
+    }
+
+    /// Builds the full topic and compresses the payload if it is over the
+    /// configured threshold.
+    fn finish(&self, topic: &str, message: Message) -> Result<OutgoingMessage> {
+        let full_topic = self.full_topic(topic, message.encoding);
+        self.finish_to(topic, full_topic, message)
+    }
+
+    /// `finish` to `full_topic` instead of the topic under the robot's prefix.
+    fn finish_to(
+        &self,
+        topic: &str,
+        mut full_topic: String,
+        message: Message,
+    ) -> Result<OutgoingMessage> {
+        let mut payload = message.payload;
+
+        if let Some(compression) = &self.config.compression {
+            if payload.len() > compression.threshold_bytes {
+                payload = compression::compress(compression.codec, &payload)?;
+                full_topic.push('/');
+                full_topic.push_str(compression.codec.topic_suffix());
+            }
+        }
+
+        if let Some(max) = self.max_payload_bytes() {
+            if payload.len() > max {
+                return Err(TelemetryError::PayloadTooLarge {
+                    size: payload.len(),
+                    max,
+                });
+            }
+        }
+
+        Ok(OutgoingMessage {
+            topic: full_topic,
+            payload,
+            qos: message.qos,
+            priority: self.topic_priority(topic),
+            user_properties: message.user_properties,
+            retain: false,
+            expires_at: self
+                .config
+                .ttl(topic)
+                .map(|ttl| Duration::from_nanos(message.captured_at_nanos) + ttl),
+        })
+    }
+
+    /// Waits until every buffered and in-flight message has been sent (and
+    /// acknowledged, for QoS 1 and 2) or `timeout` elapses. Returns the
+    /// number of messages still pending.
+    pub async fn flush_pending(&self, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let pending = self.queue_depth();
+            if pending == 0 || tokio::time::Instant::now() >= deadline {
+                return pending;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Re-sends the retained online status and schema announcement, plus a
+    /// fresh heartbeat if heartbeats are enabled, so consumers that joined
+    /// after the broker lost them see the current state. This also happens
+    /// automatically after every reconnect.
+    pub async fn republish_retained(&self) -> Result<()> {
+        if let Some((topic, payload)) = self.routing.get().online_status.clone() {
+            let mut message = OutgoingMessage::new(topic, payload, QoS::AtLeastOnce);
+            message.priority = Priority::High;
+            message.retain = true;
+            self.sink.send_message(message).await?;
+        }
+        self.republish_after_reconnect().await
+    }
+
+    /// The robot id in the topics, after sanitizing, see `set_robot_id`.
+    pub fn robot_id(&self) -> String {
+        self.routing.get().robot_id.clone()
+    }
+
+    /// Switches every publish from now on to the topics of `robot_id`, e.g.
+    /// after a controller board swap, without reconnecting. The retained
+    /// status of the old id is set to offline and that of the new one to
+    /// online, the schema is announced again and subscriptions move to the
+    /// new topics. The MQTT client id, the last will and the names of local
+    /// fallback recordings keep the id the instance was created with.
+    pub async fn set_robot_id(&self, robot_id: &str) -> Result<()> {
+        let routing = Arc::new(Routing::new(
+            &self.config,
+            topics::sanitize_robot_id(robot_id),
+        ));
+        topics::validate_robot_id(&routing.robot_id)?;
+        let old = self.routing.replace(routing.clone());
+        if old.robot_id == routing.robot_id {
+            return Ok(());
+        }
+        tracing::info!(
+            "Telemetry robot id changed from {} to {}",
+            old.robot_id,
+            routing.robot_id
+        );
+
+        let moved = self
+            .subscriptions
+            .move_root(&old.topic_root, &routing.topic_root);
+        if let Some(primary) = self.brokers.first() {
+            for topic in moved {
+                primary.client.subscribe(topic, QoS::AtLeastOnce).await?;
+            }
+        }
+
+        for status in [&old.offline_status, &routing.online_status] {
+            if let Some((topic, payload)) = status.clone() {
+                let mut message = OutgoingMessage::new(topic, payload, QoS::AtLeastOnce);
+                message.priority = Priority::High;
+                message.retain = true;
+                self.sink.send_message(message).await?;
+            }
+        }
+        self.schema.request_announcement();
+        Ok(())
+    }
+
+    /// `republish_retained` without the status, which the event loop
+    /// publishes itself on connect.
+    async fn republish_after_reconnect(&self) -> Result<()> {
+        self.schema.request_announcement();
+        if self.config.heartbeat_interval.is_some() {
+            heartbeat::publish(self).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until every message published so far has reached the primary
+    /// broker: the offline buffer is drained and every QoS 1 and 2 publish
+    /// has been acknowledged, matched by packet id. Returns whether that
+    /// happened before `timeout`.
+    ///
+    /// QoS 0 messages cannot be confirmed this way. They count as flushed
+    /// once written to the socket, whether or not the broker received them.
+    pub async fn flush(&self, timeout: Duration) -> Result<bool> {
+        let flushed = async {
+            loop {
+                self.in_flight.wait_idle().await;
+                if self.buffer.is_empty() && self.in_flight.count() == 0 {
+                    return;
+                }
+                // The buffer is still draining, or waiting for a reconnect.
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        Ok(tokio::time::timeout(timeout, flushed).await.is_ok())
+    }
+
+    /// Flushes pending messages, disconnects from the broker and uninstalls
+    /// this instance if it is the global one. `timeout` bounds both the flush
+    /// and the disconnect. Returns the number of messages that were still
+    /// pending.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<usize> {
+        let pending = self.flush_pending(timeout).await;
+        if pending > 0 {
+            tracing::warn!("Shutting down telemetry with {} pending messages", pending);
+        }
+
+        for task in self
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain(..)
+        {
+            task.abort();
+        }
+
+        // The recording is finalized once the last publish using it is done;
+        // it is uploaded by the next instance that resumes the network.
+        if let Some(recorder) = self.local.take() {
+            if let Err(e) = recorder.flush() {
+                tracing::warn!("Failed to flush local telemetry recording: {}", e);
+            }
+        }
+        self.connection.set_local_only(false);
+
+        let disconnects = self
+            .brokers
+            .iter()
+            .chain(self.bulk.iter().map(|bulk| &bulk.broker))
+            .map(|broker| self.disconnect(broker, timeout));
+        for result in futures::future::join_all(disconnects).await {
+            result?;
+        }
+
+        let mut global = TELEMETRY.lock().await;
+        if global
+            .as_ref()
+            .is_some_and(|installed| installed.is_same_instance(self))
+        {
+            *global = None;
+        }
+        drop(global);
+        tracing::debug!("Telemetry shut down for robot {}", self.robot_id());
+
+        Ok(pending)
+    }
+
+    async fn disconnect(&self, broker: &Broker, timeout: Duration) -> Result<()> {
+        let client = &broker.client;
+        // A clean disconnect does not trigger the last will, so publish the
+        // offline status ourselves.
+        if let Some((topic, payload)) = self.routing.get().offline_status.clone() {
+            if let Err(e) = client
+                .publish(topic, QoS::AtLeastOnce, true, payload, Vec::new())
+                .await
+            {
+                tracing::warn!("Failed to publish offline status: {}", e);
+            }
+        }
+
+        broker.connection.begin_shutdown();
+        let mut state = broker.connection.subscribe();
+        client.disconnect().await?;
+
+        let disconnected = async {
+            while *state.borrow_and_update() != ConnectionState::Disconnected {
+                if state.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(timeout, disconnected).await.is_err() {
+            tracing::warn!("Timed out waiting for MQTT disconnect");
+        }
+
+        Ok(())
+    }
+
+    /// Publishes to `joints`, or to `joints/{actuator_id}` per joint with
+    /// `JointTopicStrategy::PerActuator`.
+    pub async fn publish_joint_state(&self, joints: &[JointState]) -> Result<()> {
+        match self.config.joint_topic_strategy {
+            JointTopicStrategy::Batched => {
+                self.publish_typed(payloads::JOINTS_TOPIC, &joints, joints)
+                    .await
+            }
+            JointTopicStrategy::PerActuator => {
+                for joint in joints {
+                    let joint = std::slice::from_ref(joint);
+                    let topic = format!("{}/{}", payloads::JOINTS_TOPIC, joint[0].actuator_id);
+                    self.publish_typed(&topic, &joint, joint).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn publish_imu(&self, reading: &ImuReading) -> Result<()> {
+        self.publish_typed(payloads::IMU_TOPIC, reading, std::slice::from_ref(reading))
+            .await
+    }
+
+    /// Publishes `reading` to `imu/bin` in the fixed layout of
+    /// `ImuReading::to_binary`, for the highest rates and for consumers that
+    /// cannot parse JSON or MessagePack. The body carries no envelope, so
+    /// the frame number and other counters are not included.
+    pub async fn publish_imu_binary(&self, reading: &ImuReading) -> Result<()> {
+        let topic = payloads::IMU_TOPIC;
+        if self.mutes.check(topic) {
+            return Ok(());
+        }
+        let message = Message {
+            payload: reading.to_binary().to_vec(),
+            qos: self.topic_qos(topic),
+            encoding: Encoding::Binary,
+            user_properties: Vec::new(),
+            captured_at_nanos: self.monotonic_nanos(),
+        };
+        self.send(topic, message).await
+    }
+
+    /// Publishes the joint states and IMU reading of one frame as a single
+    /// message to the `frame` topic.
+    pub async fn publish_frame(&self, frame: &SyncFrame) -> Result<()> {
+        self.publish_typed(payloads::FRAME_TOPIC, frame, &frame.points())
+            .await
+    }
+
+    pub async fn publish_diagnostics(&self, diagnostics: &[Diagnostics]) -> Result<()> {
+        self.publish_typed(payloads::DIAGNOSTICS_TOPIC, &diagnostics, diagnostics)
+            .await
+    }
+
+    /// Sets the video timestamp to the frame's presentation time, then
+    /// publishes its metadata to the `video` topic.
+    pub async fn publish_video_meta(&self, meta: &VideoFrameMeta) -> Result<()> {
+        self.update_video_timestamp(meta.pts_nanos);
+        self.publish_typed(payloads::VIDEO_TOPIC, meta, std::slice::from_ref(meta))
+            .await
+    }
+
+    /// Publishes a typed payload as `json` or as line protocol `points`,
+    /// depending on `TelemetryConfig::format`. With `sanitize_non_finite`,
+    /// NaN and infinite floats become `null` in JSON and are left out of line
+    /// protocol. Unset fields are left out of JSON with `NoneFields::Omit`.
+    async fn publish_typed<T: Serialize, P: IntoLineProtocol + SchemaVersion>(
+        &self,
+        topic: &str,
+        json: &T,
+        points: &[P],
+    ) -> Result<()> {
+        let sanitize = self.config.sanitize_non_finite && {
+            let count = sanitize::non_finite_fields(points);
+            self.counters.add_sanitized_fields(count as u64);
+            count > 0
+        };
+
+        let meta = PayloadMeta {
+            schema_version: Some(P::SCHEMA_VERSION),
+            ..Default::default()
+        };
+        let omit_nulls = self.config.none_fields == NoneFields::Omit;
+        match self.format() {
+            // serde_json maps non-finite floats to null, which MessagePack
+            // can represent as well.
+            TelemetryFormat::Json if sanitize || omit_nulls => {
+                let mut value = serde_json::to_value(json)?;
+                if omit_nulls {
+                    sanitize::omit_nulls(&mut value);
+                }
+                self.publish_with_meta(topic, &value, meta).await
+            }
+            TelemetryFormat::Json => self.publish_with_meta(topic, json, meta).await,
+            TelemetryFormat::LineProtocol if sanitize => {
+                let points: Vec<_> = points.iter().map(sanitize::Finite).collect();
+                self.publish_line_protocol(topic, &points).await
+            }
+            TelemetryFormat::LineProtocol => self.publish_line_protocol(topic, points).await,
+        }
+    }
+
+    pub fn update_frame_number(&self, new_frame_number: u64) {
+        self.frame_number.store(new_frame_number, COUNTER_ORDERING);
+    }
+
+    /// Sets the video timestamp. A timestamp older than the current one, e.g.
+    /// after an encoder restart, is handled according to
+    /// `TelemetryConfig::video_timestamp_policy`.
+    pub fn update_video_timestamp(&self, new_video_timestamp: u64) {
+        let policy = self.config.video_timestamp_policy;
+        let previous = match policy {
+            VideoTimestampPolicy::AllowResets => self
+                .video_timestamp
+                .swap(new_video_timestamp, COUNTER_ORDERING),
+            VideoTimestampPolicy::Clamp => self
+                .video_timestamp
+                .fetch_max(new_video_timestamp, COUNTER_ORDERING),
+        };
+        if new_video_timestamp >= previous {
+            return;
+        }
+
+        self.counters.video_timestamp_reset();
+        match policy {
+            VideoTimestampPolicy::AllowResets => tracing::warn!(
+                "Video timestamp went backwards from {} to {}",
+                previous,
+                new_video_timestamp
+            ),
+            // Repeats on every frame until the stream catches up.
+            VideoTimestampPolicy::Clamp => tracing::debug!(
+                "Ignoring video timestamp {}, behind the current {}",
+                new_video_timestamp,
+                previous
+            ),
+        }
+    }
+
+    pub fn get_frame_number(&self) -> u64 {
+        self.frame_number.load(COUNTER_ORDERING)
+    }
+
+    pub fn increment_frame_number(&self) {
+        self.frame_number.fetch_add(1, COUNTER_ORDERING);
+    }
+
+    pub fn get_video_timestamp(&self) -> u64 {
+        self.video_timestamp.load(COUNTER_ORDERING)
+    }
+
+    pub fn update_inference_step(&self, new_inference_step: u64) {
+        self.inference_step
+            .store(new_inference_step, COUNTER_ORDERING);
+        self.notify_inference_step();
+    }
+
+    pub fn increment_inference_step(&self) {
+        self.inference_step.fetch_add(1, COUNTER_ORDERING);
+        self.notify_inference_step();
+    }
+
+    pub fn get_inference_step(&self) -> u64 {
+        self.inference_step.load(COUNTER_ORDERING)
+    }
+
+    /// Receiver that is notified whenever the inference step is updated,
+    /// incremented or reset by `start_episode`, e.g. to redraw on every new
+    /// step instead of polling `get_inference_step`.
+    pub fn inference_step_changed(&self) -> watch::Receiver<u64> {
+        self.inference_step_tx.subscribe()
+    }
+
+    fn notify_inference_step(&self) {
+        // Read the atomic under the channel's lock, so concurrent updates
+        // always leave the channel at the latest value.
+        self.inference_step_tx
+            .send_modify(|step| *step = self.inference_step.load(COUNTER_ORDERING));
+    }
+
+    /// Starts a new episode: increments the episode id and resets the
+    /// inference step to 0. Returns the new episode id.
+    pub fn start_episode(&self) -> u64 {
+        let episode_id = self.episode_id.fetch_add(1, COUNTER_ORDERING) + 1;
+        self.inference_step.store(0, COUNTER_ORDERING);
+        self.notify_inference_step();
+        episode_id
+    }
+
+    pub fn get_episode_id(&self) -> u64 {
+        self.episode_id.load(COUNTER_ORDERING)
+    }
+
+    /// Reads all counters in one go. The reads are back to back, so the
+    /// snapshot is consistent unless another thread updates a counter at the
+    /// same moment; it is best-effort, not atomic.
+    pub fn counters(&self) -> Counters {
+        Counters {
+            frame_number: self.get_frame_number(),
+            video_timestamp: self.get_video_timestamp(),
+            inference_step: self.get_inference_step(),
+            episode_id: self.get_episode_id(),
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    /// Waits until the primary broker is connected or `timeout` elapses.
+    /// Returns whether it connected. Returns immediately if it already is.
+    pub async fn wait_connected(&self, timeout: Duration) -> bool {
+        let mut state = self.connection.subscribe();
+        let connected = async {
+            while *state.borrow_and_update() != ConnectionState::Connected {
+                if state.changed().await.is_err() {
+                    return false;
+                }
+            }
+            true
+        };
+        tokio::time::timeout(timeout, connected)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Receiver that is notified on every connection state transition.
+    pub fn connection_state_changed(&self) -> watch::Receiver<ConnectionState> {
+        self.connection.subscribe()
+    }
+
+    /// One-call summary of connection state, queue depths and drops, e.g.
+    /// for a status endpoint.
+    pub fn health(&self) -> metrics::TelemetryHealth {
+        metrics::TelemetryHealth {
+            connected: self.connection.is_connected(),
+            queued: self.in_flight.count(),
+            buffered: self.buffer.len(),
+            last_publish_ago: self.counters.last_published_ago(),
+            reconnects: self.connection.reconnects(),
+            last_error: self.connection.last_error(),
+            dropped: self.rate_limiter.dropped() + self.buffer.dropped(),
+        }
+    }
+
+    /// Connection state of each broker, the primary first and then the
+    /// backups in configuration order. Empty for custom sinks.
+    pub fn broker_states(&self) -> Vec<ConnectionState> {
+        self.brokers
+            .iter()
+            .map(|broker| broker.connection.state())
+            .collect()
+    }
+
+    /// Connection state of the bulk client, or `None` without
+    /// `TelemetryConfig::dedicated_bulk_client`.
+    pub fn bulk_connection_state(&self) -> Option<ConnectionState> {
+        self.bulk
+            .as_ref()
+            .map(|bulk| bulk.broker.connection.state())
+    }
+
+    /// Number of reconnections after the first successful connection.
+    pub fn reconnect_count(&self) -> u64 {
+        self.connection.reconnects()
+    }
+
+    /// Most recent error from the primary broker connection, if there was
+    /// one. It is kept after the connection recovers.
+    pub fn last_error(&self) -> Option<String> {
+        self.connection.last_error()
+    }
+
+    /// Total number of messages handed to the MQTT client.
+    pub fn published_count(&self) -> u64 {
+        self.in_flight.total()
+    }
+
+    /// Number of messages sent but not yet acknowledged by the broker.
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.count()
+    }
+
+    /// Latency percentiles of `publish` and `try_publish` calls since the
+    /// previous call to this method, including any wait for room in the
+    /// MQTT request channel.
+    pub fn publish_latency_percentiles(&self) -> metrics::LatencyPercentiles {
+        self.counters.take_publish_latency()
+    }
+
+    /// Number of payloads that failed to serialize.
+    pub fn serialize_error_count(&self) -> u64 {
+        self.counters.serialize_errors()
+    }
+
+    /// Number of video timestamp updates that went backwards.
+    pub fn video_timestamp_reset_count(&self) -> u64 {
+        self.counters.video_timestamp_resets()
+    }
+
+    /// Number of received commands that failed to decode.
+    pub fn rejected_command_count(&self) -> u64 {
+        self.counters.rejected_commands()
+    }
+
+    /// Number of NaN or infinite float fields replaced or dropped by
+    /// `sanitize_non_finite`.
+    pub fn sanitized_field_count(&self) -> u64 {
+        self.counters.sanitized_fields()
+    }
+
+    /// Payload bytes handed to the sink, measured after compression.
+    pub fn bytes_published(&self) -> u64 {
+        self.counters.bytes_published()
+    }
+
+    /// Payload bytes dropped by `TelemetryConfig::max_bytes_per_sec`.
+    pub fn bytes_dropped(&self) -> u64 {
+        self.counters.bytes_dropped()
+    }
+
+    /// Publish and drop counts of every subtopic published to so far, sorted
+    /// by subtopic, to spot a stuck or runaway producer.
+    pub fn topic_stats(&self) -> Vec<(String, TopicStats)> {
+        let mut stats = self.topic_stats.snapshot();
+        for (topic, dropped) in self.rate_limiter.dropped_by_topic() {
+            match stats.binary_search_by(|(t, _)| t.as_str().cmp(&topic)) {
+                Ok(i) => stats[i].1.dropped += dropped,
+                Err(i) => stats.insert(
+                    i,
+                    (
+                        topic,
+                        TopicStats {
+                            dropped,
+                            ..TopicStats::default()
+                        },
+                    ),
+                ),
+            }
+        }
+        stats
+    }
+
+    /// Number of messages discarded by the per-topic rate limits.
+    pub fn dropped_by_rate_limit(&self) -> u64 {
+        self.rate_limiter.dropped()
+    }
+
+    /// Messages buffered or sent but not yet acknowledged.
+    pub fn queue_depth(&self) -> usize {
+        self.buffer.len() + self.in_flight.count()
+    }
+
+    /// Number of messages currently held while waiting for the broker.
+    pub fn buffered_count(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of messages discarded or rejected by the
+    /// `TelemetryConfig::overflow_policy` because the buffer was full.
+    pub fn buffer_dropped_count(&self) -> u64 {
+        self.buffer.dropped()
+    }
+
+    /// Number of messages lost to a full buffer under any
+    /// `TelemetryConfig::overflow_policy`: the incoming message under
+    /// `DropNewest`, the message discarded to make room under `DropOldest`,
+    /// the rejected one under `Error`, and under `Block` what `try_publish`
+    /// could not wait for.
+    pub fn overflow_drops(&self) -> u64 {
+        self.buffer.dropped()
+    }
+
+    /// Number of buffered messages discarded because their TTL passed
+    /// before the broker was reachable again.
+    pub fn expired_count(&self) -> u64 {
+        self.buffer.expired()
+    }
+
+    pub fn try_get() -> Option<Self> {
+        if !Self::is_enabled() {
+            return None;
+        }
+        // Try to get the global telemetry instance
+        if let Ok(guard) = TELEMETRY.try_lock() {
+            guard.as_ref().cloned()
+        } else {
+            None
+        }
+    }
+}
+
+/// Identifies the instance and its counters. The broker is shown as
+/// `host:port` only; credentials and TLS settings are never printed.
+impl fmt::Debug for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Telemetry")
+            .field("robot_id", &self.robot_id())
+            .field(
+                "broker",
+                &format_args!("{}:{}", self.config.mqtt_host, self.config.mqtt_port),
+            )
+            .field("connection", &self.connection_state())
+            .field("counters", &self.counters())
+            .field("published", &self.published_count())
+            .field("in_flight", &self.in_flight_count())
+            .field("buffered", &self.buffered_count())
+            .finish_non_exhaustive()
+    }
+}
+
+/// One-line health summary, e.g. for status logs.
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let health = self.health();
+        write!(
+            f,
+            "telemetry for {}: {}, {} queued, {} buffered, {} dropped, {} reconnects",
+            self.robot_id(),
+            if health.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            health.queued,
+            health.buffered,
+            health.dropped,
+            health.reconnects,
+        )?;
+        if let Some(error) = &health.last_error {
+            write!(f, ", last error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    fn memory_telemetry() -> (Telemetry, Arc<MemorySink>) {
+        let sink = Arc::new(MemorySink::new());
+        let config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        (Telemetry::with_sink(config, sink.clone()), sink)
+    }
+
+    /// Keeps whole messages, unlike `MemorySink`, and can claim MQTT v5
+    /// support.
+    #[derive(Default)]
+    struct RecordingSink {
+        messages: std::sync::Mutex<Vec<OutgoingMessage>>,
+        user_properties: bool,
+    }
+
+    impl RecordingSink {
+        fn messages(&self) -> Vec<OutgoingMessage> {
+            self.messages
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TelemetrySink for RecordingSink {
+        async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+            self.send_message(OutgoingMessage::new(topic, payload, qos))
+                .await
+        }
+
+        fn try_send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<bool> {
+            self.try_send_message(OutgoingMessage::new(topic, payload, qos))
+        }
+
+        fn supports_user_properties(&self) -> bool {
+            self.user_properties
+        }
+
+        async fn send_message(&self, message: OutgoingMessage) -> Result<()> {
+            self.try_send_message(message).map(|_| ())
+        }
+
+        fn try_send_message(&self, message: OutgoingMessage) -> Result<bool> {
+            self.messages
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(message);
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn counters_are_consistent_under_concurrent_updates() {
+        let (telemetry, _sink) = memory_telemetry();
+        let threads = 8;
+        let per_thread = 1000;
+
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let telemetry = telemetry.clone();
+                thread::spawn(move || {
+                    for i in 0..per_thread {
+                        telemetry.increment_frame_number();
+                        telemetry.update_video_timestamp(t * per_thread + i);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(telemetry.get_frame_number(), threads * per_thread);
+        // Concurrent writers race, but only ever store a value one of them
+        // wrote.
+        assert!(telemetry.get_video_timestamp() < threads * per_thread);
+    }
+
+    #[tokio::test]
+    async fn publish_sampled_publishes_every_nth_frame() {
+        let (telemetry, sink) = memory_telemetry();
+        let mut published = Vec::new();
+        for frame in 0..10 {
+            telemetry.update_frame_number(frame);
+            if telemetry
+                .publish_sampled("joints", &frame, 3)
+                .await
+                .unwrap()
+            {
+                published.push(frame);
+            }
+        }
+        assert_eq!(published, [0, 3, 6, 9]);
+        assert_eq!(sink.messages().len(), 4);
+
+        // 0 and 1 both mean every frame.
+        for every_n in [0, 1] {
+            telemetry.update_frame_number(7);
+            assert!(telemetry
+                .publish_sampled("joints", &7, every_n)
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_moves_to_user_properties_on_mqtt_v5() {
+        let sink = Arc::new(RecordingSink {
+            user_properties: true,
+            ..Default::default()
+        });
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.metadata_as_user_properties = true;
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+        telemetry.update_frame_number(12);
+        telemetry
+            .publish("joints", &serde_json::json!({ "position": 1.0 }))
+            .await
+            .unwrap();
+
+        let message = &sink.messages()[0];
+        let body: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+        assert_eq!(body, serde_json::json!({ "position": 1.0 }));
+        let property = |name: &str| {
+            message
+                .user_properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(property("frame_number").as_deref(), Some("12"));
+        assert_eq!(property("sequence").as_deref(), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn metadata_stays_in_the_body_without_mqtt_v5() {
+        let sink = Arc::new(RecordingSink::default());
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.metadata_as_user_properties = true;
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+        telemetry.publish("joints", &1.0).await.unwrap();
+
+        let message = &sink.messages()[0];
+        assert!(message.user_properties.is_empty());
+        let body: TelemetryPayload<f64> = serde_json::from_slice(&message.payload).unwrap();
+        assert_eq!(body.data, 1.0);
+    }
+
+    #[tokio::test]
+    async fn publishes_carry_consecutive_sequence_numbers() {
+        let (telemetry, sink) = memory_telemetry();
+        for _ in 0..3 {
+            telemetry.publish("joints", &1.0).await.unwrap();
+        }
+        telemetry.publish("imu", &1.0).await.unwrap();
+
+        let sequences: Vec<(String, u64)> = sink
+            .messages()
+            .iter()
+            .map(|(topic, payload)| {
+                let payload: TelemetryPayload<f64> = serde_json::from_slice(payload).unwrap();
+                (topic.clone(), payload.sequence)
+            })
+            .collect();
+        assert_eq!(
+            sequences,
+            [
+                ("robots/test_robot/joints".to_string(), 0),
+                ("robots/test_robot/joints".to_string(), 1),
+                ("robots/test_robot/joints".to_string(), 2),
+                ("robots/test_robot/imu".to_string(), 0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_raw_splices_the_body_into_the_envelope() {
+        let (telemetry, sink) = memory_telemetry();
+        telemetry
+            .publish_raw("joints", Bytes::from_static(br#"{"position":[1,2]}"#))
+            .await
+            .unwrap();
+        let payload: TelemetryPayload<serde_json::Value> =
+            serde_json::from_slice(&sink.messages()[0].1).unwrap();
+        assert_eq!(payload.data, serde_json::json!({ "position": [1, 2] }));
+    }
+
+    #[tokio::test]
+    async fn publish_raw_rejects_invalid_json_as_undecodable() {
+        for serialization in [SerializationFormat::Json, SerializationFormat::MessagePack] {
+            let sink = Arc::new(MemorySink::new());
+            let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+            config.serialization = serialization;
+            let telemetry = Telemetry::with_sink(config, sink.clone());
+            let result = telemetry
+                .publish_raw("joints", Bytes::from_static(b"{not json"))
+                .await;
+            assert!(matches!(result, Err(TelemetryError::Deserialize(_))));
+            assert!(sink.messages().is_empty());
+        }
+    }
+
+    #[test]
+    fn update_frame_number_is_visible_to_other_threads() {
+        let (telemetry, _sink) = memory_telemetry();
+        let writer = telemetry.clone();
+        thread::spawn(move || {
+            writer.update_frame_number(42);
+            writer.update_video_timestamp(1_000);
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(telemetry.get_frame_number(), 42);
+        assert_eq!(telemetry.get_video_timestamp(), 1_000);
+    }
+
+    #[tokio::test]
+    async fn publish_fleet_is_published_outside_the_robot_prefix() {
+        let (telemetry, sink) = memory_telemetry();
+        telemetry
+            .publish_fleet("status", &serde_json::json!({ "battery": 0.5 }))
+            .await
+            .unwrap();
+
+        let messages = sink.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "fleet/status");
+        let payload: TelemetryPayload<FleetPayload<serde_json::Value>> =
+            serde_json::from_slice(&messages[0].1).unwrap();
+        assert_eq!(payload.data.robot_id, "test_robot");
+        assert!(telemetry
+            .topic_stats()
+            .iter()
+            .any(|(topic, _)| topic == "fleet/status"));
+    }
+
+    #[tokio::test]
+    async fn publish_fleet_respects_mutes() {
+        let (telemetry, sink) = memory_telemetry();
+        telemetry.mute("fleet/*");
+        telemetry
+            .publish_fleet("status", &serde_json::json!({ "battery": 0.5 }))
+            .await
+            .unwrap();
+
+        assert!(sink.messages().is_empty());
+        assert_eq!(telemetry.muted_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn background_tasks_exit_after_the_last_clone_is_dropped() {
+        let broker = test_broker::TestBroker::start().await;
+        let mut config = broker.config("test_robot");
+        config.heartbeat_interval = Some(Duration::from_millis(10));
+        config
+            .watchdog
+            .insert("joints".to_string(), Duration::from_millis(10));
+        let telemetry = Telemetry::new(config).unwrap();
+        assert!(telemetry.wait_connected(Duration::from_secs(5)).await);
+        broker.wait_for_published(1).await;
+
+        // Only `Telemetry` clones hold the sequences, background tasks
+        // included.
+        let sequences = Arc::downgrade(&telemetry.sequences);
+        let clone = telemetry.clone();
+        drop(telemetry);
+        assert!(sequences.upgrade().is_some());
+        drop(clone);
+
+        broker.wait_until(|broker| broker.closed() == 1).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sequences.upgrade().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("background tasks still hold the instance");
+
+        let published = broker.published().len();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(broker.published().len(), published);
+    }
+
+    #[test]
+    fn muted_topics_are_reported_as_dropped() {
+        let (telemetry, sink) = memory_telemetry();
+        telemetry.mute("joints");
+        assert!(!telemetry.try_publish("joints", &1).unwrap());
+        assert!(telemetry.try_publish("imu", &1).unwrap());
+        assert_eq!(sink.messages().len(), 1);
+        assert_eq!(telemetry.muted_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn publish_on_change_publishes_after_unmute() {
+        let (telemetry, sink) = memory_telemetry();
+        telemetry.mute("firmware");
+        assert!(!telemetry
+            .publish_on_change("firmware", &"1.0")
+            .await
+            .unwrap());
+        assert!(telemetry.unmute("firmware"));
+        assert!(telemetry
+            .publish_on_change("firmware", &"1.0")
+            .await
+            .unwrap());
+        assert!(!telemetry
+            .publish_on_change("firmware", &"1.0")
+            .await
+            .unwrap());
+        assert_eq!(sink.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn set_robot_id_moves_later_publishes() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+        telemetry.publish("joints", &1).await.unwrap();
+
+        telemetry.set_robot_id("robot/2").await.unwrap();
+        assert_eq!(telemetry.robot_id(), "robot_2");
+        telemetry.publish("joints", &2).await.unwrap();
+        assert!(matches!(
+            telemetry.set_robot_id("").await,
+            Err(TelemetryError::InvalidConfig(_))
+        ));
+        assert_eq!(telemetry.robot_id(), "robot_2");
+
+        let topics: Vec<_> = sink
+            .messages()
+            .into_iter()
+            .filter(|message| message.topic.ends_with("/joints"))
+            .map(|message| message.topic)
+            .collect();
+        assert_eq!(
+            topics,
+            ["robots/test_robot/joints", "robots/robot_2/joints"]
+        );
+        let statuses: Vec<_> = sink
+            .messages()
+            .into_iter()
+            .filter(|message| message.retain && message.topic.ends_with("/status"))
+            .map(|message| message.topic)
+            .collect();
+        assert_eq!(
+            statuses,
+            ["robots/test_robot/status", "robots/robot_2/status"]
+        );
+    }
+
+    /// Holds every message except the retained status until `release`,
+    /// tracking how many it holds.
+    struct GatedSink {
+        gate: Semaphore,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl GatedSink {
+        fn new() -> Self {
+            Self {
+                gate: Semaphore::new(0),
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+            }
+        }
+
+        fn release(&self) {
+            self.gate.add_permits(Semaphore::MAX_PERMITS / 2);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TelemetrySink for GatedSink {
+        async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+            self.send_message(OutgoingMessage::new(topic, payload, qos))
+                .await
+        }
+
+        fn try_send(&self, _topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+            Ok(true)
+        }
+
+        async fn send_message(&self, message: OutgoingMessage) -> Result<()> {
+            if message.retain {
+                return Ok(());
+            }
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            // The semaphore is never closed.
+            let _ = self.gate.acquire().await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_publishes_bounds_in_flight_publishes() {
+        let sink = Arc::new(GatedSink::new());
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.max_concurrent_publishes = Some(3);
+        config.rate_limits.insert("imu".to_string(), 1000.0);
+        // Only the publishes below reach the sink.
+        config.schema_interval = None;
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+
+        let mut publishes = Vec::new();
+        for i in 0..10 {
+            let telemetry = telemetry.clone();
+            publishes.push(tokio::spawn(async move {
+                telemetry.publish("joints", &i).await.unwrap();
+            }));
+        }
+        // The second sample is held, then sent by a background task.
+        let held = telemetry.clone();
+        publishes.push(tokio::spawn(async move {
+            held.publish("imu", &1).await.unwrap();
+            held.publish("imu", &2).await.unwrap();
+        }));
+
+        while sink.in_flight.load(Ordering::SeqCst) < 3 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(sink.in_flight.load(Ordering::SeqCst), 3);
+        assert!(!telemetry.try_publish("joints", &0).unwrap());
+        // The sequence number it took shows up as a drop.
+        let stats = telemetry.topic_stats();
+        let (_, joints) = stats.iter().find(|(topic, _)| topic == "joints").unwrap();
+        assert_eq!(joints.dropped, 1);
+
+        sink.release();
+        for publish in publishes {
+            publish.await.unwrap();
+        }
+        assert_eq!(sink.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,156 @@
+use super::buffer::OfflineBuffer;
+use super::client::MqttClient;
+use super::config::OverflowPolicy;
+use super::connection::ConnectionTracker;
+use super::error::{Result, TelemetryError};
+use super::inflight::InFlight;
+use super::mask::FieldMasks;
+use super::metrics::Counters;
+use super::sink::{OutgoingMessage, TelemetrySink};
+use async_trait::async_trait;
+use rumqttc::QoS;
+use std::sync::Arc;
+
+/// Publishes to the broker. Messages are held in the offline buffer while
+/// the connection is down or the MQTT request channel is full, so that
+/// higher priorities can overtake bulk telemetry.
+#[derive(Clone)]
+pub(crate) struct MqttSink {
+    pub client: Arc<MqttClient>,
+    pub connection: Arc<ConnectionTracker>,
+    pub buffer: Arc<OfflineBuffer>,
+    pub in_flight: Arc<InFlight>,
+    pub counters: Arc<Counters>,
+    pub masks: Arc<FieldMasks>,
+    pub runtime: tokio::runtime::Handle,
+}
+
+impl MqttSink {
+    fn flush_in_background(&self) {
+        let sink = self.clone();
+        self.runtime.spawn(async move {
+            sink.buffer
+                .flush(&sink.client, &sink.connection, &sink.in_flight)
+                .await;
+        });
+    }
+
+    /// Buffers `message`, waiting for room under `OverflowPolicy::Block`. A
+    /// flush has to be running for room to free up.
+    async fn push(&self, message: OutgoingMessage) -> Result<()> {
+        if self.connection.is_connected() && self.buffer.is_full() {
+            self.flush_in_background();
+        }
+        match self.buffer.policy() {
+            OverflowPolicy::Block => self.buffer.push_waiting(message).await,
+            _ => self.buffer.push(message),
+        }
+    }
+
+    /// `try_send_message` for a message that is already masked.
+    fn try_publish(&self, message: OutgoingMessage) -> Result<bool> {
+        if !self.connection.is_connected() {
+            return match self.buffer.push(message) {
+                Ok(()) => Ok(true),
+                Err(TelemetryError::QueueFull) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        self.try_publish_now(message)
+    }
+
+    /// Hands `message` to the MQTT request channel if it has room, without
+    /// falling back to the buffer.
+    fn try_publish_now(&self, message: OutgoingMessage) -> Result<bool> {
+        let OutgoingMessage {
+            topic,
+            payload,
+            qos,
+            user_properties,
+            retain,
+            ..
+        } = message;
+        match self
+            .client
+            .try_publish(topic, qos, retain, payload, user_properties)
+        {
+            Ok(true) => {
+                self.in_flight.started();
+                Ok(true)
+            }
+            Ok(false) => Ok(false),
+            Err(e) => {
+                self.counters.publish_error();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for MqttSink {
+    async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+        self.send_message(OutgoingMessage::new(topic, payload, qos))
+            .await
+    }
+
+    fn try_send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<bool> {
+        self.try_send_message(OutgoingMessage::new(topic, payload, qos))
+    }
+
+    fn supports_user_properties(&self) -> bool {
+        self.client.supports_user_properties()
+    }
+
+    async fn send_message(&self, mut message: OutgoingMessage) -> Result<()> {
+        self.masks.apply(&mut message)?;
+
+        // Keep buffering until the backlog is drained so that messages of a
+        // priority are delivered in the order they were published.
+        if !self.connection.is_connected() || !self.buffer.is_empty() {
+            self.push(message).await?;
+            if self.connection.is_connected() {
+                self.flush_in_background();
+            }
+            return Ok(());
+        }
+
+        // Queue rather than wait for room in the request channel, so that
+        // higher priorities can overtake what is queued. Without a buffer
+        // there is nowhere to queue, so wait instead.
+        if self.buffer.capacity() > 0 {
+            // Not `try_publish`, which would buffer the message without
+            // waiting if the connection just dropped.
+            if !self.try_publish_now(message.clone())? {
+                self.push(message).await?;
+                self.flush_in_background();
+            }
+            return Ok(());
+        }
+
+        let OutgoingMessage {
+            topic,
+            payload,
+            qos,
+            user_properties,
+            retain,
+            ..
+        } = message;
+        if let Err(e) = self
+            .client
+            .publish(topic, qos, retain, payload, user_properties)
+            .await
+        {
+            self.counters.publish_error();
+            return Err(e);
+        }
+        self.in_flight.started();
+
+        Ok(())
+    }
+
+    fn try_send_message(&self, mut message: OutgoingMessage) -> Result<bool> {
+        self.masks.apply(&mut message)?;
+        self.try_publish(message)
+    }
+}
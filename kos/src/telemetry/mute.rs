@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+
+/// Subtopic patterns muted at runtime with `Telemetry::mute`.
+#[derive(Default)]
+pub(crate) struct Mutes {
+    patterns: RwLock<Vec<String>>,
+    muted: AtomicU64,
+}
+
+impl Mutes {
+    pub fn mute(&self, pattern: &str) {
+        let mut patterns = self
+            .patterns
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !patterns.iter().any(|existing| existing == pattern) {
+            patterns.push(pattern.to_string());
+        }
+    }
+
+    /// Returns whether `pattern` was muted.
+    pub fn unmute(&self, pattern: &str) -> bool {
+        let mut patterns = self
+            .patterns
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let before = patterns.len();
+        patterns.retain(|existing| existing != pattern);
+        patterns.len() != before
+    }
+
+    /// Whether `topic` matches a muted pattern, counting it if so.
+    pub fn check(&self, topic: &str) -> bool {
+        let muted = self
+            .patterns
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|pattern| matches(pattern, topic));
+        if muted {
+            self.muted.fetch_add(1, Ordering::Relaxed);
+        }
+        muted
+    }
+
+    pub fn muted(&self) -> u64 {
+        self.muted.load(Ordering::Relaxed)
+    }
+}
+
+/// Matches `topic` against `pattern`, where `*` stands for any run of
+/// characters within one topic level.
+fn matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_levels = pattern.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            (Some(pattern), Some(level)) if matches_level(pattern, level) => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn matches_level(pattern: &str, level: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = level.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*`, so the level has to match exactly.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,384 @@
+//! Export to an OpenTelemetry collector over OTLP/gRPC instead of MQTT.
+//!
+//! Numeric and boolean fields of each payload's `data` become gauge data
+//! points named `{subtopic}.{field}`, e.g. `imu.accel_x`, with the frame
+//! metadata attached as attributes. Elements of arrays get an `index`
+//! attribute. Payloads that are not JSON or MessagePack envelopes, such as
+//! line protocol, are exported as log records instead. The robot id is sent
+//! as the `service.instance.id` resource attribute.
+
+use super::clock::Clock;
+use super::compression;
+use super::config::TelemetryConfig;
+use super::error::{Result, TelemetryError};
+use super::sink::TelemetrySink;
+use super::topics;
+use async_trait::async_trait;
+use opentelemetry_proto::tonic::collector::logs::v1::logs_service_client::LogsServiceClient;
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use otlp_tonic::transport::{Channel, Endpoint};
+use rumqttc::QoS;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Envelope fields exported as data point attributes rather than metrics.
+const METADATA_FIELDS: [&str; 5] = [
+    "sequence",
+    "frame_number",
+    "video_timestamp",
+    "inference_step",
+    "episode_id",
+];
+
+/// Messages `try_send` queues for export before it starts dropping them.
+const PENDING_EXPORTS: usize = 256;
+
+/// Sink that ships telemetry to an OTLP/gRPC collector. Requires the `otlp`
+/// feature.
+#[derive(Clone)]
+pub struct OtlpSink {
+    exporter: Exporter,
+    /// Feeds the task exporting `try_send` messages one at a time.
+    pending: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+/// Collector clients, shared by `send` and the export task.
+#[derive(Clone)]
+struct Exporter {
+    metrics: MetricsServiceClient<Channel>,
+    logs: LogsServiceClient<Channel>,
+    /// `{topic_prefix}/{robot_id}/`, stripped from topics to get the
+    /// subtopic.
+    topic_root: String,
+    resource: Resource,
+    clock: Arc<dyn Clock>,
+}
+
+impl OtlpSink {
+    /// Connects to the collector at `endpoint`, e.g. `http://localhost:4317`.
+    /// `config` provides the robot id and topic prefix.
+    pub async fn connect(endpoint: impl Into<String>, config: &TelemetryConfig) -> Result<Self> {
+        let channel = Endpoint::from_shared(endpoint.into())
+            .map_err(|e| TelemetryError::Otlp(Box::new(e)))?
+            .connect()
+            .await
+            .map_err(|e| TelemetryError::Otlp(Box::new(e)))?;
+        Ok(Self::new(channel, config))
+    }
+
+    /// Spawns the export task, so this needs a Tokio runtime.
+    fn new(channel: Channel, config: &TelemetryConfig) -> Self {
+        let robot_id = topics::sanitize_robot_id(&config.robot_id);
+        let exporter = Exporter {
+            metrics: MetricsServiceClient::new(channel.clone()),
+            logs: LogsServiceClient::new(channel),
+            topic_root: format!("{}/{}/", config.topic_prefix, robot_id),
+            resource: Resource {
+                attributes: vec![
+                    string_attribute("service.name", "kos"),
+                    string_attribute("service.instance.id", &robot_id),
+                ],
+                ..Default::default()
+            },
+            clock: config.clock.clone(),
+        };
+
+        // Ends once every clone of the sink is dropped.
+        let (pending, mut rx) = mpsc::channel::<(String, Vec<u8>)>(PENDING_EXPORTS);
+        tokio::spawn({
+            let exporter = exporter.clone();
+            async move {
+                while let Some((topic, payload)) = rx.recv().await {
+                    if let Err(e) = exporter.clone().export(topic, payload).await {
+                        tracing::warn!("Failed to export telemetry over OTLP: {}", e);
+                    }
+                }
+            }
+        });
+        Self { exporter, pending }
+    }
+}
+
+impl Exporter {
+    fn unix_nanos(&self) -> u64 {
+        self.clock.now_unix().as_nanos() as u64
+    }
+
+    /// Strips the prefix, robot id and any encoding or compression levels.
+    fn subtopic<'a>(&self, topic: &'a str) -> &'a str {
+        let mut subtopic = topic.strip_prefix(&self.topic_root).unwrap_or(topic);
+        for suffix in ["/gzip", "/zstd", "/msgpack"] {
+            subtopic = subtopic.strip_suffix(suffix).unwrap_or(subtopic);
+        }
+        subtopic
+    }
+
+    async fn export(self, topic: String, payload: Vec<u8>) -> Result<()> {
+        let subtopic = self.subtopic(&topic).to_string();
+        let payload = compression::decompress(&payload)?;
+
+        let envelope = if topic.ends_with("/msgpack") || topic.contains("/msgpack/") {
+            rmp_serde::from_slice::<Value>(&payload).ok()
+        } else {
+            serde_json::from_slice::<Value>(&payload).ok()
+        };
+
+        match envelope {
+            Some(Value::Object(envelope)) if envelope.contains_key("data") => {
+                let request = self.metrics_request(&subtopic, &envelope);
+                self.metrics
+                    .clone()
+                    .export(request)
+                    .await
+                    .map_err(|e| TelemetryError::Otlp(Box::new(e)))?;
+            }
+            _ => {
+                let request = self.logs_request(&subtopic, &payload);
+                self.logs
+                    .clone()
+                    .export(request)
+                    .await
+                    .map_err(|e| TelemetryError::Otlp(Box::new(e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn metrics_request(
+        &self,
+        subtopic: &str,
+        envelope: &serde_json::Map<String, Value>,
+    ) -> ExportMetricsServiceRequest {
+        let time_unix_nano = envelope
+            .get("unix_nanos")
+            .and_then(Value::as_u64)
+            .unwrap_or_else(|| self.unix_nanos());
+        let attributes: Vec<KeyValue> = METADATA_FIELDS
+            .iter()
+            .filter_map(|key| {
+                let value = envelope.get(*key)?.as_i64()?;
+                Some(int_attribute(key, value))
+            })
+            .collect();
+
+        let mut points = BTreeMap::new();
+        flatten(
+            subtopic.replace('/', "."),
+            &envelope["data"],
+            &attributes,
+            &mut points,
+        );
+
+        let metrics = points
+            .into_iter()
+            .map(|(name, points)| Metric {
+                name,
+                data: Some(metric::Data::Gauge(Gauge {
+                    data_points: points
+                        .into_iter()
+                        .map(|(attributes, value)| NumberDataPoint {
+                            attributes,
+                            time_unix_nano,
+                            value: Some(value),
+                            ..Default::default()
+                        })
+                        .collect(),
+                })),
+                ..Default::default()
+            })
+            .collect();
+
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(scope()),
+                    metrics,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    fn logs_request(&self, subtopic: &str, payload: &[u8]) -> ExportLogsServiceRequest {
+        let now = self.unix_nanos();
+        ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(self.resource.clone()),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(scope()),
+                    log_records: vec![LogRecord {
+                        time_unix_nano: now,
+                        observed_time_unix_nano: now,
+                        body: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue(
+                                String::from_utf8_lossy(payload).into_owned(),
+                            )),
+                        }),
+                        attributes: vec![string_attribute("topic", subtopic)],
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+type Points = BTreeMap<String, Vec<(Vec<KeyValue>, number_data_point::Value)>>;
+
+fn flatten(name: String, value: &Value, attributes: &[KeyValue], points: &mut Points) {
+    let point = match value {
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => number_data_point::Value::AsInt(n),
+            None => number_data_point::Value::AsDouble(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::Bool(b) => number_data_point::Value::AsInt(*b as i64),
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                flatten(format!("{}.{}", name, key), value, attributes, points);
+            }
+            return;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let mut attributes = attributes.to_vec();
+                attributes.push(int_attribute("index", i as i64));
+                flatten(name.clone(), item, &attributes, points);
+            }
+            return;
+        }
+        Value::Null | Value::String(_) => return,
+    };
+    points
+        .entry(name)
+        .or_default()
+        .push((attributes.to_vec(), point));
+}
+
+fn scope() -> InstrumentationScope {
+    InstrumentationScope {
+        name: "kos-telemetry".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn int_attribute(key: &str, value: i64) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::IntValue(value)),
+        }),
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for OtlpSink {
+    async fn send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<()> {
+        self.exporter.clone().export(topic, payload).await
+    }
+
+    /// Queues the message for a background task that exports one message at
+    /// a time. Returns `Ok(false)` if `PENDING_EXPORTS` messages are already
+    /// waiting, e.g. because the collector is slow.
+    fn try_send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+        Ok(self.pending.try_send((topic, payload)).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sink() -> OtlpSink {
+        // Nothing listens there, and the lazy channel only connects on the
+        // first export.
+        let channel = Endpoint::from_static("http://127.0.0.1:9").connect_lazy();
+        OtlpSink::new(
+            channel,
+            &TelemetryConfig::new("test_robot", "localhost", 1883),
+        )
+    }
+
+    #[tokio::test]
+    async fn try_send_drops_once_the_queue_is_full() {
+        let sink = sink();
+        // The export task cannot run before this test yields, so nothing is
+        // taken off the queue.
+        for _ in 0..PENDING_EXPORTS {
+            assert!(sink
+                .try_send(
+                    "robots/test_robot/imu".to_string(),
+                    Vec::new(),
+                    QoS::AtMostOnce
+                )
+                .unwrap());
+        }
+        assert!(!sink
+            .try_send(
+                "robots/test_robot/imu".to_string(),
+                Vec::new(),
+                QoS::AtMostOnce
+            )
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn subtopic_strips_root_and_encoding() {
+        let sink = sink();
+        assert_eq!(sink.exporter.subtopic("robots/test_robot/imu"), "imu");
+        assert_eq!(
+            sink.exporter
+                .subtopic("robots/test_robot/joints/left/msgpack/gzip"),
+            "joints/left"
+        );
+    }
+
+    #[tokio::test]
+    async fn fields_become_gauges_with_metadata_attributes() {
+        let sink = sink();
+        let envelope = json!({
+            "frame_number": 3,
+            "unix_nanos": 1_000,
+            "data": [{ "position": 1.5, "online": true, "name": "hip" }],
+        });
+        let request = sink
+            .exporter
+            .metrics_request("joints", envelope.as_object().unwrap());
+        let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+        let names: Vec<_> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["joints.online", "joints.position"]);
+
+        let Some(metric::Data::Gauge(gauge)) = &metrics[1].data else {
+            panic!("expected a gauge");
+        };
+        let point = &gauge.data_points[0];
+        assert_eq!(point.time_unix_nano, 1_000);
+        assert_eq!(point.value, Some(number_data_point::Value::AsDouble(1.5)));
+        let keys: Vec<_> = point.attributes.iter().map(|a| a.key.as_str()).collect();
+        assert_eq!(keys, ["frame_number", "index"]);
+    }
+}
@@ -0,0 +1,673 @@
+//! Typed payloads for the canonical telemetry topics. These define the schema
+//! that the InfluxDB side depends on, so fields should only ever be added.
+
+use super::line_protocol::{FieldValue, IntoLineProtocol};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+pub const JOINTS_TOPIC: &str = "joints";
+pub const IMU_TOPIC: &str = "imu";
+pub const HEARTBEAT_TOPIC: &str = "heartbeat";
+pub const COMMAND_TOPIC: &str = "command";
+pub const STATUS_TOPIC: &str = "status";
+pub const VIDEO_TOPIC: &str = "video";
+pub const DIAGNOSTICS_TOPIC: &str = "diagnostics";
+pub const FRAME_TOPIC: &str = "frame";
+pub const LOGS_TOPIC: &str = "logs";
+pub const SCHEMA_TOPIC: &str = "schema";
+/// Root of the fleet-wide topic tree, outside `{topic_prefix}/{robot_id}`.
+pub const FLEET_TOPIC: &str = "fleet";
+pub const TIME_REQUEST_TOPIC: &str = "time/request";
+pub const TIME_RESPONSE_TOPIC: &str = "time/response";
+pub const CONFIG_TOPIC: &str = "config";
+pub const CONFIG_ACK_TOPIC: &str = "config/ack";
+pub const ALERT_TOPIC: &str = "alert";
+
+/// JSON Schema of `T`, e.g. to validate ingestion configs against or to
+/// generate consumer code from. Requires the `schemars` feature.
+#[cfg(feature = "schemars")]
+pub fn payload_schema<T: schemars::JsonSchema>() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(T)).unwrap_or_default()
+}
+
+#[cfg(feature = "schemars")]
+pub fn joint_state_schema() -> serde_json::Value {
+    payload_schema::<JointState>()
+}
+
+#[cfg(feature = "schemars")]
+pub fn imu_reading_schema() -> serde_json::Value {
+    payload_schema::<ImuReading>()
+}
+
+#[cfg(feature = "schemars")]
+pub fn diagnostics_schema() -> serde_json::Value {
+    payload_schema::<Diagnostics>()
+}
+
+/// Version of a payload's schema, stamped into the envelope as
+/// `schema_version` so consumers can branch on it. Bump it whenever the
+/// struct changes and note what changed next to the impl. That includes
+/// payloads embedded in it: a struct carrying a `JointState` is bumped along
+/// with `JointState`, since its envelope is the only version consumers see.
+pub trait SchemaVersion {
+    const SCHEMA_VERSION: u16;
+}
+
+/// Which setpoint of a `JointState` the actuator is following.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMode {
+    /// Not reported, e.g. by publishers predating the field.
+    #[default]
+    Unknown,
+    Position,
+    Velocity,
+    Torque,
+}
+
+impl ControlMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ControlMode::Unknown => "unknown",
+            ControlMode::Position => "position",
+            ControlMode::Velocity => "velocity",
+            ControlMode::Torque => "torque",
+        }
+    }
+}
+
+/// Desired vs actual state of a single actuator. Fields that do not apply to
+/// the actuator's control mode are left as `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct JointState {
+    pub actuator_id: u32,
+    /// Which of the desired fields is the active setpoint.
+    #[serde(default)]
+    pub control_mode: ControlMode,
+    pub desired_position: Option<f32>,
+    pub actual_position: Option<f32>,
+    pub desired_velocity: Option<f32>,
+    pub actual_velocity: Option<f32>,
+    pub desired_torque: Option<f32>,
+    pub actual_torque: Option<f32>,
+}
+
+/// Health of a single actuator. Readings the actuator does not report are
+/// left as `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Diagnostics {
+    pub actuator_id: u32,
+    pub temperature_c: Option<f32>,
+    pub voltage_v: Option<f32>,
+    pub current_a: Option<f32>,
+    pub fault_code: Option<u32>,
+    pub online: bool,
+}
+
+/// Periodic liveness message, published when
+/// `TelemetryConfig::heartbeat_interval` is set.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Heartbeat {
+    pub frame_number: u64,
+    pub inference_step: u64,
+    /// Time since the current MQTT connection was established.
+    pub connection_uptime_secs: f64,
+}
+
+/// Retained list of the subtopics a robot publishes, so dashboards can
+/// configure themselves.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchemaAnnouncement {
+    pub topics: Vec<TopicSchema>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopicSchema {
+    pub topic: String,
+    /// Rust type name of the payload data.
+    pub type_name: String,
+    /// `SchemaVersion` of the payload, or 0 if it is unversioned.
+    #[serde(default)]
+    pub schema_version: u16,
+}
+
+/// Commands accepted on `robots/{robot_id}/command`, e.g.
+/// `"start_logging"` or `{"set_episode": 3}` as the envelope's `data`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    StartLogging,
+    StopLogging,
+    SetEpisode(u64),
+}
+
+/// Body of messages published with `Telemetry::publish_fleet`. The robot id
+/// is stamped in since the topic no longer identifies the robot.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FleetPayload<T> {
+    pub robot_id: String,
+    pub data: T,
+}
+
+/// Published to `time/request` when measuring the clock offset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSyncRequest {
+    pub id: u32,
+    /// Robot wall-clock time the request was sent at.
+    pub robot_unix_nanos: u64,
+}
+
+/// Expected on `time/response` for every `TimeSyncRequest`: the request's
+/// fields echoed back, plus the server's wall-clock time on receipt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSyncResponse {
+    pub id: u32,
+    pub robot_unix_nanos: u64,
+    pub server_unix_nanos: u64,
+}
+
+/// Expected on `config` with `TelemetryConfig::remote_config`: the settings
+/// to change on the running robot. Fields that cannot be changed at runtime,
+/// such as the QoS or the broker, are rejected as unknown. An update is
+/// applied in full or not at all.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    /// Echoed in the `ConfigAck`.
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// Messages per second by subtopic, `null` lifting the limit.
+    #[serde(default)]
+    pub rate_limits: HashMap<String, Option<f32>>,
+    /// Subtopic patterns to pass to `Telemetry::mute`.
+    #[serde(default)]
+    pub mute: Vec<String>,
+    /// Subtopic patterns to pass to `Telemetry::unmute`.
+    #[serde(default)]
+    pub unmute: Vec<String>,
+    /// Passed to `Telemetry::set_enabled`.
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// `json` or `line_protocol`, see `Telemetry::set_format`.
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// Published to `config/ack` for every message received on `config`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigAck {
+    pub id: Option<u64>,
+    pub accepted: bool,
+    /// Why the update was rejected.
+    pub error: Option<String>,
+}
+
+/// Published to `alert` when a topic listed in `TelemetryConfig::watchdog`
+/// has gone quiet for longer than its interval.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WatchdogAlert {
+    /// The stalled subtopic.
+    pub topic: String,
+    /// Time since the last publish to it, or since startup if there was none.
+    pub silent_secs: f64,
+}
+
+/// Minimum, maximum and mean of one numeric field over an aggregation
+/// window. Non-finite values are left out.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldSummary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub count: u64,
+}
+
+/// Per-window aggregate of one actuator's `JointState`s, published by an
+/// `Aggregator`. Fields the actuator did not report are missing.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JointSummary {
+    pub actuator_id: u32,
+    pub samples: u64,
+    pub fields: BTreeMap<String, FieldSummary>,
+}
+
+/// Per-window aggregate of `ImuReading`s, published by an `Aggregator`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImuSummary {
+    pub samples: u64,
+    pub fields: BTreeMap<String, FieldSummary>,
+}
+
+/// A single IMU sample. The JSON keys are pinned with explicit renames so
+/// that renaming a Rust field can never silently change the schema.
+///
+/// | key           | unit             |
+/// |---------------|------------------|
+/// | `accel_{xyz}` | m/s^2            |
+/// | `gyro_{xyz}`  | deg/s            |
+/// | `mag_{xyz}`   | uT               |
+/// | `quaternion`  | `[x, y, z, w]`   |
+/// | `temperature` | degrees Celsius  |
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ImuReading {
+    #[serde(rename = "accel_x")]
+    pub accel_x: f32,
+    #[serde(rename = "accel_y")]
+    pub accel_y: f32,
+    #[serde(rename = "accel_z")]
+    pub accel_z: f32,
+    #[serde(rename = "gyro_x")]
+    pub gyro_x: f32,
+    #[serde(rename = "gyro_y")]
+    pub gyro_y: f32,
+    #[serde(rename = "gyro_z")]
+    pub gyro_z: f32,
+    #[serde(rename = "mag_x")]
+    pub mag_x: Option<f32>,
+    #[serde(rename = "mag_y")]
+    pub mag_y: Option<f32>,
+    #[serde(rename = "mag_z")]
+    pub mag_z: Option<f32>,
+    #[serde(rename = "quaternion")]
+    pub quaternion: Option<[f32; 4]>,
+    #[serde(rename = "temperature")]
+    pub temperature: Option<f32>,
+}
+
+/// Version byte of the `ImuReading::to_binary` layout.
+const IMU_BINARY_VERSION: u8 = 1;
+
+/// Presence bits of the optional `ImuReading` fields in the binary layout.
+const IMU_HAS_MAG_X: u8 = 1 << 0;
+const IMU_HAS_MAG_Y: u8 = 1 << 1;
+const IMU_HAS_MAG_Z: u8 = 1 << 2;
+const IMU_HAS_QUATERNION: u8 = 1 << 3;
+const IMU_HAS_TEMPERATURE: u8 = 1 << 4;
+
+impl ImuReading {
+    /// Size of the `to_binary` encoding.
+    pub const BINARY_LEN: usize = 60;
+
+    /// Encodes the reading in a fixed little-endian layout, regardless of
+    /// the host byte order, for consumers that copy it straight into a
+    /// struct:
+    ///
+    /// | Offset | Type       | Field                                  |
+    /// |--------|------------|----------------------------------------|
+    /// | 0      | `u8`       | layout version, 1                      |
+    /// | 1      | `u8`       | presence bits, see below               |
+    /// | 2      | `u16`      | reserved, 0                            |
+    /// | 4      | `f32`      | `accel_x`                              |
+    /// | 8      | `f32`      | `accel_y`                              |
+    /// | 12     | `f32`      | `accel_z`                              |
+    /// | 16     | `f32`      | `gyro_x`                               |
+    /// | 20     | `f32`      | `gyro_y`                               |
+    /// | 24     | `f32`      | `gyro_z`                               |
+    /// | 28     | `f32`      | `mag_x`                                |
+    /// | 32     | `f32`      | `mag_y`                                |
+    /// | 36     | `f32`      | `mag_z`                                |
+    /// | 40     | `[f32; 4]` | `quaternion` as `[x, y, z, w]`         |
+    /// | 56     | `f32`      | `temperature`                          |
+    ///
+    /// Bits 0 to 2 of the presence byte are set when `mag_x`, `mag_y` and
+    /// `mag_z` are, bit 3 for `quaternion` and bit 4 for `temperature`.
+    /// Absent fields are written as 0.
+    pub fn to_binary(&self) -> [u8; Self::BINARY_LEN] {
+        let mut presence = 0;
+        for (bit, present) in [
+            (IMU_HAS_MAG_X, self.mag_x.is_some()),
+            (IMU_HAS_MAG_Y, self.mag_y.is_some()),
+            (IMU_HAS_MAG_Z, self.mag_z.is_some()),
+            (IMU_HAS_QUATERNION, self.quaternion.is_some()),
+            (IMU_HAS_TEMPERATURE, self.temperature.is_some()),
+        ] {
+            if present {
+                presence |= bit;
+            }
+        }
+
+        let quaternion = self.quaternion.unwrap_or_default();
+        let floats = [
+            self.accel_x,
+            self.accel_y,
+            self.accel_z,
+            self.gyro_x,
+            self.gyro_y,
+            self.gyro_z,
+            self.mag_x.unwrap_or_default(),
+            self.mag_y.unwrap_or_default(),
+            self.mag_z.unwrap_or_default(),
+            quaternion[0],
+            quaternion[1],
+            quaternion[2],
+            quaternion[3],
+            self.temperature.unwrap_or_default(),
+        ];
+
+        let mut bytes = [0; Self::BINARY_LEN];
+        bytes[0] = IMU_BINARY_VERSION;
+        bytes[1] = presence;
+        for (chunk, value) in bytes[4..].chunks_exact_mut(4).zip(floats) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes the layout written by `to_binary`.
+    pub fn from_binary(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() != Self::BINARY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "binary IMU reading must be {} bytes, got {}",
+                    Self::BINARY_LEN,
+                    bytes.len()
+                ),
+            ));
+        }
+        if bytes[0] != IMU_BINARY_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported binary IMU layout version {}", bytes[0]),
+            ));
+        }
+
+        let presence = bytes[1];
+        let float = |index: usize| {
+            let offset = 4 + 4 * index;
+            f32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let optional = |bit: u8, index: usize| (presence & bit != 0).then(|| float(index));
+
+        Ok(Self {
+            accel_x: float(0),
+            accel_y: float(1),
+            accel_z: float(2),
+            gyro_x: float(3),
+            gyro_y: float(4),
+            gyro_z: float(5),
+            mag_x: optional(IMU_HAS_MAG_X, 6),
+            mag_y: optional(IMU_HAS_MAG_Y, 7),
+            mag_z: optional(IMU_HAS_MAG_Z, 8),
+            quaternion: (presence & IMU_HAS_QUATERNION != 0)
+                .then(|| [float(9), float(10), float(11), float(12)]),
+            temperature: optional(IMU_HAS_TEMPERATURE, 13),
+        })
+    }
+}
+
+/// Metadata of one encoded video frame. Publishing it also sets the
+/// `video_timestamp` stamped on every other payload, so robot state can be
+/// joined to the exact frame it was captured with.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct VideoFrameMeta {
+    pub frame_number: u64,
+    /// Presentation timestamp of the frame in nanoseconds.
+    pub pts_nanos: u64,
+    pub keyframe: bool,
+    pub codec: String,
+}
+
+/// Joint states and the IMU reading of one control frame, published as a
+/// single message so the two can never be split or misaligned downstream.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SyncFrame {
+    pub joints: Vec<JointState>,
+    pub imu: ImuReading,
+}
+
+impl SyncFrame {
+    /// Line protocol points of the frame: one per joint, then the IMU.
+    pub(crate) fn points(&self) -> Vec<FramePoint<'_>> {
+        self.joints
+            .iter()
+            .map(FramePoint::Joint)
+            .chain(std::iter::once(FramePoint::Imu(&self.imu)))
+            .collect()
+    }
+}
+
+/// A line of a `SyncFrame` in line protocol, keeping the `joints` and `imu`
+/// measurements.
+pub(crate) enum FramePoint<'a> {
+    Joint(&'a JointState),
+    Imu(&'a ImuReading),
+}
+
+impl IntoLineProtocol for FramePoint<'_> {
+    fn measurement(&self) -> &'static str {
+        match self {
+            FramePoint::Joint(joint) => joint.measurement(),
+            FramePoint::Imu(imu) => imu.measurement(),
+        }
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        match self {
+            FramePoint::Joint(joint) => joint.tags(),
+            FramePoint::Imu(imu) => imu.tags(),
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        match self {
+            FramePoint::Joint(joint) => joint.fields(),
+            FramePoint::Imu(imu) => imu.fields(),
+        }
+    }
+}
+
+impl IntoLineProtocol for JointState {
+    fn measurement(&self) -> &'static str {
+        JOINTS_TOPIC
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("actuator_id", self.actuator_id.to_string()),
+            ("control_mode", self.control_mode.as_str().to_string()),
+        ]
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        vec![
+            ("desired_position", self.desired_position.map(Into::into)),
+            ("actual_position", self.actual_position.map(Into::into)),
+            ("desired_velocity", self.desired_velocity.map(Into::into)),
+            ("actual_velocity", self.actual_velocity.map(Into::into)),
+            ("desired_torque", self.desired_torque.map(Into::into)),
+            ("actual_torque", self.actual_torque.map(Into::into)),
+        ]
+    }
+}
+
+impl IntoLineProtocol for Diagnostics {
+    fn measurement(&self) -> &'static str {
+        DIAGNOSTICS_TOPIC
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![("actuator_id", self.actuator_id.to_string())]
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        vec![
+            ("temperature_c", self.temperature_c.map(Into::into)),
+            ("voltage_v", self.voltage_v.map(Into::into)),
+            ("current_a", self.current_a.map(Into::into)),
+            ("fault_code", self.fault_code.map(Into::into)),
+            ("online", Some(self.online.into())),
+        ]
+    }
+}
+
+impl IntoLineProtocol for ImuReading {
+    fn measurement(&self) -> &'static str {
+        IMU_TOPIC
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        let quaternion = self.quaternion;
+        vec![
+            ("accel_x", Some(self.accel_x.into())),
+            ("accel_y", Some(self.accel_y.into())),
+            ("accel_z", Some(self.accel_z.into())),
+            ("gyro_x", Some(self.gyro_x.into())),
+            ("gyro_y", Some(self.gyro_y.into())),
+            ("gyro_z", Some(self.gyro_z.into())),
+            ("mag_x", self.mag_x.map(Into::into)),
+            ("mag_y", self.mag_y.map(Into::into)),
+            ("mag_z", self.mag_z.map(Into::into)),
+            ("quat_x", quaternion.map(|q| q[0].into())),
+            ("quat_y", quaternion.map(|q| q[1].into())),
+            ("quat_z", quaternion.map(|q| q[2].into())),
+            ("quat_w", quaternion.map(|q| q[3].into())),
+            ("temperature", self.temperature.map(Into::into)),
+        ]
+    }
+}
+
+impl IntoLineProtocol for VideoFrameMeta {
+    fn measurement(&self) -> &'static str {
+        VIDEO_TOPIC
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        vec![("codec", self.codec.clone())]
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        vec![
+            ("video_frame_number", Some(self.frame_number.into())),
+            ("pts_nanos", Some(self.pts_nanos.into())),
+            ("keyframe", Some(self.keyframe.into())),
+        ]
+    }
+}
+
+// 1: initial schema.
+// 2: added `control_mode`.
+impl SchemaVersion for JointState {
+    const SCHEMA_VERSION: u16 = 2;
+}
+
+// 1: initial schema.
+impl SchemaVersion for Diagnostics {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+// 1: initial schema.
+impl SchemaVersion for Heartbeat {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+// 1: initial schema.
+impl SchemaVersion for ImuReading {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+// 1: initial schema.
+impl SchemaVersion for VideoFrameMeta {
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+// 1: initial schema.
+// 2: `joints` are `JointState` 2, with `control_mode`.
+impl SchemaVersion for SyncFrame {
+    const SCHEMA_VERSION: u16 = 2;
+}
+
+// Fails to build if a payload `SyncFrame` embeds is bumped without it.
+const _: () = assert!(
+    SyncFrame::SCHEMA_VERSION >= JointState::SCHEMA_VERSION
+        && SyncFrame::SCHEMA_VERSION >= ImuReading::SCHEMA_VERSION
+);
+
+impl SchemaVersion for FramePoint<'_> {
+    const SCHEMA_VERSION: u16 = SyncFrame::SCHEMA_VERSION;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> ImuReading {
+        ImuReading {
+            accel_x: 1.0,
+            accel_y: -1.0,
+            accel_z: 9.5,
+            gyro_x: 0.5,
+            gyro_y: 2.0,
+            gyro_z: 0.25,
+            mag_x: Some(4.0),
+            mag_y: None,
+            mag_z: Some(-2.0),
+            quaternion: Some([0.0, 0.0, 0.0, 1.0]),
+            temperature: None,
+        }
+    }
+
+    #[test]
+    fn imu_binary_layout_is_fixed() {
+        #[rustfmt::skip]
+        let expected: [u8; ImuReading::BINARY_LEN] = [
+            // version, presence of mag_x, mag_z and quaternion, reserved
+            0x01, 0x0d, 0x00, 0x00,
+            0x00, 0x00, 0x80, 0x3f, // accel_x 1.0
+            0x00, 0x00, 0x80, 0xbf, // accel_y -1.0
+            0x00, 0x00, 0x18, 0x41, // accel_z 9.5
+            0x00, 0x00, 0x00, 0x3f, // gyro_x 0.5
+            0x00, 0x00, 0x00, 0x40, // gyro_y 2.0
+            0x00, 0x00, 0x80, 0x3e, // gyro_z 0.25
+            0x00, 0x00, 0x80, 0x40, // mag_x 4.0
+            0x00, 0x00, 0x00, 0x00, // mag_y absent
+            0x00, 0x00, 0x00, 0xc0, // mag_z -2.0
+            0x00, 0x00, 0x00, 0x00, // quaternion x
+            0x00, 0x00, 0x00, 0x00, // quaternion y
+            0x00, 0x00, 0x00, 0x00, // quaternion z
+            0x00, 0x00, 0x80, 0x3f, // quaternion w 1.0
+            0x00, 0x00, 0x00, 0x00, // temperature absent
+        ];
+        assert_eq!(reading().to_binary(), expected);
+        assert_eq!(ImuReading::from_binary(&expected).unwrap(), reading());
+    }
+
+    #[test]
+    fn imu_binary_round_trips() {
+        let all = ImuReading {
+            mag_y: Some(0.125),
+            temperature: Some(36.6),
+            ..reading()
+        };
+        for reading in [reading(), all, ImuReading::default()] {
+            assert_eq!(
+                ImuReading::from_binary(&reading.to_binary()).unwrap(),
+                reading
+            );
+        }
+    }
+
+    #[test]
+    fn imu_binary_rejects_other_lengths_and_versions() {
+        let bytes = reading().to_binary();
+        assert!(ImuReading::from_binary(&bytes[..59]).is_err());
+
+        let mut future = bytes;
+        future[0] = 2;
+        assert!(ImuReading::from_binary(&future).is_err());
+    }
+}
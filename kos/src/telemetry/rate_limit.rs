@@ -0,0 +1,336 @@
+use super::clock::Clock;
+use super::error::{Result, TelemetryError};
+use super::message::Message;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+/// Rates in Hz a topic can be limited to, so the interval between messages
+/// stays within what a `Duration` holds and above timer resolution.
+pub(crate) const RATE_RANGE: RangeInclusive<f32> = 0.001..=100_000.0;
+
+/// Fails with `InvalidConfig` unless `hz` is within `RATE_RANGE`.
+pub(crate) fn validate_rate(topic: &str, hz: f32) -> Result<()> {
+    if !RATE_RANGE.contains(&hz) {
+        return Err(TelemetryError::InvalidConfig(format!(
+            "rate limit for {:?} must be between {} and {} Hz, got {}",
+            topic,
+            RATE_RANGE.start(),
+            RATE_RANGE.end(),
+            hz
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) enum Admission {
+    /// Send the message right away.
+    Send(Message),
+    /// The message is held as the latest sample of the current window. The
+    /// caller should call `take_pending` once `wake_in` has elapsed.
+    Held { wake_in: Duration },
+    /// The message replaced an already held sample; a wake-up is already
+    /// scheduled.
+    Replaced,
+}
+
+pub(crate) enum Wake {
+    Ready(Option<Message>),
+    Retry(Duration),
+}
+
+#[derive(Default)]
+struct TopicWindow {
+    /// Monotonic time of the last send.
+    last_sent: Option<Duration>,
+    pending: Option<Message>,
+    wake_scheduled: bool,
+    /// Held samples superseded by a newer one.
+    dropped: u64,
+}
+
+/// Per-topic rate limiter. At most one message is sent per interval; if more
+/// arrive, only the most recent one is kept and sent when the interval ends.
+/// Uses monotonic time so wall-clock jumps cannot cause bursts.
+pub(crate) struct RateLimiter {
+    /// Configured messages per second by topic.
+    rates: RwLock<HashMap<String, f32>>,
+    /// Bits of the `f32` every rate is multiplied by, see `set_scale`.
+    scale: AtomicU32,
+    windows: Mutex<HashMap<String, TopicWindow>>,
+    dropped: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limits: &HashMap<String, f32>, clock: Arc<dyn Clock>) -> Self {
+        // `TelemetryConfig::validate` rejects these, but `with_sink` does not
+        // validate.
+        let rates = rate_limits
+            .iter()
+            .filter(|(topic, hz)| match validate_rate(topic, **hz) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!("Ignoring rate limit: {}", e);
+                    false
+                }
+            })
+            .map(|(topic, hz)| (topic.clone(), *hz))
+            .collect();
+
+        Self {
+            rates: RwLock::new(rates),
+            scale: AtomicU32::new(1.0f32.to_bits()),
+            windows: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    /// Limits `topic` to `hz` messages per second, or lifts its limit with
+    /// `None`. A sample held under the old limit is sent at the next wake.
+    /// Rates outside `RATE_RANGE` are ignored, keeping the current limit.
+    pub fn set_rate(&self, topic: &str, hz: Option<f32>) {
+        if let Some(Err(e)) = hz.map(|hz| validate_rate(topic, hz)) {
+            tracing::warn!("Ignoring rate limit: {}", e);
+            return;
+        }
+        let mut rates = self.rates.write().unwrap_or_else(PoisonError::into_inner);
+        match hz {
+            Some(hz) => rates.insert(topic.to_string(), hz),
+            None => rates.remove(topic),
+        };
+    }
+
+    /// Multiplies every rate by `scale`, in `(0, 1]`, to throttle all limited
+    /// topics at once.
+    pub fn set_scale(&self, scale: f32) {
+        self.scale.store(scale.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn scale(&self) -> f32 {
+        f32::from_bits(self.scale.load(Ordering::Relaxed))
+    }
+
+    /// Current messages per second of every limited topic, after `scale`.
+    pub fn effective_rates(&self) -> Vec<(String, f32)> {
+        let scale = self.scale();
+        self.rates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(topic, hz)| (topic.clone(), hz * scale))
+            .collect()
+    }
+
+    fn interval(&self, topic: &str) -> Option<Duration> {
+        let hz = *self
+            .rates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)?;
+        // A tiny scale can still stretch the interval past `Duration::MAX`.
+        Some(Duration::try_from_secs_f32(1.0 / (hz * self.scale())).unwrap_or(Duration::MAX))
+    }
+
+    pub fn admit(&self, topic: &str, message: Message) -> Admission {
+        let Some(interval) = self.interval(topic) else {
+            return Admission::Send(message);
+        };
+
+        let now = self.clock.now_monotonic();
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let window = windows.entry(topic.to_string()).or_default();
+
+        match window.last_sent {
+            Some(last_sent) if now.saturating_sub(last_sent) < interval => {
+                if window.pending.replace(message).is_some() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    window.dropped += 1;
+                }
+                if window.wake_scheduled {
+                    Admission::Replaced
+                } else {
+                    window.wake_scheduled = true;
+                    Admission::Held {
+                        wake_in: interval - now.saturating_sub(last_sent),
+                    }
+                }
+            }
+            _ => {
+                window.last_sent = Some(now);
+                if window.pending.take().is_some() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    window.dropped += 1;
+                }
+                Admission::Send(message)
+            }
+        }
+    }
+
+    /// Called when a scheduled wake-up fires. Returns the held sample and
+    /// starts a new window, or asks to wait longer if a message was sent in
+    /// the meantime and the current window has not ended yet.
+    pub fn take_pending(&self, topic: &str) -> Wake {
+        let interval = self.interval(topic);
+        let now = self.clock.now_monotonic();
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(window) = windows.get_mut(topic) else {
+            return Wake::Ready(None);
+        };
+        // The limit was lifted while a sample was held.
+        let Some(interval) = interval else {
+            window.wake_scheduled = false;
+            return Wake::Ready(window.pending.take());
+        };
+
+        if let Some(last_sent) = window.last_sent {
+            let elapsed = now.saturating_sub(last_sent);
+            if elapsed < interval && window.pending.is_some() {
+                return Wake::Retry(interval - elapsed);
+            }
+        }
+
+        window.wake_scheduled = false;
+        let pending = window.pending.take();
+        if pending.is_some() {
+            window.last_sent = Some(now);
+        }
+        Wake::Ready(pending)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// `dropped` by topic, for the topics that dropped any.
+    pub fn dropped_by_topic(&self) -> Vec<(String, u64)> {
+        self.windows
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, window)| window.dropped > 0)
+            .map(|(topic, window)| (topic.clone(), window.dropped))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::TestClock;
+    use crate::telemetry::message::Encoding;
+    use rumqttc::QoS;
+
+    fn limiter(hz: f32) -> (RateLimiter, Arc<TestClock>) {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let rates = HashMap::from([("imu".to_string(), hz)]);
+        (RateLimiter::new(&rates, clock.clone()), clock)
+    }
+
+    fn message(payload: &[u8]) -> Message {
+        Message {
+            payload: payload.to_vec(),
+            qos: QoS::AtMostOnce,
+            encoding: Encoding::Json,
+            user_properties: Vec::new(),
+            captured_at_nanos: 0,
+        }
+    }
+
+    #[test]
+    fn holds_the_latest_sample_until_the_window_ends() {
+        let (limiter, clock) = limiter(4.0);
+        assert!(matches!(
+            limiter.admit("imu", message(b"1")),
+            Admission::Send(_)
+        ));
+
+        clock.advance(Duration::from_millis(100));
+        match limiter.admit("imu", message(b"2")) {
+            Admission::Held { wake_in } => assert_eq!(wake_in, Duration::from_millis(150)),
+            _ => panic!("expected the sample to be held"),
+        }
+        assert!(matches!(
+            limiter.admit("imu", message(b"3")),
+            Admission::Replaced
+        ));
+        assert_eq!(limiter.dropped(), 1);
+        assert_eq!(limiter.dropped_by_topic(), vec![("imu".to_string(), 1)]);
+
+        clock.advance(Duration::from_millis(150));
+        match limiter.take_pending("imu") {
+            Wake::Ready(Some(message)) => assert_eq!(message.payload, b"3"),
+            _ => panic!("expected the held sample"),
+        }
+    }
+
+    #[test]
+    fn early_wake_is_retried() {
+        let (limiter, clock) = limiter(4.0);
+        limiter.admit("imu", message(b"1"));
+        clock.advance(Duration::from_millis(50));
+        limiter.admit("imu", message(b"2"));
+
+        clock.advance(Duration::from_millis(50));
+        assert!(matches!(
+            limiter.take_pending("imu"),
+            Wake::Retry(wait) if wait == Duration::from_millis(150)
+        ));
+    }
+
+    #[test]
+    fn unlimited_topics_are_sent_immediately() {
+        let (limiter, _clock) = limiter(4.0);
+        for _ in 0..3 {
+            assert!(matches!(
+                limiter.admit("gps", message(b"1")),
+                Admission::Send(_)
+            ));
+        }
+        assert_eq!(limiter.dropped(), 0);
+    }
+
+    #[test]
+    fn scale_lengthens_the_window() {
+        let (limiter, clock) = limiter(4.0);
+        limiter.set_scale(0.5);
+        assert_eq!(limiter.effective_rates(), vec![("imu".to_string(), 2.0)]);
+
+        limiter.admit("imu", message(b"1"));
+        clock.advance(Duration::from_millis(300));
+        assert!(matches!(
+            limiter.admit("imu", message(b"2")),
+            Admission::Held { .. }
+        ));
+        clock.advance(Duration::from_millis(200));
+        assert!(matches!(limiter.take_pending("imu"), Wake::Ready(Some(_))));
+    }
+
+    #[test]
+    fn rates_out_of_range_are_ignored() {
+        let (limiter, _clock) = limiter(4.0);
+        for hz in [1e-20, 0.0, -1.0, f32::NAN, f32::INFINITY, 1e9] {
+            limiter.set_rate("imu", Some(hz));
+        }
+        assert_eq!(limiter.effective_rates(), vec![("imu".to_string(), 4.0)]);
+
+        let clock = Arc::new(TestClock::new(Duration::ZERO));
+        let rates = HashMap::from([("imu".to_string(), 1e-20)]);
+        assert!(RateLimiter::new(&rates, clock).effective_rates().is_empty());
+    }
+
+    #[test]
+    fn tiny_scale_does_not_overflow_the_interval() {
+        let (limiter, clock) = limiter(4.0);
+        limiter.set_scale(1e-30);
+        limiter.admit("imu", message(b"1"));
+        clock.advance(Duration::from_secs(3600));
+        assert!(matches!(
+            limiter.admit("imu", message(b"2")),
+            Admission::Held { .. }
+        ));
+    }
+}
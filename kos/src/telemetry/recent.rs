@@ -0,0 +1,60 @@
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+type Entries = Mutex<VecDeque<(Duration, Bytes)>>;
+
+/// The last few payloads of each subtopic. Each topic has its own lock, so
+/// publishers of different topics only share the map lock, and only
+/// exclusively when a topic is seen for the first time.
+pub(crate) struct RecentCache {
+    capacity: usize,
+    topics: RwLock<HashMap<String, Entries>>,
+}
+
+impl RecentCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            topics: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn push(&self, topic: &str, at: Duration, payload: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(entries) = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)
+        {
+            Self::push_bounded(entries, self.capacity, at, payload);
+            return;
+        }
+
+        let mut topics = self.topics.write().unwrap_or_else(PoisonError::into_inner);
+        let entries = topics.entry(topic.to_string()).or_default();
+        Self::push_bounded(entries, self.capacity, at, payload);
+    }
+
+    pub fn recent(&self, topic: &str, n: usize) -> Vec<(Duration, Bytes)> {
+        let topics = self.topics.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(entries) = topics.get(topic) else {
+            return Vec::new();
+        };
+        let entries = entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let skip = entries.len().saturating_sub(n);
+        entries.iter().skip(skip).cloned().collect()
+    }
+
+    fn push_bounded(entries: &Entries, capacity: usize, at: Duration, payload: Bytes) {
+        let mut entries = entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == capacity {
+            entries.pop_front();
+        }
+        entries.push_back((at, payload));
+    }
+}
@@ -0,0 +1,615 @@
+//! Capture-and-replay of telemetry for robots without a network link.
+//!
+//! A recording starts with [`MAGIC`] followed by one record per message:
+//!
+//! | bytes | content                        |
+//! |-------|--------------------------------|
+//! | 8     | unix nanos, little endian      |
+//! | 8     | monotonic nanos, little endian |
+//! | 1     | QoS (0, 1 or 2)                |
+//! | 2     | topic length, little endian    |
+//! | n     | full topic, UTF-8              |
+//! | 4     | payload length, little endian  |
+//! | n     | encoded payload                |
+//!
+//! Both times are read from `TelemetryConfig::clock`. Replay spaces messages
+//! by the monotonic one, so wall-clock jumps while recording do not turn
+//! into pauses or bursts. Recordings made before it was added start with
+//! `KOSREC\x00\x01` and lack that field; they are still read, and replayed
+//! by the unix time. Gzip compressed recordings wrap the same stream.
+
+use super::clock::{Clock, SystemClock};
+use super::error::{Result, TelemetryError};
+use super::sink::TelemetrySink;
+use super::Telemetry;
+use async_trait::async_trait;
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rumqttc::QoS;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+pub const MAGIC: &[u8; 8] = b"KOSREC\x00\x02";
+
+/// Recordings without the monotonic time.
+const MAGIC_V1: &[u8; 8] = b"KOSREC\x00\x01";
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Local start time in recording names, `{robot_id}_{start}.kosrec`, or
+/// `{robot_id}_{start}_{n}.kosrec` for the `n`th later recording started in
+/// the same second.
+pub(crate) const START_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// Sink that appends every message to a recording on disk. The file is
+/// finalized when the recorder is dropped.
+pub struct FileRecorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+    path: PathBuf,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileRecorder {
+    /// Starts a new recording in `dir`, named after the robot and the local
+    /// start time. Existing recordings are never overwritten.
+    pub fn create(dir: impl AsRef<Path>, robot_id: &str, gzip: bool) -> io::Result<Self> {
+        Self::with_clock(dir, robot_id, gzip, Arc::new(SystemClock))
+    }
+
+    /// `create` that timestamps messages with `clock`, e.g. the configured
+    /// `TelemetryConfig::clock`.
+    pub fn with_clock(
+        dir: impl AsRef<Path>,
+        robot_id: &str,
+        gzip: bool,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let timestamp = Local::now().format(START_FORMAT).to_string();
+        let extension = if gzip { "kosrec.gz" } else { "kosrec" };
+        // Names only resolve to the second, so a recording started in the
+        // same second as an earlier one gets a counter instead of
+        // truncating it.
+        let mut attempt = 0u32;
+        let (file, path) = loop {
+            let name = match attempt {
+                0 => format!("{}_{}.{}", robot_id, timestamp, extension),
+                n => format!("{}_{}_{}.{}", robot_id, timestamp, n, extension),
+            };
+            let path = dir.as_ref().join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (file, path),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let file = BufWriter::new(file);
+        let mut writer: Box<dyn Write + Send> = if gzip {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        writer.write_all(MAGIC)?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            path,
+            clock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush()
+    }
+
+    fn record(&self, topic: &str, payload: &[u8], qos: QoS) -> Result<()> {
+        let topic_len = u16::try_from(topic.len())
+            .map_err(|_| TelemetryError::InvalidTopic("topic is too long to record".to_string()))?;
+        let payload_len = u32::try_from(payload.len()).map_err(|_| {
+            TelemetryError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload is too large to record",
+            ))
+        })?;
+
+        let mut record = Vec::with_capacity(23 + topic.len() + payload.len());
+        let unix_nanos = self.clock.now_unix().as_nanos() as u64;
+        let monotonic_nanos = self.clock.now_monotonic().as_nanos() as u64;
+        record.extend_from_slice(&unix_nanos.to_le_bytes());
+        record.extend_from_slice(&monotonic_nanos.to_le_bytes());
+        record.push(qos as u8);
+        record.extend_from_slice(&topic_len.to_le_bytes());
+        record.extend_from_slice(topic.as_bytes());
+        record.extend_from_slice(&payload_len.to_le_bytes());
+        record.extend_from_slice(payload);
+
+        self.writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(&record)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for FileRecorder {
+    async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+        self.record(&topic, &payload, qos)
+    }
+
+    fn try_send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<bool> {
+        self.record(&topic, &payload, qos)?;
+        Ok(true)
+    }
+}
+
+/// A single message read back from a recording.
+#[derive(Clone, Debug)]
+pub struct RecordedMessage {
+    pub unix_nanos: u64,
+    /// `None` in recordings made before it was recorded.
+    pub monotonic_nanos: Option<u64>,
+    pub qos: QoS,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Reads the messages of a recording in order, detecting gzip compression
+/// from the file contents.
+pub struct RecordingReader {
+    reader: Box<dyn Read + Send>,
+    /// Whether records carry the monotonic time.
+    monotonic: bool,
+}
+
+impl RecordingReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut head = [0u8; 2];
+        file.read_exact(&mut head)?;
+        let file = io::Cursor::new(head).chain(file);
+
+        let mut reader: Box<dyn Read + Send> = if head == GZIP_MAGIC {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        let monotonic = match &magic {
+            MAGIC => true,
+            MAGIC_V1 => false,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a kos telemetry recording",
+                ))
+            }
+        };
+
+        Ok(Self { reader, monotonic })
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<RecordedMessage>> {
+        let mut unix_nanos = [0u8; 8];
+        match self.reader.read_exact(&mut unix_nanos) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let monotonic_nanos = if self.monotonic {
+            let mut monotonic_nanos = [0u8; 8];
+            self.reader.read_exact(&mut monotonic_nanos)?;
+            Some(u64::from_le_bytes(monotonic_nanos))
+        } else {
+            None
+        };
+
+        let mut qos = [0u8; 1];
+        self.reader.read_exact(&mut qos)?;
+        let qos = match qos[0] {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid QoS in recording",
+                ))
+            }
+        };
+
+        let mut topic_len = [0u8; 2];
+        self.reader.read_exact(&mut topic_len)?;
+        let mut topic = vec![0u8; u16::from_le_bytes(topic_len) as usize];
+        self.reader.read_exact(&mut topic)?;
+        let topic =
+            String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut payload_len = [0u8; 4];
+        self.reader.read_exact(&mut payload_len)?;
+        let mut payload = vec![0u8; u32::from_le_bytes(payload_len) as usize];
+        self.reader.read_exact(&mut payload)?;
+
+        Ok(Some(RecordedMessage {
+            unix_nanos: u64::from_le_bytes(unix_nanos),
+            monotonic_nanos,
+            qos,
+            topic,
+            payload,
+        }))
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = io::Result<RecordedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Bounds of `ReplaySpeed::Multiplier`, so stretched gaps stay within what a
+/// `Duration` holds.
+const MIN_MULTIPLIER: f64 = 0.001;
+const MAX_MULTIPLIER: f64 = 1000.0;
+
+/// How fast `replay` re-publishes a recording.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplaySpeed {
+    /// Keep the gaps between messages as they were recorded.
+    RealTime,
+    /// Play this many times faster than recorded, e.g. 0.5 for half speed.
+    /// Clamped to `0.001..=1000`; values that are not positive play
+    /// `Unlimited`.
+    Multiplier(f32),
+    /// As fast as the sink accepts messages.
+    #[default]
+    Unlimited,
+}
+
+impl ReplaySpeed {
+    /// Factor recorded gaps are divided by, or `None` to not wait at all.
+    fn scale(self) -> Option<f64> {
+        match self {
+            ReplaySpeed::RealTime => Some(1.0),
+            ReplaySpeed::Multiplier(n) if n > 0.0 && n.is_finite() => {
+                Some((n as f64).clamp(MIN_MULTIPLIER, MAX_MULTIPLIER))
+            }
+            ReplaySpeed::Multiplier(_) | ReplaySpeed::Unlimited => None,
+        }
+    }
+}
+
+/// Pauses and resumes a `replay_with` while it runs. Clones control the same
+/// replay.
+#[derive(Clone, Debug)]
+pub struct ReplayControl {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ReplayControl {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl ReplayControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Holds the next message until `resume` is called. Time spent paused is
+    /// not counted against the recorded gaps.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+}
+
+/// Re-publishes every message of the recording at `path` through
+/// `telemetry`'s sink, keeping the recorded topics. Returns the number of
+/// messages replayed.
+pub async fn replay(
+    path: impl AsRef<Path>,
+    telemetry: &Telemetry,
+    speed: ReplaySpeed,
+) -> Result<usize> {
+    replay_with(path, telemetry, speed, &ReplayControl::new()).await
+}
+
+/// `replay` that can be paused and resumed through `control`. Gaps are
+/// taken from the monotonic time each message was recorded at.
+pub async fn replay_with(
+    path: impl AsRef<Path>,
+    telemetry: &Telemetry,
+    speed: ReplaySpeed,
+    control: &ReplayControl,
+) -> Result<usize> {
+    let mut paused = control.paused.subscribe();
+    // When the first message was replayed and recorded.
+    let mut start: Option<(Instant, u64)> = None;
+    let mut replayed = 0;
+    for message in RecordingReader::open(path)? {
+        let message = message?;
+        if let Some(scale) = speed.scale() {
+            let recorded_at = message.monotonic_nanos.unwrap_or(message.unix_nanos);
+            match start {
+                None => start = Some((Instant::now(), recorded_at)),
+                Some((started, first)) => {
+                    let gap = Duration::from_nanos(recorded_at.saturating_sub(first));
+                    tokio::time::sleep_until(started + gap.div_f64(scale)).await;
+                }
+            }
+        }
+
+        if *paused.borrow_and_update() {
+            let paused_at = Instant::now();
+            // Cannot fail, `control` holds the sender.
+            let _ = paused.wait_for(|paused| !paused).await;
+            if let Some((started, _)) = &mut start {
+                *started += paused_at.elapsed();
+            }
+        }
+
+        telemetry
+            .sink
+            .send(message.topic, message.payload, message.qos)
+            .await?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::TestClock;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kos-recorder-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn timestamps_come_from_the_clock() {
+        let dir = temp_dir("clock");
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let recorder = FileRecorder::with_clock(&dir, "test_robot", false, clock.clone()).unwrap();
+        recorder
+            .try_send(
+                "robots/test_robot/imu".to_string(),
+                b"1".to_vec(),
+                QoS::AtMostOnce,
+            )
+            .unwrap();
+        clock.advance(Duration::from_millis(250));
+        recorder
+            .try_send(
+                "robots/test_robot/imu".to_string(),
+                b"2".to_vec(),
+                QoS::AtLeastOnce,
+            )
+            .unwrap();
+        let path = recorder.path().to_path_buf();
+        drop(recorder);
+
+        let messages: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].unix_nanos, 1_700_000_000_000_000_000);
+        assert_eq!(messages[1].unix_nanos, 1_700_000_000_250_000_000);
+        assert_eq!(messages[0].monotonic_nanos, Some(0));
+        assert_eq!(messages[1].monotonic_nanos, Some(250_000_000));
+        assert_eq!(messages[1].qos, QoS::AtLeastOnce);
+        assert_eq!(messages[1].payload, b"2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recordings_started_in_the_same_second_are_kept() {
+        let dir = temp_dir("same_second");
+        let first = FileRecorder::create(&dir, "test_robot", false).unwrap();
+        first
+            .try_send("imu".to_string(), b"1".to_vec(), QoS::AtMostOnce)
+            .unwrap();
+        let first_path = first.path().to_path_buf();
+        drop(first);
+
+        // Either in the same second as the first, or the one after.
+        let second = FileRecorder::create(&dir, "test_robot", false).unwrap();
+        let third = FileRecorder::create(&dir, "test_robot", false).unwrap();
+        assert_ne!(second.path(), first_path);
+        assert_ne!(third.path(), second.path());
+        assert_ne!(third.path(), first_path);
+        drop((second, third));
+
+        let messages: Vec<_> = RecordingReader::open(&first_path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, b"1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_recordings_read_back() {
+        let dir = temp_dir("gzip");
+        let recorder = FileRecorder::create(&dir, "test_robot", true).unwrap();
+        recorder
+            .try_send(
+                "robots/test_robot/gps".to_string(),
+                b"{}".to_vec(),
+                QoS::AtMostOnce,
+            )
+            .unwrap();
+        let path = recorder.path().to_path_buf();
+        drop(recorder);
+
+        assert!(path.to_string_lossy().ends_with(".kosrec.gz"));
+        let messages: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "robots/test_robot/gps");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_recordings_without_monotonic_time() {
+        let dir = temp_dir("v1");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test_robot_20240101_000000.kosrec");
+        let mut bytes = MAGIC_V1.to_vec();
+        bytes.extend_from_slice(&42u64.to_le_bytes());
+        bytes.push(1);
+        bytes.extend_from_slice(&3u16.to_le_bytes());
+        bytes.extend_from_slice(b"imu");
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(b"{}");
+        std::fs::write(&path, bytes).unwrap();
+
+        let messages: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].unix_nanos, 42);
+        assert_eq!(messages[0].monotonic_nanos, None);
+        assert_eq!(messages[0].qos, QoS::AtLeastOnce);
+        assert_eq!(messages[0].topic, "imu");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records the tokio time each replayed message arrived at.
+    #[derive(Default)]
+    struct TimingSink {
+        arrivals: Mutex<Vec<(String, Instant)>>,
+    }
+
+    impl TimingSink {
+        /// Time between the first replayed message and each later one.
+        fn gaps(&self) -> Vec<Duration> {
+            let arrivals = self.arrivals.lock().unwrap_or_else(PoisonError::into_inner);
+            let replayed: Vec<_> = arrivals
+                .iter()
+                .filter(|(topic, _)| topic.ends_with("/imu"))
+                .map(|(_, at)| *at)
+                .collect();
+            replayed[1..].iter().map(|at| *at - replayed[0]).collect()
+        }
+    }
+
+    #[async_trait]
+    impl TelemetrySink for TimingSink {
+        async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()> {
+            self.try_send(topic, payload, qos).map(|_| ())
+        }
+
+        fn try_send(&self, topic: String, _payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+            self.arrivals
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((topic, Instant::now()));
+            Ok(true)
+        }
+    }
+
+    /// A recording of three messages 2 and 3 seconds apart, with the wall
+    /// clock stepped back an hour in between.
+    fn timed_recording(name: &str) -> (PathBuf, PathBuf) {
+        let dir = temp_dir(name);
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        let recorder = FileRecorder::with_clock(&dir, "test_robot", false, clock.clone()).unwrap();
+        let record = |payload: &[u8]| {
+            recorder
+                .try_send(
+                    "robots/test_robot/imu".to_string(),
+                    payload.to_vec(),
+                    QoS::AtMostOnce,
+                )
+                .unwrap();
+        };
+        record(b"1");
+        clock.advance(Duration::from_secs(2));
+        clock.set_unix(Duration::from_secs(1_700_000_000 - 3600));
+        record(b"2");
+        clock.advance(Duration::from_secs(3));
+        record(b"3");
+        let path = recorder.path().to_path_buf();
+        (dir, path)
+    }
+
+    async fn replay_gaps(path: &Path, speed: ReplaySpeed) -> Vec<Duration> {
+        let sink = Arc::new(TimingSink::default());
+        let config = crate::telemetry::TelemetryConfig::new("test_robot", "localhost", 1883);
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+        assert_eq!(replay(path, &telemetry, speed).await.unwrap(), 3);
+        sink.gaps()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_keeps_the_monotonic_gaps() {
+        let (dir, path) = timed_recording("timing");
+        let s = Duration::from_secs;
+
+        assert_eq!(
+            replay_gaps(&path, ReplaySpeed::RealTime).await,
+            [s(2), s(5)]
+        );
+        assert_eq!(
+            replay_gaps(&path, ReplaySpeed::Multiplier(2.0)).await,
+            [Duration::from_millis(1000), Duration::from_millis(2500)]
+        );
+        assert_eq!(
+            replay_gaps(&path, ReplaySpeed::Unlimited).await,
+            [Duration::ZERO, Duration::ZERO]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_clamps_extreme_multipliers() {
+        let (dir, path) = timed_recording("clamp");
+        let s = Duration::from_secs;
+
+        assert_eq!(
+            replay_gaps(&path, ReplaySpeed::Multiplier(1e-30)).await,
+            [s(2000), s(5000)]
+        );
+        assert_eq!(
+            replay_gaps(&path, ReplaySpeed::Multiplier(1e30)).await,
+            [Duration::from_millis(2), Duration::from_millis(5)]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
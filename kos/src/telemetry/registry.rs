@@ -0,0 +1,52 @@
+use super::sink::{OutgoingMessage, TelemetrySink};
+use futures::future::join_all;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Sinks added with `Telemetry::add_sink`, on top of the main one. Each gets
+/// its own copy of every message, and their failures are only logged, so a
+/// broken backend cannot fail a publish or hold up the others.
+#[derive(Default)]
+pub(crate) struct SinkRegistry {
+    sinks: RwLock<Vec<Arc<dyn TelemetrySink>>>,
+}
+
+impl SinkRegistry {
+    pub fn add(&self, sink: Arc<dyn TelemetrySink>) {
+        self.sinks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sink);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    fn sinks(&self) -> Vec<Arc<dyn TelemetrySink>> {
+        self.sinks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub async fn send(&self, message: OutgoingMessage) {
+        let sinks = self.sinks();
+        let results = join_all(sinks.iter().map(|sink| sink.send_message(message.clone()))).await;
+        for e in results.into_iter().filter_map(Result::err) {
+            tracing::warn!("Failed to send telemetry to a registered sink: {}", e);
+        }
+    }
+
+    pub fn try_send(&self, message: OutgoingMessage) {
+        for sink in self.sinks() {
+            match sink.try_send_message(message.clone()) {
+                Ok(true) => {}
+                Ok(false) => tracing::trace!("Registered sink is busy, dropping message"),
+                Err(e) => tracing::warn!("Failed to send telemetry to a registered sink: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Settings pushed by operators to `robots/{robot_id}/config` as a
+//! `ConfigUpdate`, see `TelemetryConfig::remote_config`. Every message is
+//! answered with a `ConfigAck` on `config/ack`.
+
+use super::config::TelemetryFormat;
+use super::error::{Result, TelemetryError};
+use super::payloads::{ConfigAck, ConfigUpdate, CONFIG_ACK_TOPIC, CONFIG_TOPIC};
+use super::rate_limit;
+use super::sink::OutgoingMessage;
+use super::topics;
+use super::Telemetry;
+use bytes::Bytes;
+use rumqttc::QoS;
+use tokio::sync::mpsc;
+
+/// Updates received but not applied yet.
+const PENDING_UPDATES: usize = 16;
+
+pub(crate) async fn run(telemetry: Telemetry) {
+    let (tx, mut rx) = mpsc::channel::<Bytes>(PENDING_UPDATES);
+    let subscribed = telemetry
+        .subscribe(CONFIG_TOPIC, move |payload| {
+            if tx.try_send(payload).is_err() {
+                tracing::warn!("Dropping remote config update, too many pending");
+            }
+        })
+        .await;
+    if let Err(e) = subscribed {
+        tracing::warn!("Failed to subscribe to remote config: {}", e);
+        return;
+    }
+
+    while let Some(payload) = rx.recv().await {
+        let (id, result) = match serde_json::from_slice::<ConfigUpdate>(&payload) {
+            Ok(update) => (update.id, apply(&telemetry, &update)),
+            Err(e) => (None, Err(TelemetryError::Deserialize(e))),
+        };
+        match &result {
+            Ok(()) => tracing::info!("Applied remote config update {:?}", id),
+            Err(e) => tracing::warn!("Rejected remote config update {:?}: {}", id, e),
+        }
+        let ack = ConfigAck {
+            id,
+            accepted: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        };
+        if let Err(e) = send_ack(&telemetry, &ack).await {
+            tracing::warn!("Failed to publish remote config ack: {}", e);
+        }
+    }
+}
+
+/// Applies `update` if all of it is valid, and nothing otherwise.
+pub(crate) fn apply(telemetry: &Telemetry, update: &ConfigUpdate) -> Result<()> {
+    for (topic, hz) in &update.rate_limits {
+        topics::validate(topic)?;
+        if let Some(hz) = hz {
+            rate_limit::validate_rate(topic, *hz)?;
+        }
+    }
+    for pattern in update.mute.iter().chain(&update.unmute) {
+        topics::validate(pattern)?;
+    }
+    let format = match update.format.as_deref() {
+        None => None,
+        Some("json") => Some(TelemetryFormat::Json),
+        Some("line_protocol") => Some(TelemetryFormat::LineProtocol),
+        Some(other) => {
+            return Err(TelemetryError::InvalidConfig(format!(
+                "unknown format {:?}",
+                other
+            )))
+        }
+    };
+
+    for (topic, hz) in &update.rate_limits {
+        telemetry.set_rate_limit(topic, *hz);
+    }
+    for pattern in &update.mute {
+        telemetry.mute(pattern);
+    }
+    for pattern in &update.unmute {
+        telemetry.unmute(pattern);
+    }
+    if let Some(format) = format {
+        telemetry.set_format(format);
+    }
+    if let Some(enabled) = update.enabled {
+        Telemetry::set_enabled(enabled);
+    }
+    Ok(())
+}
+
+/// Sends `ack` straight to the sink, so it goes out even when the update
+/// disabled or muted telemetry.
+async fn send_ack(telemetry: &Telemetry, ack: &ConfigAck) -> Result<()> {
+    let message = OutgoingMessage::new(
+        telemetry.robot_topic(CONFIG_ACK_TOPIC),
+        serde_json::to_vec(ack)?,
+        QoS::AtLeastOnce,
+    );
+    telemetry.sink.send_message(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::config::TelemetryConfig;
+    use crate::telemetry::sink::MemorySink;
+    use std::sync::Arc;
+
+    fn telemetry() -> Telemetry {
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.rate_limits.insert("imu".to_string(), 100.0);
+        Telemetry::with_sink(config, Arc::new(MemorySink::new()))
+    }
+
+    fn update(json: &str) -> ConfigUpdate {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn rejects_rates_out_of_range() {
+        let telemetry = telemetry();
+        for json in [
+            r#"{"rate_limits":{"imu":1e-20}}"#,
+            r#"{"rate_limits":{"imu":0}}"#,
+            r#"{"rate_limits":{"imu":-5}}"#,
+            r#"{"rate_limits":{"imu":1e9}}"#,
+        ] {
+            assert!(
+                matches!(
+                    apply(&telemetry, &update(json)),
+                    Err(TelemetryError::InvalidConfig(_))
+                ),
+                "{}",
+                json
+            );
+        }
+        assert_eq!(
+            telemetry.effective_rates(),
+            vec![("imu".to_string(), 100.0)]
+        );
+    }
+
+    #[test]
+    fn applies_nothing_unless_all_of_it_is_valid() {
+        let telemetry = telemetry();
+        let invalid = update(r#"{"rate_limits":{"imu":10,"gps":1e-20},"mute":["joints"]}"#);
+        assert!(apply(&telemetry, &invalid).is_err());
+        assert_eq!(
+            telemetry.effective_rates(),
+            vec![("imu".to_string(), 100.0)]
+        );
+        assert!(!telemetry.unmute("joints"));
+
+        apply(&telemetry, &update(r#"{"rate_limits":{"imu":10}}"#)).unwrap();
+        assert_eq!(telemetry.effective_rates(), vec![("imu".to_string(), 10.0)]);
+    }
+}
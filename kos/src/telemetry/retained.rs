@@ -0,0 +1,25 @@
+use super::connection::ConnectionState;
+use super::Telemetry;
+
+/// Re-publishes retained state after every reconnect, in case the broker
+/// lost it. The event loop re-sends the online status on its own.
+pub(crate) async fn run(telemetry: Telemetry) {
+    let mut state = telemetry.connection.subscribe();
+    let mut connected_before = *state.borrow_and_update() == ConnectionState::Connected;
+
+    while state.changed().await.is_ok() {
+        if *state.borrow_and_update() != ConnectionState::Connected {
+            continue;
+        }
+        // Everything was just published for the first time.
+        if !std::mem::replace(&mut connected_before, true) {
+            continue;
+        }
+        if telemetry.connection.is_shutting_down() {
+            break;
+        }
+        if let Err(e) = telemetry.republish_after_reconnect().await {
+            tracing::warn!("Failed to re-publish retained telemetry: {}", e);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use super::config::TelemetryConfig;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// The robot id and the topics derived from it. Replaced as a whole by
+/// `Telemetry::set_robot_id`, so a publish never mixes two ids.
+pub(crate) struct Routing {
+    pub robot_id: String,
+    /// `{topic_prefix}/{robot_id}/`, built once for the publish path.
+    pub topic_root: Arc<str>,
+    /// Full status topic and payload, if the last will is enabled.
+    pub online_status: Option<(String, Vec<u8>)>,
+    pub offline_status: Option<(String, Vec<u8>)>,
+}
+
+impl Routing {
+    pub fn new(config: &TelemetryConfig, robot_id: String) -> Self {
+        let topic_root = format!("{}/{}/", config.topic_prefix, robot_id);
+        Self {
+            online_status: config.status_message_under(&topic_root, true),
+            offline_status: config.status_message_under(&topic_root, false),
+            topic_root: topic_root.into(),
+            robot_id,
+        }
+    }
+
+    /// `{topic_prefix}/{robot_id}/{subtopic}`.
+    pub fn topic(&self, subtopic: &str) -> String {
+        format!("{}{}", self.topic_root, subtopic)
+    }
+}
+
+/// The current `Routing`, shared by a `Telemetry`, its connections and its
+/// field masks.
+pub(crate) struct RoutingCell(RwLock<Arc<Routing>>);
+
+impl RoutingCell {
+    pub fn new(routing: Routing) -> Self {
+        Self(RwLock::new(Arc::new(routing)))
+    }
+
+    pub fn get(&self) -> Arc<Routing> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Installs `routing` and returns the previous one.
+    pub fn replace(&self, routing: Arc<Routing>) -> Arc<Routing> {
+        std::mem::replace(
+            &mut *self.0.write().unwrap_or_else(PoisonError::into_inner),
+            routing,
+        )
+    }
+}
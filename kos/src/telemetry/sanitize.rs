@@ -0,0 +1,54 @@
+use super::line_protocol::{FieldValue, IntoLineProtocol};
+use serde_json::Value;
+
+fn is_non_finite(value: &Option<FieldValue>) -> bool {
+    matches!(value, Some(FieldValue::Float(v)) if !v.is_finite())
+}
+
+/// Number of NaN or infinite float fields across `points`.
+pub(crate) fn non_finite_fields<P: IntoLineProtocol>(points: &[P]) -> usize {
+    points
+        .iter()
+        .map(|point| {
+            point
+                .fields()
+                .iter()
+                .filter(|v| is_non_finite(&v.1))
+                .count()
+        })
+        .sum()
+}
+
+/// Line protocol view of a point with its NaN and infinite fields omitted.
+pub(crate) struct Finite<'a, P>(pub &'a P);
+
+impl<P: IntoLineProtocol> IntoLineProtocol for Finite<'_, P> {
+    fn measurement(&self) -> &'static str {
+        self.0.measurement()
+    }
+
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        self.0.tags()
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Option<FieldValue>)> {
+        self.0
+            .fields()
+            .into_iter()
+            .map(|(key, value)| (key, if is_non_finite(&value) { None } else { value }))
+            .collect()
+    }
+}
+
+/// Removes the `null` members of every object in `value`. Nulls in arrays
+/// are kept, as they hold a position.
+pub(crate) fn omit_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, member| !member.is_null());
+            object.values_mut().for_each(omit_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(omit_nulls),
+        _ => {}
+    }
+}
@@ -0,0 +1,112 @@
+use super::config::Priority;
+use super::payloads::{SchemaAnnouncement, TopicSchema, SCHEMA_TOPIC};
+use super::sink::OutgoingMessage;
+use super::Telemetry;
+use rumqttc::QoS;
+use std::collections::BTreeMap;
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+
+/// Subtopics published so far and the payload type and schema version of
+/// each.
+#[derive(Default)]
+pub(crate) struct Schema {
+    topics: RwLock<BTreeMap<String, (&'static str, u16)>>,
+    changed: Notify,
+}
+
+impl Schema {
+    /// Records that `topic` carries `type_name` at `schema_version`, waking
+    /// the announcement task if that is new.
+    pub fn record(&self, topic: &str, type_name: &'static str, schema_version: u16) {
+        if self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)
+            == Some(&(type_name, schema_version))
+        {
+            return;
+        }
+
+        self.topics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(topic.to_string(), (type_name, schema_version));
+        self.changed.notify_one();
+    }
+
+    /// Wakes the announcement task without a change, to re-publish.
+    pub fn request_announcement(&self) {
+        self.changed.notify_one();
+    }
+
+    pub fn announcement(&self) -> SchemaAnnouncement {
+        let topics = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(topic, (type_name, schema_version))| TopicSchema {
+                topic: topic.clone(),
+                type_name: type_name.to_string(),
+                schema_version: *schema_version,
+            })
+            .collect();
+        SchemaAnnouncement { topics }
+    }
+}
+
+/// Publishes the retained schema on startup, whenever a topic is first
+/// published to, and every `interval` if set. A single task does all of it
+/// so announcements cannot overtake each other.
+pub(crate) async fn run(telemetry: Telemetry, interval: Option<Duration>) {
+    let mut ticker = interval.map(|interval| {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+
+    announce(&telemetry).await;
+    if let Some(ticker) = &mut ticker {
+        // The first tick completes immediately.
+        ticker.tick().await;
+    }
+
+    loop {
+        match &mut ticker {
+            Some(ticker) => tokio::select! {
+                _ = ticker.tick() => {}
+                _ = telemetry.schema.changed.notified() => {}
+            },
+            None => telemetry.schema.changed.notified().await,
+        }
+        if telemetry.connection.is_shutting_down() {
+            break;
+        }
+        announce(&telemetry).await;
+    }
+}
+
+async fn announce(telemetry: &Telemetry) {
+    let payload = match serde_json::to_vec(&telemetry.schema.announcement()) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::warn!("Failed to serialize schema announcement: {}", e);
+            return;
+        }
+    };
+
+    let mut message = OutgoingMessage::new(
+        telemetry.robot_topic(SCHEMA_TOPIC),
+        payload,
+        QoS::AtLeastOnce,
+    );
+    message.priority = Priority::High;
+    message.retain = true;
+    if let Err(e) = telemetry.sink.send_message(message).await {
+        tracing::warn!("Failed to publish schema announcement: {}", e);
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+
+/// Per-topic sequence numbers. Each `Telemetry` owns one, so topics of
+/// different robots never share a sequence.
+#[derive(Default)]
+pub(crate) struct Sequences {
+    topics: RwLock<HashMap<String, AtomicU64>>,
+}
+
+impl Sequences {
+    /// Returns the next sequence number for `topic`, starting at 0.
+    pub fn next(&self, topic: &str) -> u64 {
+        if let Some(sequence) = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)
+        {
+            return sequence.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.topics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(topic.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_topic_separately() {
+        let sequences = Sequences::default();
+        assert_eq!(sequences.next("joints"), 0);
+        assert_eq!(sequences.next("joints"), 1);
+        assert_eq!(sequences.next("imu"), 0);
+        assert_eq!(sequences.next("joints"), 2);
+    }
+}
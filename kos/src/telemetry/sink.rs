@@ -0,0 +1,212 @@
+use super::compression;
+use super::config::Priority;
+use super::error::Result;
+use async_trait::async_trait;
+use rumqttc::QoS;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Destination for encoded telemetry. `Telemetry` builds the full topic and
+/// payload and hands them to its sink; the default sink publishes to MQTT.
+#[async_trait]
+pub trait TelemetrySink: Send + Sync {
+    async fn send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<()>;
+
+    /// Sends without waiting. Returns `Ok(false)` if the message was dropped
+    /// because the sink is busy.
+    fn try_send(&self, topic: String, payload: Vec<u8>, qos: QoS) -> Result<bool>;
+
+    /// Whether the sink can deliver MQTT v5 user properties. Only then does
+    /// `metadata_as_user_properties` move the metadata out of the body.
+    fn supports_user_properties(&self) -> bool {
+        false
+    }
+
+    /// Like `send`, with the priority and user properties of the message.
+    /// Sinks that ignore them only need to implement `send`.
+    async fn send_message(&self, message: OutgoingMessage) -> Result<()> {
+        self.send(message.topic, message.payload, message.qos).await
+    }
+
+    /// Like `try_send`, with the priority and user properties of the
+    /// message.
+    fn try_send_message(&self, message: OutgoingMessage) -> Result<bool> {
+        self.try_send(message.topic, message.payload, message.qos)
+    }
+}
+
+/// A fully encoded message handed to a `TelemetrySink`.
+#[derive(Clone, Debug)]
+pub struct OutgoingMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub priority: Priority,
+    /// MQTT v5 user properties, empty unless the metadata travels outside
+    /// the body.
+    pub user_properties: Vec<(String, String)>,
+    /// Whether the broker keeps this as the retained message of the topic.
+    pub retain: bool,
+    /// Monotonic time, by `TelemetryConfig::clock`, after which the message
+    /// is dropped instead of being sent from the offline buffer.
+    pub expires_at: Option<Duration>,
+}
+
+impl OutgoingMessage {
+    pub fn new(topic: String, payload: Vec<u8>, qos: QoS) -> Self {
+        Self {
+            topic,
+            payload,
+            qos,
+            priority: Priority::default(),
+            user_properties: Vec::new(),
+            retain: false,
+            expires_at: None,
+        }
+    }
+}
+
+/// Sink that records every message in memory, for tests that need to assert
+/// on published telemetry without a broker.
+#[derive(Default)]
+pub struct MemorySink {
+    messages: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All `(topic, payload)` pairs received so far, in order.
+    pub fn messages(&self) -> Vec<(String, Vec<u8>)> {
+        self.lock().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(String, Vec<u8>)>> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for MemorySink {
+    async fn send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<()> {
+        self.lock().push((topic, payload));
+        Ok(())
+    }
+
+    fn try_send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+        self.lock().push((topic, payload));
+        Ok(true)
+    }
+}
+
+/// Sink that logs every message at info level instead of publishing it, used
+/// by `TelemetryMode::DryRun`. JSON and MessagePack payloads are
+/// pretty-printed.
+#[derive(Default)]
+pub struct DryRunSink;
+
+impl DryRunSink {
+    fn log(&self, topic: &str, payload: &[u8]) {
+        let payload = match compression::decompress(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::info!("Dry run: {} <undecodable payload: {}>", topic, e);
+                return;
+            }
+        };
+        let value = if topic.split('/').any(|level| level == "msgpack") {
+            rmp_serde::from_slice::<serde_json::Value>(&payload).ok()
+        } else {
+            serde_json::from_slice::<serde_json::Value>(&payload).ok()
+        };
+        match value.and_then(|value| serde_json::to_string_pretty(&value).ok()) {
+            Some(pretty) => tracing::info!("Dry run: {}\n{}", topic, pretty),
+            None => tracing::info!("Dry run: {}\n{}", topic, String::from_utf8_lossy(&payload)),
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for DryRunSink {
+    async fn send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<()> {
+        self.log(&topic, &payload);
+        Ok(())
+    }
+
+    fn try_send(&self, topic: String, payload: Vec<u8>, _qos: QoS) -> Result<bool> {
+        self.log(&topic, &payload);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::test_broker::TestBroker;
+    use crate::telemetry::{Telemetry, TelemetryMode};
+
+    #[tokio::test]
+    async fn memory_sink_records_in_order() {
+        let sink = MemorySink::new();
+        sink.send("a".to_string(), b"1".to_vec(), QoS::AtMostOnce)
+            .await
+            .unwrap();
+        assert!(sink
+            .try_send("b".to_string(), b"2".to_vec(), QoS::AtLeastOnce)
+            .unwrap());
+        assert_eq!(
+            sink.messages(),
+            [
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec())
+            ]
+        );
+        sink.clear();
+        assert!(sink.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_accepts_any_payload() {
+        let sink = DryRunSink;
+        sink.send(
+            "robots/r/joints".to_string(),
+            b"{}".to_vec(),
+            QoS::AtMostOnce,
+        )
+        .await
+        .unwrap();
+        assert!(sink
+            .try_send(
+                "robots/r/joints/msgpack".to_string(),
+                vec![0xc1],
+                QoS::AtMostOnce
+            )
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn dry_run_never_connects() {
+        let broker = TestBroker::start().await;
+        let mut config = broker.config("test_robot");
+        config.mode = TelemetryMode::DryRun;
+        config.heartbeat_interval = Some(Duration::from_millis(10));
+        let telemetry = Telemetry::new(config).unwrap();
+
+        telemetry.publish("joints", &1.0).await.unwrap();
+        assert!(telemetry.try_publish("joints", &2.0).unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        telemetry
+            .shutdown(Duration::from_millis(100))
+            .await
+            .unwrap();
+
+        assert_eq!(broker.connects(), 0);
+        assert!(broker.published().is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use super::compression;
+use super::config::SerializationFormat;
+use super::error::{Result, TelemetryError};
+use super::TelemetryPayload;
+use bytes::Bytes;
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc;
+
+/// Messages received on a subscribed subtopic, decoded into
+/// `TelemetryPayload<T>`. Created with `Telemetry::stream`.
+pub struct TelemetryStream<T> {
+    rx: mpsc::Receiver<Bytes>,
+    serialization: SerializationFormat,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TelemetryStream<T> {
+    pub(crate) fn new(rx: mpsc::Receiver<Bytes>, serialization: SerializationFormat) -> Self {
+        Self {
+            rx,
+            serialization,
+            _payload: PhantomData,
+        }
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<TelemetryPayload<T>> {
+        decode(payload, self.serialization)
+    }
+}
+
+/// Decompresses `payload` if needed and decodes the envelope.
+pub(crate) fn decode<T: DeserializeOwned>(
+    payload: &[u8],
+    serialization: SerializationFormat,
+) -> Result<TelemetryPayload<T>> {
+    let payload = compression::decompress(payload)?;
+    let payload = payload.as_ref();
+    match serialization {
+        SerializationFormat::Json => {
+            serde_json::from_slice(payload).map_err(TelemetryError::Deserialize)
+        }
+        SerializationFormat::MessagePack => {
+            rmp_serde::from_slice(payload).map_err(TelemetryError::DeserializeMessagePack)
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Stream for TelemetryStream<T> {
+    type Item = Result<TelemetryPayload<T>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let payload = ready!(self.rx.poll_recv(cx));
+        Poll::Ready(payload.map(|payload| self.decode(&payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    fn envelope(sequence: u64) -> serde_json::Value {
+        json!({
+            "sequence": sequence,
+            "frame_number": 7,
+            "video_timestamp": 0,
+            "inference_step": 0,
+            "captured_at_nanos": 0,
+            "data": { "position": 1.5 },
+        })
+    }
+
+    #[tokio::test]
+    async fn yields_decoded_payloads_in_order() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut stream = TelemetryStream::<serde_json::Value>::new(rx, SerializationFormat::Json);
+        for sequence in 0..2 {
+            let payload = serde_json::to_vec(&envelope(sequence)).unwrap();
+            tx.send(Bytes::from(payload)).await.unwrap();
+        }
+        drop(tx);
+
+        for sequence in 0..2 {
+            let payload = stream.next().await.unwrap().unwrap();
+            assert_eq!(payload.sequence, sequence);
+            assert_eq!(payload.frame_number, 7);
+            assert_eq!(payload.data, json!({ "position": 1.5 }));
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_undecodable_payloads_and_continues() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut stream = TelemetryStream::<serde_json::Value>::new(rx, SerializationFormat::Json);
+        tx.send(Bytes::from_static(b"not json")).await.unwrap();
+        let payload = serde_json::to_vec(&envelope(1)).unwrap();
+        tx.send(Bytes::from(payload)).await.unwrap();
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(TelemetryError::Deserialize(_)))
+        ));
+        assert_eq!(stream.next().await.unwrap().unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn decodes_message_pack() {
+        let payload = rmp_serde::to_vec_named(&envelope(3)).unwrap();
+        let decoded: TelemetryPayload<serde_json::Value> =
+            decode(&payload, SerializationFormat::MessagePack).unwrap();
+        assert_eq!(decoded.sequence, 3);
+    }
+}
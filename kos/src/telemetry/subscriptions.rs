@@ -0,0 +1,60 @@
+use super::topics;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+type Handler = Box<dyn Fn(Bytes) + Send>;
+
+/// Handlers for incoming publishes, keyed by full topic filter. Shared
+/// between `Telemetry::subscribe` and the event loop.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    handlers: Mutex<HashMap<String, Handler>>,
+}
+
+impl Subscriptions {
+    /// Registers `handler` for `filter`, replacing any previous one.
+    pub fn insert(&self, filter: String, handler: Handler) {
+        self.lock().insert(filter, handler);
+    }
+
+    /// Moves the handlers of the filters under `old_root` to the same filters
+    /// under `new_root`. Returns the moved filters.
+    pub fn move_root(&self, old_root: &str, new_root: &str) -> Vec<String> {
+        let mut handlers = self.lock();
+        let old: Vec<String> = handlers
+            .keys()
+            .filter(|filter| filter.starts_with(old_root))
+            .cloned()
+            .collect();
+        old.into_iter()
+            .filter_map(|filter| {
+                let handler = handlers.remove(&filter)?;
+                let moved = format!("{}{}", new_root, &filter[old_root.len()..]);
+                handlers.insert(moved.clone(), handler);
+                Some(moved)
+            })
+            .collect()
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    /// Calls every handler whose filter matches `topic`. Returns false if
+    /// there is none.
+    pub fn dispatch(&self, topic: &str, payload: Bytes) -> bool {
+        let mut handled = false;
+        for (filter, handler) in self.lock().iter() {
+            if topics::matches(filter, topic) {
+                handler(payload.clone());
+                handled = true;
+            }
+        }
+        handled
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Handler>> {
+        self.handlers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
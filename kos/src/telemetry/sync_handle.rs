@@ -0,0 +1,116 @@
+use super::error::Result;
+use super::{Counters, Telemetry};
+use serde::Serialize;
+
+/// Synchronous view of a `Telemetry` instance for threads that run outside
+/// the async executor, such as an OS-priority control loop. Every method
+/// returns immediately; publishes go through `Telemetry::try_publish`.
+///
+/// The handle shares its MQTT client and frame/video/inference counters with
+/// the `Telemetry` it was created from.
+#[derive(Clone)]
+pub struct SyncTelemetry {
+    telemetry: Telemetry,
+}
+
+impl SyncTelemetry {
+    pub(crate) fn new(telemetry: Telemetry) -> Self {
+        Self { telemetry }
+    }
+
+    /// Returns `Ok(false)` if the message was dropped, see
+    /// `Telemetry::try_publish`.
+    pub fn publish<T: Serialize>(&self, topic: &str, payload: &T) -> Result<bool> {
+        self.telemetry.try_publish(topic, payload)
+    }
+
+    pub fn update_frame_number(&self, new_frame_number: u64) {
+        self.telemetry.update_frame_number(new_frame_number);
+    }
+
+    pub fn increment_frame_number(&self) {
+        self.telemetry.increment_frame_number();
+    }
+
+    pub fn get_frame_number(&self) -> u64 {
+        self.telemetry.get_frame_number()
+    }
+
+    pub fn update_video_timestamp(&self, new_video_timestamp: u64) {
+        self.telemetry.update_video_timestamp(new_video_timestamp);
+    }
+
+    pub fn get_video_timestamp(&self) -> u64 {
+        self.telemetry.get_video_timestamp()
+    }
+
+    pub fn update_inference_step(&self, new_inference_step: u64) {
+        self.telemetry.update_inference_step(new_inference_step);
+    }
+
+    pub fn increment_inference_step(&self) {
+        self.telemetry.increment_inference_step();
+    }
+
+    pub fn get_inference_step(&self) -> u64 {
+        self.telemetry.get_inference_step()
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.telemetry.counters()
+    }
+
+    pub fn start_episode(&self) -> u64 {
+        self.telemetry.start_episode()
+    }
+
+    pub fn get_episode_id(&self) -> u64 {
+        self.telemetry.get_episode_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry::test_broker::TestBroker;
+    use crate::telemetry::Telemetry;
+    use serde_json::json;
+    use std::thread;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publishes_from_a_thread_without_a_runtime() {
+        let broker = TestBroker::start().await;
+        let mut config = broker.config("test_robot");
+        config.rate_limits.insert("joints".to_string(), 4.0);
+        let telemetry = Telemetry::new(config).unwrap();
+        assert!(telemetry.wait_connected(Duration::from_secs(5)).await);
+
+        let handle = telemetry.sync_handle();
+        thread::spawn(move || {
+            assert!(tokio::runtime::Handle::try_current().is_err());
+            handle.update_frame_number(7);
+            assert!(handle.publish("imu", &json!({ "ax": 0.1 })).unwrap());
+            // The second sample is held and sent by the runtime the
+            // `Telemetry` was created on.
+            assert!(handle.publish("joints", &json!({ "q": [0.0] })).unwrap());
+            assert!(handle.publish("joints", &json!({ "q": [1.0] })).unwrap());
+        })
+        .join()
+        .unwrap();
+
+        let published = broker.wait_for_published(3).await;
+        let topics: Vec<_> = published.iter().map(|p| p.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "robots/test_robot/imu",
+                "robots/test_robot/joints",
+                "robots/test_robot/joints"
+            ]
+        );
+        let last: serde_json::Value = serde_json::from_slice(&published[2].payload).unwrap();
+        assert_eq!(last["data"], json!({ "q": [1.0] }));
+        assert_eq!(last["frame_number"], 7);
+        telemetry.shutdown(Duration::from_secs(1)).await.unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Minimal MQTT 3.1.1 broker for tests. It accepts any client, records every
+//! PUBLISH and acknowledges QoS 1 and 2, without routing messages to
+//! subscribers.
+
+// Not every test uses every accessor.
+#![allow(dead_code)]
+
+use super::config::{ReconnectBackoff, TelemetryConfig};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Published {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+#[derive(Default)]
+struct State {
+    published: Mutex<Vec<Published>>,
+    changed: Notify,
+    /// Whether connections are served, or closed as soon as they are
+    /// accepted.
+    rejecting: AtomicBool,
+    /// Whether QoS 1 and 2 publishes go unacknowledged.
+    withholding_acks: AtomicBool,
+    connects: AtomicUsize,
+    /// Connections that ended, with or without a DISCONNECT.
+    closed: AtomicUsize,
+}
+
+pub(crate) struct TestBroker {
+    port: u16,
+    state: Arc<State>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl TestBroker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(State::default());
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    if state.rejecting.load(Ordering::SeqCst) {
+                        drop(stream);
+                        continue;
+                    }
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let _ = serve(stream, &state).await;
+                        state.closed.fetch_add(1, Ordering::SeqCst);
+                        state.changed.notify_waiters();
+                    });
+                }
+            }
+        });
+        Self { port, state, task }
+    }
+
+    /// A config for robot `robot_id` on this broker that reconnects quickly.
+    pub fn config(&self, robot_id: &str) -> TelemetryConfig {
+        let mut config = TelemetryConfig::new(robot_id, "127.0.0.1", self.port);
+        config.reconnect_backoff = ReconnectBackoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        config
+    }
+
+    pub fn set_rejecting(&self, rejecting: bool) {
+        self.state.rejecting.store(rejecting, Ordering::SeqCst);
+    }
+
+    pub fn set_withholding_acks(&self, withholding: bool) {
+        self.state
+            .withholding_acks
+            .store(withholding, Ordering::SeqCst);
+    }
+
+    pub fn connects(&self) -> usize {
+        self.state.connects.load(Ordering::SeqCst)
+    }
+
+    pub fn closed(&self) -> usize {
+        self.state.closed.load(Ordering::SeqCst)
+    }
+
+    /// Everything published so far, except the retained robot status and
+    /// schema announcements.
+    pub fn published(&self) -> Vec<Published> {
+        self.state
+            .published
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|published| {
+                !published.topic.ends_with("/status") && !published.topic.ends_with("/schema")
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Waits up to a few seconds for `done` to hold.
+    pub async fn wait_until(&self, mut done: impl FnMut(&Self) -> bool) {
+        let wait = async {
+            loop {
+                let changed = self.state.changed.notified();
+                tokio::pin!(changed);
+                changed.as_mut().enable();
+                if done(self) {
+                    return;
+                }
+                changed.await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("timed out waiting for the test broker");
+    }
+
+    /// Waits for at least `n` publishes and returns them.
+    pub async fn wait_for_published(&self, n: usize) -> Vec<Published> {
+        self.wait_until(|broker| broker.published().len() >= n)
+            .await;
+        self.published()
+    }
+}
+
+impl Drop for TestBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: TcpStream, state: &State) -> std::io::Result<()> {
+    loop {
+        let header = stream.read_u8().await?;
+        let mut len = 0usize;
+        for shift in (0..4).map(|i| 7 * i) {
+            let byte = stream.read_u8().await?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+
+        match header >> 4 {
+            // CONNECT
+            1 => {
+                state.connects.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await?;
+                state.changed.notify_waiters();
+            }
+            // PUBLISH
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).into_owned();
+                let mut offset = 2 + topic_len;
+                let pkid = (qos > 0).then(|| [body[offset], body[offset + 1]]);
+                if pkid.is_some() {
+                    offset += 2;
+                }
+                state
+                    .published
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(Published {
+                        topic,
+                        payload: body[offset..].to_vec(),
+                        qos,
+                        retain: header & 0x01 != 0,
+                    });
+                state.changed.notify_waiters();
+
+                if let Some([msb, lsb]) = pkid {
+                    if !state.withholding_acks.load(Ordering::SeqCst) {
+                        // PUBACK for QoS 1, PUBREC for QoS 2.
+                        let ack = if qos == 1 { 0x40 } else { 0x50 };
+                        stream.write_all(&[ack, 0x02, msb, lsb]).await?;
+                    }
+                }
+            }
+            // PUBREL
+            6 => stream.write_all(&[0x70, 0x02, body[0], body[1]]).await?,
+            // SUBSCRIBE, granting QoS 1 to every filter.
+            8 => {
+                let mut filters = 0;
+                let mut offset = 2;
+                while offset < body.len() {
+                    let filter_len = u16::from_be_bytes([body[offset], body[offset + 1]]) as usize;
+                    offset += 2 + filter_len + 1;
+                    filters += 1;
+                }
+                let mut suback = vec![0x90, 2 + filters as u8, body[0], body[1]];
+                suback.extend(std::iter::repeat_n(0x01, filters));
+                stream.write_all(&suback).await?;
+            }
+            // UNSUBSCRIBE
+            10 => stream.write_all(&[0xb0, 0x02, body[0], body[1]]).await?,
+            // PINGREQ
+            12 => stream.write_all(&[0xd0, 0x00]).await?,
+            // DISCONNECT
+            14 => return Ok(()),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
+
+/// Counts for one subtopic, from `Telemetry::topic_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TopicStats {
+    /// Messages handed to the sink.
+    pub published: u64,
+    /// Messages dropped by the rate limits or the byte-rate cap, or by
+    /// `try_publish` while every `max_concurrent_publishes` slot was taken.
+    pub dropped: u64,
+    /// Bytes published, after compression.
+    pub bytes: u64,
+    /// `TelemetryConfig::clock` monotonic time of the last publish.
+    pub last_publish: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Counts {
+    published: AtomicU64,
+    dropped: AtomicU64,
+    bytes: AtomicU64,
+    /// Monotonic nanoseconds, or 0 before the first publish.
+    last_publish_nanos: AtomicU64,
+}
+
+/// Per-subtopic counters. Topics are only added under the write lock, so
+/// publishing to a known topic only takes the read lock.
+#[derive(Default)]
+pub(crate) struct TopicCounters {
+    topics: RwLock<HashMap<String, Counts>>,
+}
+
+impl TopicCounters {
+    pub fn published(&self, topic: &str, bytes: usize, now: Duration) {
+        self.with(topic, |counts| {
+            counts.published.fetch_add(1, Ordering::Relaxed);
+            counts.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            counts
+                .last_publish_nanos
+                .store((now.as_nanos() as u64).max(1), Ordering::Relaxed);
+        });
+    }
+
+    pub fn dropped(&self, topic: &str) {
+        self.with(topic, |counts| {
+            counts.dropped.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Monotonic time of the last publish to `topic`.
+    pub fn last_publish(&self, topic: &str) -> Option<Duration> {
+        let nanos = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)?
+            .last_publish_nanos
+            .load(Ordering::Relaxed);
+        (nanos > 0).then(|| Duration::from_nanos(nanos))
+    }
+
+    /// Every topic seen so far, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, TopicStats)> {
+        let mut stats: Vec<_> = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(topic, counts)| {
+                let last_publish_nanos = counts.last_publish_nanos.load(Ordering::Relaxed);
+                let stats = TopicStats {
+                    published: counts.published.load(Ordering::Relaxed),
+                    dropped: counts.dropped.load(Ordering::Relaxed),
+                    bytes: counts.bytes.load(Ordering::Relaxed),
+                    last_publish: (last_publish_nanos > 0)
+                        .then(|| Duration::from_nanos(last_publish_nanos)),
+                };
+                (topic.clone(), stats)
+            })
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    fn with(&self, topic: &str, f: impl FnOnce(&Counts)) {
+        if let Some(counts) = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)
+        {
+            return f(counts);
+        }
+
+        f(self
+            .topics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(topic.to_string())
+            .or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_publishes_and_bytes_per_topic() {
+        let counters = TopicCounters::default();
+        let sizes = [10, 25, 40, 85];
+        for (i, size) in sizes.iter().enumerate() {
+            counters.published("joints", *size, Duration::from_millis(i as u64 + 1));
+        }
+        counters.dropped("joints");
+        counters.published("imu", 5, Duration::from_millis(9));
+
+        let stats = counters.snapshot();
+        assert_eq!(stats[0].0, "imu");
+        assert_eq!(stats[1].0, "joints");
+        let joints = stats[1].1;
+        let total: usize = sizes.iter().sum();
+        assert_eq!(joints.published, sizes.len() as u64);
+        assert_eq!(joints.dropped, 1);
+        assert_eq!(joints.bytes, total as u64);
+        assert_eq!(joints.bytes / joints.published, 40);
+        assert_eq!(joints.last_publish, Some(Duration::from_millis(4)));
+        assert_eq!(
+            counters.last_publish("joints"),
+            Some(Duration::from_millis(4))
+        );
+    }
+
+    #[test]
+    fn last_publish_is_none_before_the_first_publish() {
+        let counters = TopicCounters::default();
+        assert_eq!(counters.last_publish("joints"), None);
+        counters.dropped("joints");
+        assert_eq!(counters.last_publish("joints"), None);
+        // A publish at monotonic time 0 still counts.
+        counters.published("joints", 1, Duration::ZERO);
+        assert!(counters.last_publish("joints").is_some());
+    }
+}
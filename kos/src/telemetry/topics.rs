@@ -0,0 +1,204 @@
+use super::error::{Result, TelemetryError};
+use super::payloads::{
+    COMMAND_TOPIC, DIAGNOSTICS_TOPIC, FRAME_TOPIC, HEARTBEAT_TOPIC, IMU_TOPIC, JOINTS_TOPIC,
+    LOGS_TOPIC, SCHEMA_TOPIC, STATUS_TOPIC, VIDEO_TOPIC,
+};
+use std::fmt;
+
+/// Canonical telemetry subtopics. Using these instead of bare strings keeps
+/// typos from silently creating new InfluxDB measurements.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Joints,
+    Imu,
+    Command,
+    Status,
+    Heartbeat,
+    Video,
+    Diagnostics,
+    Frame,
+    Logs,
+    Schema,
+    Custom(String),
+}
+
+impl Topic {
+    /// Subtopic under `robots/{robot_id}/`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Topic::Joints => JOINTS_TOPIC,
+            Topic::Imu => IMU_TOPIC,
+            Topic::Command => COMMAND_TOPIC,
+            Topic::Status => STATUS_TOPIC,
+            Topic::Heartbeat => HEARTBEAT_TOPIC,
+            Topic::Video => VIDEO_TOPIC,
+            Topic::Diagnostics => DIAGNOSTICS_TOPIC,
+            Topic::Frame => FRAME_TOPIC,
+            Topic::Logs => LOGS_TOPIC,
+            Topic::Schema => SCHEMA_TOPIC,
+            Topic::Custom(topic) => topic,
+        }
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Checks that `topic` can be used as a subtopic under
+/// `robots/{robot_id}/`. Wildcards are only valid in subscriptions, and null
+/// or other control characters are rejected by brokers.
+pub(crate) fn validate(topic: &str) -> Result<()> {
+    if topic.is_empty() {
+        return Err(TelemetryError::InvalidTopic(
+            "topic must not be empty".to_string(),
+        ));
+    }
+    if let Some(c) = topic
+        .chars()
+        .find(|c| matches!(c, '#' | '+') || c.is_control())
+    {
+        return Err(TelemetryError::InvalidTopic(format!(
+            "topic {:?} contains disallowed character {:?}",
+            topic, c
+        )));
+    }
+    Ok(())
+}
+
+/// Like `validate`, but allows the `+` and `#` wildcards when they make up a
+/// whole level, with `#` only as the last one.
+pub(crate) fn validate_filter(filter: &str) -> Result<()> {
+    if filter.is_empty() {
+        return Err(TelemetryError::InvalidTopic(
+            "topic filter must not be empty".to_string(),
+        ));
+    }
+    if let Some(c) = filter.chars().find(|c| c.is_control()) {
+        return Err(TelemetryError::InvalidTopic(format!(
+            "topic filter {:?} contains disallowed character {:?}",
+            filter, c
+        )));
+    }
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard_ok = match *level {
+            "+" => true,
+            "#" => i == levels.len() - 1,
+            level => !level.contains(['#', '+']),
+        };
+        if !wildcard_ok {
+            return Err(TelemetryError::InvalidTopic(format!(
+                "topic filter {:?} uses a wildcard inside level {:?}",
+                filter, level
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`.
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Rejects an empty robot id, which would leave an empty topic level.
+pub(crate) fn validate_robot_id(robot_id: &str) -> Result<()> {
+    if robot_id.is_empty() {
+        return Err(TelemetryError::InvalidConfig(
+            "robot id must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces characters that would change the topic structure or make it
+/// invalid, so the robot id always forms exactly one topic level.
+pub(crate) fn sanitize_robot_id(robot_id: &str) -> String {
+    let sanitized: String = robot_id
+        .chars()
+        .map(|c| {
+            if matches!(c, '/' | '#' | '+') || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    if sanitized != robot_id {
+        tracing::warn!(
+            "Robot id {:?} is not a valid topic level, using {:?}",
+            robot_id,
+            sanitized
+        );
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_replaces_each_disallowed_character() {
+        for c in ['+', '#', '/', '\0'] {
+            let robot_id = format!("robot{}1", c);
+            assert_eq!(sanitize_robot_id(&robot_id), "robot_1", "{:?}", c);
+        }
+        assert_eq!(sanitize_robot_id("robot_1"), "robot_1");
+        // Empty ids are left for the caller to reject.
+        assert_eq!(sanitize_robot_id(""), "");
+    }
+
+    #[test]
+    fn validate_robot_id_rejects_empty_ids() {
+        assert!(matches!(
+            validate_robot_id(""),
+            Err(TelemetryError::InvalidConfig(_))
+        ));
+        assert!(validate_robot_id("robot_1").is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_each_disallowed_character() {
+        for topic in ["joints/+", "joints/#", "joints\0", "joints\n", ""] {
+            assert!(
+                matches!(validate(topic), Err(TelemetryError::InvalidTopic(_))),
+                "{:?}",
+                topic
+            );
+        }
+        assert!(validate("joints").is_ok());
+        assert!(validate("joints/left_arm").is_ok());
+    }
+
+    #[test]
+    fn validate_filter_allows_whole_level_wildcards() {
+        assert!(validate_filter("robots/+/joints").is_ok());
+        assert!(validate_filter("robots/#").is_ok());
+        for filter in ["robots/#/joints", "robots/a+/joints", "robots/\0", ""] {
+            assert!(validate_filter(filter).is_err(), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn matches_follows_mqtt_wildcards() {
+        assert!(matches("robots/+/joints", "robots/r1/joints"));
+        assert!(matches("robots/#", "robots/r1/joints/left"));
+        assert!(!matches("robots/+/joints", "robots/r1/imu"));
+        assert!(!matches("robots/+", "robots/r1/joints"));
+    }
+}
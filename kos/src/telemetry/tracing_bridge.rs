@@ -0,0 +1,137 @@
+//! Mirrors `tracing` events to the `logs` telemetry topic, so remote
+//! dashboards see robot logs next to the metrics without a separate log
+//! shipper.
+//!
+//! ```ignore
+//! tracing_subscriber::registry()
+//!     .with(tracing_subscriber::fmt::layer())
+//!     .with(TelemetryLayer::new(tracing::Level::WARN))
+//!     .init();
+//! ```
+
+use super::payloads::LOGS_TOPIC;
+use super::Telemetry;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+thread_local! {
+    // Set while an event is being forwarded, so that events logged by the
+    // publish path itself are not forwarded again.
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A forwarded log event.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogEvent {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// `tracing_subscriber` layer that publishes events at `level` or more severe
+/// with `Telemetry::try_publish`. Events are dropped rather than waited on
+/// when the queue is full, and while telemetry is not initialized.
+pub struct TelemetryLayer {
+    telemetry: Option<Telemetry>,
+    level: Level,
+}
+
+impl TelemetryLayer {
+    /// Forwards to the global instance, once one is installed.
+    pub fn new(level: Level) -> Self {
+        Self {
+            telemetry: None,
+            level,
+        }
+    }
+
+    /// Forwards to `telemetry` instead of the global instance.
+    pub fn for_instance(telemetry: Telemetry, level: Level) -> Self {
+        Self {
+            telemetry: Some(telemetry),
+            level,
+        }
+    }
+
+    fn forward(&self, event: &Event<'_>) {
+        let Some(telemetry) = self.telemetry.clone().or_else(Telemetry::try_get) else {
+            return;
+        };
+
+        let metadata = event.metadata();
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let log_event = LogEvent {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        };
+        // Nothing useful can be done with a failure from inside a log call.
+        let _ = telemetry.try_publish(LOGS_TOPIC, &log_event);
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TelemetryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() > self.level {
+            return;
+        }
+        if FORWARDING.with(|forwarding| forwarding.replace(true)) {
+            return;
+        }
+        self.forward(event);
+        FORWARDING.with(|forwarding| forwarding.set(false));
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: serde_json::Value) {
+        self.fields.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.insert(field, format!("{:?}", value).into());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.insert(field, value.into());
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+}
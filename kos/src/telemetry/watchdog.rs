@@ -0,0 +1,124 @@
+use super::payloads::{WatchdogAlert, ALERT_TOPIC};
+use super::Telemetry;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Shortest time between two checks, however short the intervals are.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Publishes a `WatchdogAlert` for every topic in `intervals` that has not
+/// been published to for longer than its interval. Topics never published
+/// to count from when the watchdog started.
+pub(crate) async fn run(telemetry: Telemetry, intervals: HashMap<String, Duration>) {
+    // Checking at a quarter of the shortest interval alerts at most a
+    // quarter interval late.
+    let Some(shortest) = intervals.values().min().copied() else {
+        return;
+    };
+    let mut ticker = tokio::time::interval((shortest / 4).max(MIN_CHECK_INTERVAL));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let started = telemetry.config.clock.now_monotonic();
+    let mut stalled = HashSet::new();
+    loop {
+        ticker.tick().await;
+        if telemetry.connection.is_shutting_down() {
+            break;
+        }
+
+        let now = telemetry.config.clock.now_monotonic();
+        for (topic, interval) in &intervals {
+            let last = telemetry.topic_stats.last_publish(topic).unwrap_or(started);
+            let silent = now.saturating_sub(last);
+            if silent <= *interval {
+                if stalled.remove(topic) {
+                    tracing::info!("Telemetry topic {} is publishing again", topic);
+                }
+                continue;
+            }
+            if !stalled.insert(topic.clone()) {
+                continue;
+            }
+
+            tracing::warn!("No telemetry on topic {} for {:?}", topic, silent);
+            let alert = WatchdogAlert {
+                topic: topic.clone(),
+                silent_secs: silent.as_secs_f64(),
+            };
+            if let Err(e) = telemetry.publish(ALERT_TOPIC, &alert).await {
+                tracing::warn!("Failed to publish watchdog alert: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::clock::TestClock;
+    use crate::telemetry::config::TelemetryConfig;
+    use crate::telemetry::sink::MemorySink;
+    use crate::telemetry::TelemetryPayload;
+    use std::sync::Arc;
+
+    const STEP: Duration = Duration::from_millis(250);
+
+    /// Moves the configured clock forward `n` check intervals, letting the
+    /// watchdog check after each one.
+    async fn advance(clock: &TestClock, n: u32) {
+        for _ in 0..n {
+            clock.advance(STEP);
+            tokio::time::sleep(STEP).await;
+        }
+    }
+
+    fn alerts(sink: &MemorySink) -> Vec<WatchdogAlert> {
+        sink.messages()
+            .into_iter()
+            .filter(|(topic, _)| topic == "robots/test_robot/alert")
+            .map(|(_, payload)| {
+                serde_json::from_slice::<TelemetryPayload<WatchdogAlert>>(&payload)
+                    .unwrap()
+                    .data
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alerts_once_per_stall_and_rearms_on_resume() {
+        let clock = Arc::new(TestClock::new(Duration::from_secs(1_700_000_000)));
+        // Publish times of exactly 0 are stored as 1ns.
+        clock.advance(Duration::from_secs(1));
+        let sink = Arc::new(MemorySink::new());
+        let mut config = TelemetryConfig::new("test_robot", "localhost", 1883);
+        config.clock = clock.clone();
+        // Checked every quarter second.
+        config
+            .watchdog
+            .insert("joints".to_string(), Duration::from_secs(1));
+        let telemetry = Telemetry::with_sink(config, sink.clone());
+        telemetry.publish("joints", &1).await.unwrap();
+        // Act between checks, so each one sees the clock settled.
+        tokio::time::sleep(STEP / 2).await;
+
+        advance(&clock, 4).await;
+        assert!(alerts(&sink).is_empty(), "silent for exactly the interval");
+        advance(&clock, 1).await;
+        assert_eq!(
+            alerts(&sink),
+            [WatchdogAlert {
+                topic: "joints".to_string(),
+                silent_secs: 1.25,
+            }]
+        );
+        advance(&clock, 8).await;
+        assert_eq!(alerts(&sink).len(), 1, "a stall alerts once");
+
+        telemetry.publish("joints", &2).await.unwrap();
+        advance(&clock, 4).await;
+        assert_eq!(alerts(&sink).len(), 1);
+        advance(&clock, 1).await;
+        assert_eq!(alerts(&sink).len(), 2, "rearmed by the publish");
+    }
+}